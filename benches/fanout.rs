@@ -0,0 +1,66 @@
+// benches/fanout.rs
+//
+// 50 KB のフレームを複数ビューアへ配信するコストを比較する。
+// `Vec<u8>` は受信ごとにフレーム全体がコピーされ、`Bytes` (と `Frame`) は参照カウントのみ。
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::FutureExt;
+use std::hint::black_box;
+use tokio::sync::broadcast;
+use web2ws::server::{DeliveryMode, PublisherPolicy, StreamRegistry};
+
+const FRAME_SIZE: usize = 50 * 1024;
+const VIEWER_COUNTS: [usize; 3] = [1, 16, 64];
+
+fn fanout(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fanout_50k_frame");
+
+    for viewers in VIEWER_COUNTS {
+        group.throughput(Throughput::Bytes((FRAME_SIZE * viewers) as u64));
+
+        group.bench_with_input(BenchmarkId::new("vec_u8", viewers), &viewers, |b, &viewers| {
+            let (tx, _) = broadcast::channel::<Vec<u8>>(4);
+            let mut rxs: Vec<_> = (0..viewers).map(|_| tx.subscribe()).collect();
+            let frame = vec![0xABu8; FRAME_SIZE];
+            b.iter(|| {
+                tx.send(frame.clone()).unwrap();
+                for rx in &mut rxs {
+                    black_box(rx.try_recv().unwrap());
+                }
+            });
+        });
+
+        group.bench_with_input(BenchmarkId::new("bytes", viewers), &viewers, |b, &viewers| {
+            let (tx, _) = broadcast::channel::<Bytes>(4);
+            let mut rxs: Vec<_> = (0..viewers).map(|_| tx.subscribe()).collect();
+            let frame = Bytes::from(vec![0xABu8; FRAME_SIZE]);
+            b.iter(|| {
+                tx.send(frame.clone()).unwrap();
+                for rx in &mut rxs {
+                    black_box(rx.try_recv().unwrap());
+                }
+            });
+        });
+
+        // サーバーと同じ経路: Publisher::send → Viewer::next_frame
+        group.bench_with_input(BenchmarkId::new("registry", viewers), &viewers, |b, &viewers| {
+            let registry = StreamRegistry::new(4);
+            let publisher = registry.publish("bench", PublisherPolicy::Merge).unwrap();
+            let mut subscribers: Vec<_> = (0..viewers)
+                .map(|_| registry.subscribe("bench", DeliveryMode::Queue))
+                .collect();
+            let frame = Bytes::from(vec![0xABu8; FRAME_SIZE]);
+            b.iter(|| {
+                publisher.send(frame.clone());
+                for viewer in &mut subscribers {
+                    black_box(viewer.next_frame().now_or_never().flatten().unwrap());
+                }
+            });
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, fanout);
+criterion_main!(benches);
@@ -1,72 +1,224 @@
 // src/camera/mod.rs
-use std::time::{Instant, Duration};
+use std::time::Duration;
+use std::path::PathBuf;
+use std::str::FromStr;
 use anyhow::Result;
 
+pub mod pacing;
+pub mod pattern;
+pub mod pipeline;
+pub mod replay;
+pub mod synthetic;
+pub mod v4l2;
+#[cfg(target_os = "linux")]
+mod v4l2_sys;
+
+pub use pacing::{FpsReport, FramePacer};
+pub use pattern::PatternSource;
+pub use pipeline::{CapturePipeline, CaptureStats};
+pub use replay::ReplaySource;
+pub use synthetic::SyntheticSource;
+
+// `Camera::fps` で受け付ける上限
+pub const MAX_FPS: f64 = 120.0;
+
+// Settings a source should honour once `Camera::build` configures it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CaptureSettings {
+    pub fps: f64,
+    // JPEG quality (10-95)
+    pub quality: u8,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Default for CaptureSettings {
+    fn default() -> Self {
+        Self {
+            fps: 30.0,
+            quality: 85,
+            width: 640,
+            height: 480,
+        }
+    }
+}
+
+// Anything that produces JPEG frames. `capture_frame` may block until the
+// next frame is ready.
+pub trait FrameSource: Send {
+    fn configure(&mut self, _settings: &CaptureSettings) -> Result<()> {
+        Ok(())
+    }
+
+    fn capture_frame(&mut self) -> Result<Vec<u8>>;
+
+    fn is_open(&self) -> bool {
+        true
+    }
+}
+
+impl<S: FrameSource + ?Sized> FrameSource for Box<S> {
+    fn configure(&mut self, settings: &CaptureSettings) -> Result<()> {
+        (**self).configure(settings)
+    }
+
+    fn capture_frame(&mut self) -> Result<Vec<u8>> {
+        (**self).capture_frame()
+    }
+
+    fn is_open(&self) -> bool {
+        (**self).is_open()
+    }
+}
+
+// `--source` の指定: pattern | synthetic | v4l2[:N] | file:PATH
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SourceSpec {
+    // サーバー側でキャプチャしない（ブラウザの配信者だけ）
+    None,
+    Pattern,
+    Synthetic,
+    V4l2(i32),
+    // MJPEG ファイル、連番 JPEG のディレクトリ、またはセッションファイル
+    File(PathBuf),
+}
+
+impl FromStr for SourceSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.split_once(':') {
+            None if s == "none" => Ok(SourceSpec::None),
+            None if s == "pattern" => Ok(SourceSpec::Pattern),
+            None if s == "synthetic" => Ok(SourceSpec::Synthetic),
+            None if s == "v4l2" => Ok(SourceSpec::V4l2(0)),
+            Some(("v4l2", device)) => match device.parse() {
+                Ok(device) if device >= 0 => Ok(SourceSpec::V4l2(device)),
+                _ => anyhow::bail!("Invalid V4L2 device number `{}`", device),
+            },
+            Some(("file", path)) if !path.is_empty() => Ok(SourceSpec::File(PathBuf::from(path))),
+            _ => anyhow::bail!("Unknown source `{}` (expected none, pattern, synthetic, v4l2[:N] or file:PATH)", s),
+        }
+    }
+}
+
 pub struct Camera {
-    #[allow(dead_code)]
-    device_id: i32,
-    target_fps: f64,
-    quality: u8,
+    source: Box<dyn FrameSource>,
+    settings: CaptureSettings,
+    configured: bool,
     frame_interval: Duration,
-    next_frame_time: Option<Instant>,
-    is_open: bool,
 }
 
 impl Camera {
+    // Opens `/dev/video{device_id}`. Format negotiation happens in `build()`.
     pub fn new(device_id: i32) -> Result<Self> {
-        Ok(Self {
-            device_id,
-            target_fps: 30.0,
-            quality: 85,
-            frame_interval: Duration::from_secs_f64(1.0 / 30.0),
-            next_frame_time: None,
-            is_open: true,
-        })
+        #[cfg(target_os = "linux")]
+        {
+            let device = v4l2_sys::Device::open(device_id)?;
+            Ok(Self::with_source(v4l2::V4l2Source::new(device)))
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            anyhow::bail!("V4L2 capture is only available on Linux (device {})", device_id)
+        }
+    }
+
+    // Colour bars with a frame counter and timestamp, as decodable JPEGs
+    pub fn pattern() -> Self {
+        Self::with_source(PatternSource::default())
+    }
+
+    // Placeholder frames that need no device and no encoding (tests)
+    pub fn synthetic() -> Self {
+        Self::with_source(SyntheticSource::default())
+    }
+
+    pub fn with_source(source: impl FrameSource + 'static) -> Self {
+        let settings = CaptureSettings::default();
+        Self {
+            source: Box::new(source),
+            frame_interval: Duration::from_secs_f64(1.0 / settings.fps),
+            settings,
+            configured: false,
+        }
+    }
+
+    pub fn open(spec: &SourceSpec) -> Result<Self> {
+        match spec {
+            SourceSpec::None => anyhow::bail!("Source `none` has no frames to capture"),
+            SourceSpec::Pattern => Ok(Self::pattern()),
+            SourceSpec::Synthetic => Ok(Self::synthetic()),
+            SourceSpec::V4l2(device_id) => Self::new(*device_id),
+            SourceSpec::File(path) => Ok(Self::with_source(ReplaySource::open(path)?)),
+        }
     }
 
     pub fn is_open(&self) -> bool {
-        self.is_open
+        self.source.is_open()
+    }
+
+    pub fn settings(&self) -> &CaptureSettings {
+        &self.settings
     }
 
     pub fn fps(mut self, fps: f64) -> Self {
-        let clamped_fps = if fps <= 0.0 { 1.0 } else { fps };
-        self.target_fps = clamped_fps;
-        self.frame_interval = Duration::from_secs_f64(1.0 / clamped_fps);
+        // NaN や無限大、0 以下は 1 FPS とみなす
+        let clamped_fps = if fps.is_finite() && fps > 0.0 { fps.min(MAX_FPS) } else { 1.0 };
+        self.settings.fps = clamped_fps;
+        self.frame_interval = pacing::frame_period(clamped_fps);
         self
     }
 
+    pub fn frame_interval(&self) -> Duration {
+        self.frame_interval
+    }
+
+    // Deadline-based ticks at this camera's FPS (needs a tokio runtime)
+    pub fn pacer(&self) -> FramePacer {
+        FramePacer::new(self.settings.fps)
+    }
+
     pub fn quality(mut self, quality: u8) -> Self {
-        self.quality = quality.clamp(10, 95);
+        self.settings.quality = quality.clamp(10, 95);
+        self
+    }
+
+    // Requested capture size; the device may pick the nearest it supports
+    pub fn resolution(mut self, width: u32, height: u32) -> Self {
+        self.settings.width = width.max(1);
+        self.settings.height = height.max(1);
         self
     }
 
     pub fn capture_frame(&mut self) -> Result<Vec<u8>> {
-        // FPS制御はmain.rsのキャプチャループで行う（ブロッキングスリープを避ける）
-        // JPEG フレームをシミュレート
-        let mut frame = vec![0xFFu8, 0xD8u8, 0xFFu8]; // JPEG SOI marker
-        
-        // 品質に応じたサイズを生成 (10-95 の品質に対応)
-        // 品質が高いほど大きいフレームサイズ
-        let quality_range = self.quality.saturating_sub(10);
-        let size_factor = (quality_range as f32) / 85.0;
-        let base_size = 15_000u32;
-        let frame_size = (base_size as f32 * (0.5 + size_factor)) as u32;
-        let frame_size = frame_size.max(10_000).min(50_000);
-        
-        frame.resize(frame_size as usize, 0xFF);
-        frame.extend_from_slice(&[0xFFu8, 0xD9u8]); // JPEG EOI marker
-
-        Ok(frame)
-    }
-
-    pub fn build(self) -> Result<Self> {
+        // FPS制御は呼び出し側の FramePacer で行う（ブロッキングスリープを避ける）
+        if !self.configured {
+            self.source.configure(&self.settings)?;
+            self.configured = true;
+        }
+        self.source.capture_frame()
+    }
+
+    pub fn build(mut self) -> Result<Self> {
         // 最終確認・初期化
         if !self.is_open() {
             anyhow::bail!("Camera failed to open");
         }
-        if self.target_fps <= 0.0 {
+        if self.settings.fps <= 0.0 {
             anyhow::bail!("FPS must be positive");
         }
+        self.source.configure(&self.settings)?;
+        self.configured = true;
         Ok(self)
     }
 }
+
+impl FrameSource for Camera {
+    fn capture_frame(&mut self) -> Result<Vec<u8>> {
+        Camera::capture_frame(self)
+    }
+
+    fn is_open(&self) -> bool {
+        Camera::is_open(self)
+    }
+}
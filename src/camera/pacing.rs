@@ -0,0 +1,141 @@
+// src/camera/pacing.rs
+// 締め切りベースのフレーム間隔制御
+//
+// Ticks fall on `start + n * interval`, so time spent capturing and sending
+// does not push later frames back. A capture that overruns skips the ticks
+// it missed instead of bursting to catch up.
+use std::time::Duration;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+
+// FPS を報告する間隔
+pub const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+// tokio の interval は 0 の周期を受け付けないので、どんな FPS でもこれより短くしない
+pub const MIN_FRAME_PERIOD: Duration = Duration::from_millis(1);
+
+// Frame period for `fps`; 1 second for a non-positive or NaN rate
+pub fn frame_period(fps: f64) -> Duration {
+    Duration::try_from_secs_f64(1.0 / fps)
+        .ok()
+        .filter(|_| fps > 0.0)
+        .unwrap_or(Duration::from_secs(1))
+        .max(MIN_FRAME_PERIOD)
+}
+
+pub struct FramePacer {
+    interval: Interval,
+    meter: FpsMeter,
+}
+
+impl FramePacer {
+    pub fn new(fps: f64) -> Self {
+        let mut interval = time::interval(frame_period(fps));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        Self {
+            interval,
+            meter: FpsMeter::new(fps),
+        }
+    }
+
+    // Waits for the next frame deadline; the first tick completes immediately
+    pub async fn tick(&mut self) -> Instant {
+        self.interval.tick().await;
+        let now = Instant::now();
+        self.meter.record(now);
+        now
+    }
+
+    pub fn meter(&self) -> &FpsMeter {
+        &self.meter
+    }
+
+    // A report once every `REPORT_INTERVAL`, which also starts a new window
+    pub fn take_report(&mut self) -> Option<FpsReport> {
+        self.meter.take_report(REPORT_INTERVAL)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FpsReport {
+    pub frames: u64,
+    pub target_fps: f64,
+    pub actual_fps: f64,
+    // 目標間隔からのずれの平均と最大
+    pub mean_jitter: Duration,
+    pub max_jitter: Duration,
+}
+
+// Measures the rate and regularity of frame times against a target FPS
+pub struct FpsMeter {
+    target_fps: f64,
+    target_interval: Duration,
+    window_start: Option<Instant>,
+    last: Option<Instant>,
+    frames: u64,
+    intervals: u64,
+    elapsed: Duration,
+    total_jitter: Duration,
+    max_jitter: Duration,
+}
+
+impl FpsMeter {
+    pub fn new(target_fps: f64) -> Self {
+        Self {
+            target_fps,
+            target_interval: frame_period(target_fps),
+            window_start: None,
+            last: None,
+            frames: 0,
+            intervals: 0,
+            elapsed: Duration::ZERO,
+            total_jitter: Duration::ZERO,
+            max_jitter: Duration::ZERO,
+        }
+    }
+
+    pub fn record(&mut self, now: Instant) {
+        self.window_start.get_or_insert(now);
+        if let Some(last) = self.last {
+            let interval = now - last;
+            let jitter = interval.abs_diff(self.target_interval);
+            self.intervals += 1;
+            self.elapsed += interval;
+            self.total_jitter += jitter;
+            self.max_jitter = self.max_jitter.max(jitter);
+        }
+        self.last = Some(now);
+        self.frames += 1;
+    }
+
+    // Statistics of the current window, without resetting it
+    pub fn report(&self) -> FpsReport {
+        let actual_fps = if self.elapsed.is_zero() {
+            0.0
+        } else {
+            self.intervals as f64 / self.elapsed.as_secs_f64()
+        };
+        FpsReport {
+            frames: self.frames,
+            target_fps: self.target_fps,
+            actual_fps,
+            mean_jitter: self.total_jitter.checked_div(self.intervals as u32).unwrap_or_default(),
+            max_jitter: self.max_jitter,
+        }
+    }
+
+    // Returns the window's report and starts a new one once the window
+    // spans `window`; the last frame time carries over so no interval is lost
+    pub fn take_report(&mut self, window: Duration) -> Option<FpsReport> {
+        let (start, last) = (self.window_start?, self.last?);
+        if last - start < window {
+            return None;
+        }
+        let report = self.report();
+        *self = Self {
+            window_start: Some(last),
+            last: Some(last),
+            ..Self::new(self.target_fps)
+        };
+        Some(report)
+    }
+}
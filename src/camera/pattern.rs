@@ -0,0 +1,232 @@
+// src/camera/pattern.rs
+// SMPTE 風カラーバー + 動く箱 + フレーム番号 + 時刻のテストパターン
+use super::{CaptureSettings, FrameSource};
+use anyhow::Result;
+use jpeg_encoder::{ColorType, Encoder};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// 75% のカラーバー（左から白・黄・シアン・緑・マゼンタ・赤・青）
+pub const BARS: [[u8; 3]; 7] = [
+    [191, 191, 191],
+    [191, 191, 0],
+    [0, 191, 191],
+    [0, 191, 0],
+    [191, 0, 191],
+    [191, 0, 0],
+    [0, 0, 191],
+];
+
+// バーの下の帯は逆順（青・黒・マゼンタ・黒・シアン・黒・白）
+const REVERSE_BARS: [[u8; 3]; 7] = [
+    [0, 0, 191],
+    [0, 0, 0],
+    [191, 0, 191],
+    [0, 0, 0],
+    [0, 191, 191],
+    [0, 0, 0],
+    [191, 191, 191],
+];
+
+pub const BOX_COLOR: [u8; 3] = [255, 255, 255];
+pub const TEXT_COLOR: [u8; 3] = [255, 255, 255];
+
+// 1フレームあたりの箱の移動量（ピクセル）
+const BOX_SPEED: u64 = 4;
+
+// 3x5 のビットマップ数字。各行の下位3ビットを左から使う
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+const COLON: [u8; 5] = [0b000, 0b010, 0b000, 0b010, 0b000];
+const DOT: [u8; 5] = [0b000, 0b000, 0b000, 0b000, 0b010];
+
+fn glyph(c: char) -> Option<[u8; 5]> {
+    match c {
+        '0'..='9' => Some(DIGITS[c as usize - '0' as usize]),
+        ':' => Some(COLON),
+        '.' => Some(DOT),
+        _ => None,
+    }
+}
+
+// Where the parts of the pattern sit in a `width` x `height` frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub width: usize,
+    pub height: usize,
+    // バーは [0, bars_bottom)、逆バーは [bars_bottom, text_top)、文字は [text_top, height)
+    pub bars_bottom: usize,
+    pub text_top: usize,
+    pub box_size: usize,
+    pub box_top: usize,
+    // 文字の1ドットの大きさ
+    pub scale: usize,
+}
+
+impl Layout {
+    pub fn new(width: usize, height: usize) -> Self {
+        let bars_bottom = height * 2 / 3;
+        let text_top = height * 3 / 4;
+        let box_size = (height / 6).max(1);
+        Self {
+            width,
+            height,
+            bars_bottom,
+            text_top,
+            box_size,
+            box_top: height / 3,
+            // 6桁の番号と12文字の時刻が横に並ぶ大きさまで
+            scale: ((height - text_top) / 8).min(width / 80).max(1),
+        }
+    }
+
+    // 左端から右端まで往復する
+    pub fn box_left(&self, frame: u64) -> usize {
+        let travel = self.width.saturating_sub(self.box_size) as u64;
+        if travel == 0 {
+            return 0;
+        }
+        let position = (frame * BOX_SPEED) % (2 * travel);
+        (if position <= travel { position } else { 2 * travel - position }) as usize
+    }
+
+    pub fn bar_at(&self, x: usize) -> usize {
+        x * BARS.len() / self.width
+    }
+}
+
+// `frame` as a 6 digit counter (wraps at a million)
+pub fn counter_text(frame: u64) -> String {
+    format!("{:06}", frame % 1_000_000)
+}
+
+// HH:MM:SS.mmm of the UTC time of day
+pub fn timestamp_text(time_of_day: Duration) -> String {
+    let millis = time_of_day.as_millis() % 86_400_000;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
+
+// Draws frame `frame` as packed RGB
+pub fn render(layout: &Layout, frame: u64, time_of_day: Duration) -> Vec<u8> {
+    let (width, height) = (layout.width, layout.height);
+    let mut rgb = vec![0u8; width * height * 3];
+
+    for y in 0..layout.text_top {
+        let bars = if y < layout.bars_bottom { &BARS } else { &REVERSE_BARS };
+        for x in 0..width {
+            let offset = (y * width + x) * 3;
+            rgb[offset..offset + 3].copy_from_slice(&bars[layout.bar_at(x)]);
+        }
+    }
+
+    let left = layout.box_left(frame);
+    fill(&mut rgb, layout, left, layout.box_top, layout.box_size, layout.box_size, BOX_COLOR);
+
+    // 下の黒帯の左にフレーム番号、右に時刻
+    let margin = layout.scale * 2;
+    let text_y = layout.text_top + (height - layout.text_top).saturating_sub(5 * layout.scale) / 2;
+    draw_text(&mut rgb, layout, margin, text_y, &counter_text(frame));
+    let timestamp = timestamp_text(time_of_day);
+    let text_width = timestamp.len() * 4 * layout.scale;
+    draw_text(&mut rgb, layout, width.saturating_sub(text_width + margin), text_y, &timestamp);
+
+    rgb
+}
+
+fn fill(rgb: &mut [u8], layout: &Layout, left: usize, top: usize, w: usize, h: usize, color: [u8; 3]) {
+    for y in top..(top + h).min(layout.height) {
+        for x in left..(left + w).min(layout.width) {
+            let offset = (y * layout.width + x) * 3;
+            rgb[offset..offset + 3].copy_from_slice(&color);
+        }
+    }
+}
+
+fn draw_text(rgb: &mut [u8], layout: &Layout, left: usize, top: usize, text: &str) {
+    let scale = layout.scale;
+    for (i, c) in text.chars().enumerate() {
+        let Some(rows) = glyph(c) else { continue };
+        let char_left = left + i * 4 * scale;
+        for (row, bits) in rows.iter().enumerate() {
+            for col in 0..3 {
+                if bits & (0b100 >> col) != 0 {
+                    fill(rgb, layout, char_left + col * scale, top + row * scale, scale, scale, TEXT_COLOR);
+                }
+            }
+        }
+    }
+}
+
+// Test pattern frames encoded as real JPEGs at the configured size and quality
+pub struct PatternSource {
+    layout: Layout,
+    quality: u8,
+    frame: u64,
+}
+
+impl Default for PatternSource {
+    fn default() -> Self {
+        let settings = CaptureSettings::default();
+        Self {
+            layout: Layout::new(settings.width as usize, settings.height as usize),
+            quality: settings.quality,
+            frame: 0,
+        }
+    }
+}
+
+impl PatternSource {
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    // Number of the next frame
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn encode(&self, frame: u64, time_of_day: Duration) -> Result<Vec<u8>> {
+        let rgb = render(&self.layout, frame, time_of_day);
+        let mut jpeg = Vec::new();
+        Encoder::new(&mut jpeg, self.quality).encode(
+            &rgb,
+            self.layout.width as u16,
+            self.layout.height as u16,
+            ColorType::Rgb,
+        )?;
+        Ok(jpeg)
+    }
+}
+
+impl FrameSource for PatternSource {
+    fn configure(&mut self, settings: &CaptureSettings) -> Result<()> {
+        // JPEG の寸法は 16 ビット
+        let width = settings.width.min(u16::MAX as u32) as usize;
+        let height = settings.height.min(u16::MAX as u32) as usize;
+        self.layout = Layout::new(width, height);
+        self.quality = settings.quality;
+        Ok(())
+    }
+
+    fn capture_frame(&mut self) -> Result<Vec<u8>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let jpeg = self.encode(self.frame, now)?;
+        self.frame += 1;
+        Ok(jpeg)
+    }
+}
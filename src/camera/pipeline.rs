@@ -0,0 +1,223 @@
+// src/camera/pipeline.rs
+// ブロッキングなキャプチャを専用スレッドで回し、非同期側へフレームを渡す
+//
+//   ticker task ──tick──▶ capture thread ──frame──▶ bounded buffer ──▶ recv()
+//
+// The ticker requests one frame per `FramePacer` tick. A tick that arrives
+// while the previous capture is still running is skipped, so a source slower
+// than the target FPS runs flat out without a backlog. When the consumer is
+// behind and the buffer is full, the oldest buffered frame is dropped so
+// `recv` always returns the freshest frames.
+use super::{Camera, FpsReport, FrameSource};
+use super::pacing::{FpsMeter, FramePacer};
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc as std_mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+// 既定のバッファ: 1枚を渡している間にもう1枚を用意できる
+pub const DEFAULT_CAPTURE_BUFFER: usize = 2;
+
+#[derive(Debug, Default)]
+pub struct CaptureStats {
+    pub captured: AtomicU64,
+    pub errors: AtomicU64,
+    // キャプチャが間に合わず飛ばしたティック
+    pub skipped_ticks: AtomicU64,
+    // 受け取り側が遅れてバッファから押し出された古いフレーム
+    pub dropped_frames: AtomicU64,
+}
+
+impl CaptureStats {
+    pub fn captured(&self) -> u64 {
+        self.captured.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub fn skipped_ticks(&self) -> u64 {
+        self.skipped_ticks.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames.load(Ordering::Relaxed)
+    }
+}
+
+// 古いものから押し出される有界バッファ
+struct FrameBuffer {
+    frames: Mutex<BufferState>,
+    ready: Notify,
+}
+
+struct BufferState {
+    frames: VecDeque<Result<Vec<u8>>>,
+    capacity: usize,
+    // キャプチャスレッドが終了した
+    closed: bool,
+}
+
+impl FrameBuffer {
+    fn new(capacity: usize) -> Self {
+        Self {
+            frames: Mutex::new(BufferState {
+                frames: VecDeque::with_capacity(capacity),
+                capacity,
+                closed: false,
+            }),
+            ready: Notify::new(),
+        }
+    }
+
+    // Returns whether an older frame had to be dropped
+    fn push(&self, frame: Result<Vec<u8>>) -> bool {
+        let mut state = self.frames.lock().unwrap();
+        let dropped = state.frames.len() >= state.capacity && state.frames.pop_front().is_some();
+        state.frames.push_back(frame);
+        drop(state);
+        self.ready.notify_one();
+        dropped
+    }
+
+    fn close(&self) {
+        self.frames.lock().unwrap().closed = true;
+        self.ready.notify_one();
+    }
+
+    async fn pop(&self) -> Option<Result<Vec<u8>>> {
+        loop {
+            {
+                let mut state = self.frames.lock().unwrap();
+                if let Some(frame) = state.frames.pop_front() {
+                    return Some(frame);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            // notify_one は待ち手がいなくても許可を1つ残すので取りこぼさない
+            self.ready.notified().await;
+        }
+    }
+}
+
+pub struct CapturePipeline {
+    frames: Arc<FrameBuffer>,
+    stats: Arc<CaptureStats>,
+    meter: FpsMeter,
+    ticker: JoinHandle<()>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl CapturePipeline {
+    // Starts capturing from `source` at `fps`, keeping at most the `buffer`
+    // newest frames the consumer has not taken yet. Must be called from a
+    // tokio runtime.
+    pub fn spawn(source: impl FrameSource + 'static, fps: f64, buffer: usize) -> Result<Self> {
+        let frames = Arc::new(FrameBuffer::new(buffer.max(1)));
+        // 容量1: 実行中のキャプチャの次の1回分だけ予約できる
+        let (tick_tx, tick_rx) = std_mpsc::sync_channel::<()>(1);
+        let stats = Arc::new(CaptureStats::default());
+
+        let worker_frames = frames.clone();
+        let worker_stats = stats.clone();
+        let worker = thread::Builder::new()
+            .name("web2ws-capture".to_string())
+            .spawn(move || capture_loop(source, tick_rx, worker_frames, worker_stats))?;
+
+        let ticker_stats = stats.clone();
+        let mut pacer = FramePacer::new(fps);
+        let ticker = tokio::spawn(async move {
+            loop {
+                pacer.tick().await;
+                match tick_tx.try_send(()) {
+                    Ok(()) => {}
+                    Err(std_mpsc::TrySendError::Full(())) => {
+                        ticker_stats.skipped_ticks.fetch_add(1, Ordering::Relaxed);
+                    }
+                    // キャプチャスレッドが終了した
+                    Err(std_mpsc::TrySendError::Disconnected(())) => break,
+                }
+            }
+        });
+
+        Ok(Self {
+            frames,
+            stats,
+            meter: FpsMeter::new(fps),
+            ticker,
+            worker: Some(worker),
+        })
+    }
+
+    // The oldest frame or capture error still buffered; `None` once the
+    // source has no more frames
+    pub async fn recv(&mut self) -> Option<Result<Vec<u8>>> {
+        let frame = self.frames.pop().await?;
+        if frame.is_ok() {
+            self.meter.record(tokio::time::Instant::now());
+        }
+        Some(frame)
+    }
+
+    pub fn stats(&self) -> Arc<CaptureStats> {
+        self.stats.clone()
+    }
+
+    // Rate and jitter of frames handed to `recv`, once every `REPORT_INTERVAL`
+    pub fn take_report(&mut self) -> Option<FpsReport> {
+        self.meter.take_report(super::pacing::REPORT_INTERVAL)
+    }
+
+    // Stops capturing and waits for the capture thread to release the source
+    pub async fn close(mut self) {
+        // ティッカーが止まるとキャプチャスレッドも抜ける
+        self.ticker.abort();
+        if let Some(worker) = self.worker.take() {
+            let _ = tokio::task::spawn_blocking(move || worker.join()).await;
+        }
+    }
+}
+
+impl Drop for CapturePipeline {
+    // スレッドは実行中のキャプチャを終えると自分で抜ける
+    fn drop(&mut self) {
+        self.ticker.abort();
+    }
+}
+
+fn capture_loop(
+    mut source: impl FrameSource,
+    ticks: std_mpsc::Receiver<()>,
+    frames: Arc<FrameBuffer>,
+    stats: Arc<CaptureStats>,
+) {
+    while ticks.recv().is_ok() {
+        if !source.is_open() {
+            break;
+        }
+        let frame = source.capture_frame();
+        match &frame {
+            Ok(_) => stats.captured.fetch_add(1, Ordering::Relaxed),
+            Err(_) => stats.errors.fetch_add(1, Ordering::Relaxed),
+        };
+        if frames.push(frame) {
+            stats.dropped_frames.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    frames.close();
+}
+
+impl Camera {
+    // Moves capture onto its own thread, paced at this camera's FPS
+    pub fn into_pipeline(self, buffer: usize) -> Result<CapturePipeline> {
+        let fps = self.settings().fps;
+        CapturePipeline::spawn(self, fps, buffer)
+    }
+}
@@ -0,0 +1,313 @@
+// src/camera/replay.rs
+// 録画済みの映像を再生するソース
+//
+// Accepted inputs:
+//   - a raw MJPEG stream (JPEGs back to back, anything between them skipped;
+//     SOI/EOI inside a segment such as an EXIF thumbnail do not split frames)
+//   - a directory of numbered `.jpg`/`.jpeg` files, played in numeric order
+//   - a web2ws session file: the 8 byte magic `W2WSREC1`, then for every
+//     frame a big-endian u32 length and a protocol envelope of that length
+use super::{CaptureSettings, FrameSource};
+use crate::protocol::{self, FrameHeader};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub const SESSION_MAGIC: [u8; 8] = *b"W2WSREC1";
+
+// Writes a session file that `ReplaySource` can play back
+pub struct SessionWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> SessionWriter<W> {
+    pub fn new(mut out: W) -> Result<Self> {
+        out.write_all(&SESSION_MAGIC)?;
+        Ok(Self { out })
+    }
+
+    pub fn write_frame(&mut self, header: &FrameHeader, payload: &[u8]) -> Result<()> {
+        let envelope = protocol::encode(header, payload);
+        self.out.write_all(&(envelope.len() as u32).to_be_bytes())?;
+        self.out.write_all(&envelope)?;
+        Ok(())
+    }
+
+    pub fn into_inner(mut self) -> Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+enum Frames {
+    // フレームはファイル全体を読み込んだバッファのスライス
+    Memory(Vec<Bytes>),
+    // 連番 JPEG はキャプチャのたびに読む
+    Files(Vec<PathBuf>),
+}
+
+impl Frames {
+    fn len(&self) -> usize {
+        match self {
+            Frames::Memory(frames) => frames.len(),
+            Frames::Files(paths) => paths.len(),
+        }
+    }
+}
+
+pub struct ReplaySource {
+    path: PathBuf,
+    frames: Frames,
+    position: usize,
+    looping: bool,
+    realtime: bool,
+    frame_interval: Duration,
+    next_due: Option<Instant>,
+}
+
+impl ReplaySource {
+    // Loops by default and does not pace itself; see `looping` and `realtime`
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let frames = if path.is_dir() {
+            Frames::Files(numbered_jpegs(path)?)
+        } else {
+            let data = Bytes::from(
+                std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?,
+            );
+            if data.starts_with(&SESSION_MAGIC) {
+                Frames::Memory(split_session(&data)?)
+            } else if data.starts_with(&[0xFF, 0xD8]) {
+                Frames::Memory(split_mjpeg(&data))
+            } else {
+                anyhow::bail!("{} is neither an MJPEG stream nor a web2ws session", path.display());
+            }
+        };
+        if frames.len() == 0 {
+            anyhow::bail!("{} contains no frames", path.display());
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            frames,
+            position: 0,
+            looping: true,
+            realtime: false,
+            frame_interval: Duration::from_secs_f64(1.0 / CaptureSettings::default().fps),
+            next_due: None,
+        })
+    }
+
+    // Start over after the last frame instead of ending
+    pub fn looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        self
+    }
+
+    // Make `capture_frame` block until the next frame is due at the
+    // configured FPS, for callers that do not pace themselves
+    pub fn realtime(mut self, realtime: bool) -> Self {
+        self.realtime = realtime;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.len() == 0
+    }
+
+    // Index of the next frame to be returned
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn seek(&mut self, frame: usize) -> Result<()> {
+        if frame >= self.len() {
+            anyhow::bail!("Cannot seek to frame {} of {} ({} frames)", frame, self.path.display(), self.len());
+        }
+        self.position = frame;
+        self.next_due = None;
+        Ok(())
+    }
+
+    // Seeks to the frame shown `offset` into playback at the configured FPS
+    pub fn seek_time(&mut self, offset: Duration) -> Result<()> {
+        let frame = (offset.as_secs_f64() / self.frame_interval.as_secs_f64()).floor() as usize;
+        let frame = if self.looping { frame % self.len() } else { frame };
+        self.seek(frame)
+    }
+
+    fn wait_until_due(&mut self) {
+        let now = Instant::now();
+        let due = match self.next_due {
+            // 大きく遅れたら追いつこうとせずに基準を取り直す
+            Some(due) if now.saturating_duration_since(due) < self.frame_interval => due,
+            _ => now,
+        };
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+        self.next_due = Some(due + self.frame_interval);
+    }
+}
+
+impl FrameSource for ReplaySource {
+    // 録画された解像度と画質のまま返すので使うのは FPS だけ
+    fn configure(&mut self, settings: &CaptureSettings) -> Result<()> {
+        self.frame_interval = Duration::from_secs_f64(1.0 / settings.fps);
+        self.next_due = None;
+        Ok(())
+    }
+
+    fn capture_frame(&mut self) -> Result<Vec<u8>> {
+        if self.position >= self.len() {
+            if !self.looping {
+                anyhow::bail!("Replay of {} finished", self.path.display());
+            }
+            self.position = 0;
+        }
+        if self.realtime {
+            self.wait_until_due();
+        }
+        let frame = match &self.frames {
+            Frames::Memory(frames) => frames[self.position].to_vec(),
+            Frames::Files(paths) => {
+                let path = &paths[self.position];
+                std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?
+            }
+        };
+        self.position += 1;
+        Ok(frame)
+    }
+
+    fn is_open(&self) -> bool {
+        self.looping || self.position < self.len()
+    }
+}
+
+// SOI から始まる JPEG をマーカー構造に沿って読み、その EOI までを1フレームとする。
+// EXIF のサムネイルなどセグメント内の SOI/EOI は長さで読み飛ばすので区切りと取り違えない
+fn split_mjpeg(data: &Bytes) -> Vec<Bytes> {
+    let mut frames = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = data[offset..].windows(2).position(|w| w == [0xFF, 0xD8]).map(|i| offset + i) {
+        match jpeg_end(data, start) {
+            JpegEnd::Eoi(end) => {
+                frames.push(data.slice(start..end));
+                offset = end;
+            }
+            // 壊れたフレームは捨てて次の SOI から探し直す
+            JpegEnd::Malformed => offset = start + 2,
+            // 最後のフレームが途中で切れている（中のサムネイルを拾わないようここで終える）
+            JpegEnd::Truncated => break,
+        }
+    }
+    frames
+}
+
+enum JpegEnd {
+    // EOI の直後のオフセット
+    Eoi(usize),
+    Malformed,
+    Truncated,
+}
+
+// Walks the marker segments of the JPEG whose SOI is at `start`
+fn jpeg_end(data: &[u8], start: usize) -> JpegEnd {
+    let byte = |pos: usize| data.get(pos).copied();
+    let mut pos = start + 2;
+    loop {
+        match byte(pos) {
+            None => return JpegEnd::Truncated,
+            Some(0xFF) => {}
+            Some(_) => return JpegEnd::Malformed,
+        }
+        // マーカーの前には 0xFF の詰め物があってよい
+        while byte(pos + 1) == Some(0xFF) {
+            pos += 1;
+        }
+        let Some(marker) = byte(pos + 1) else { return JpegEnd::Truncated };
+        pos += 2;
+        match marker {
+            0xD9 => return JpegEnd::Eoi(pos),
+            // 長さを持たないマーカー
+            0x01 | 0xD0..=0xD7 => {}
+            0x00 | 0xD8 => return JpegEnd::Malformed,
+            _ => {
+                let (Some(high), Some(low)) = (byte(pos), byte(pos + 1)) else { return JpegEnd::Truncated };
+                let len = u16::from_be_bytes([high, low]) as usize;
+                if len < 2 {
+                    return JpegEnd::Malformed;
+                }
+                pos += len;
+                // プログレッシブ JPEG ではスキャンの後に次のセグメントが続く
+                if marker == 0xDA {
+                    let Some(end) = scan_end(data, pos) else { return JpegEnd::Truncated };
+                    pos = end;
+                }
+            }
+        }
+    }
+}
+
+// Skips entropy-coded scan data, returning the offset of the marker after it
+fn scan_end(data: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        pos += data.get(pos..)?.iter().position(|&b| b == 0xFF)?;
+        match *data.get(pos + 1)? {
+            // バイトスタッフィング（FF 00）と RSTn はスキャンの一部
+            0x00 | 0xD0..=0xD7 => pos += 2,
+            _ => return Some(pos),
+        }
+    }
+}
+
+fn split_session(data: &Bytes) -> Result<Vec<Bytes>> {
+    let mut frames = Vec::new();
+    let mut offset = SESSION_MAGIC.len();
+    while offset < data.len() {
+        let Some(len) = data.get(offset..offset + 4) else {
+            anyhow::bail!("Session is truncated at byte {}", offset);
+        };
+        let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+        let start = offset + 4;
+        if data.len() - start < len {
+            anyhow::bail!("Session is truncated at byte {}", offset);
+        }
+        let (_, payload) = protocol::decode(&data.slice(start..start + len))
+            .map_err(|e| anyhow::anyhow!("Bad frame at byte {} of the session: {}", offset, e))?;
+        frames.push(payload);
+        offset = start + len;
+    }
+    Ok(frames)
+}
+
+// frame2.jpg が frame10.jpg より前に来るようにファイル名中の数字で並べる
+fn numbered_jpegs(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        let is_jpeg = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("jpg") || ext.eq_ignore_ascii_case("jpeg"));
+        if !is_jpeg || !path.is_file() {
+            continue;
+        }
+        let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+        // 最後に現れる数字の並びを番号とする（cam1_0002.jpg なら 2）
+        let digits: String = stem
+            .rsplit(|c: char| !c.is_ascii_digit())
+            .find(|run| !run.is_empty())
+            .unwrap_or_default()
+            .to_string();
+        let number = digits.parse::<u64>().unwrap_or(u64::MAX);
+        files.push((number, path));
+    }
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
@@ -0,0 +1,41 @@
+// src/camera/synthetic.rs
+use super::{CaptureSettings, FrameSource};
+use anyhow::Result;
+
+// JPEG の形（SOI/EOI）だけを持つダミーフレーム。サイズは品質に比例する
+pub struct SyntheticSource {
+    quality: u8,
+}
+
+impl Default for SyntheticSource {
+    fn default() -> Self {
+        Self {
+            quality: CaptureSettings::default().quality,
+        }
+    }
+}
+
+impl FrameSource for SyntheticSource {
+    fn configure(&mut self, settings: &CaptureSettings) -> Result<()> {
+        self.quality = settings.quality;
+        Ok(())
+    }
+
+    fn capture_frame(&mut self) -> Result<Vec<u8>> {
+        // JPEG フレームをシミュレート
+        let mut frame = vec![0xFFu8, 0xD8u8, 0xFFu8]; // JPEG SOI marker
+
+        // 品質に応じたサイズを生成 (10-95 の品質に対応)
+        // 品質が高いほど大きいフレームサイズ
+        let quality_range = self.quality.saturating_sub(10);
+        let size_factor = (quality_range as f32) / 85.0;
+        let base_size = 15_000u32;
+        let frame_size = (base_size as f32 * (0.5 + size_factor)) as u32;
+        let frame_size = frame_size.clamp(10_000, 50_000);
+
+        frame.resize(frame_size as usize, 0xFF);
+        frame.extend_from_slice(&[0xFFu8, 0xD9u8]); // JPEG EOI marker
+
+        Ok(frame)
+    }
+}
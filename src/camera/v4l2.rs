@@ -0,0 +1,246 @@
+// src/camera/v4l2.rs
+use super::{CaptureSettings, FrameSource};
+use anyhow::Result;
+use jpeg_encoder::{ColorType, Encoder};
+use std::io;
+
+pub const CAP_VIDEO_CAPTURE: u32 = 0x0000_0001;
+pub const CAP_STREAMING: u32 = 0x0400_0000;
+pub const CAP_DEVICE_CAPS: u32 = 0x8000_0000;
+
+pub const CID_JPEG_COMPRESSION_QUALITY: u32 = 0x009d_0903;
+
+// ドライバに確保してもらう mmap バッファ数
+pub const BUFFER_COUNT: u32 = 4;
+
+pub const fn fourcc(code: &[u8; 4]) -> u32 {
+    u32::from_le_bytes(*code)
+}
+
+pub const PIX_FMT_MJPEG: u32 = fourcc(b"MJPG");
+pub const PIX_FMT_JPEG: u32 = fourcc(b"JPEG");
+pub const PIX_FMT_YUYV: u32 = fourcc(b"YUYV");
+
+pub fn fourcc_name(code: u32) -> String {
+    code.to_le_bytes().iter().map(|&b| b as char).collect()
+}
+
+// VIDIOC_QUERYCAP
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capability {
+    pub driver: String,
+    pub card: String,
+    pub capabilities: u32,
+    pub device_caps: u32,
+}
+
+impl Capability {
+    // Capabilities of the opened node rather than of the whole device
+    pub fn effective(&self) -> u32 {
+        if self.capabilities & CAP_DEVICE_CAPS != 0 {
+            self.device_caps
+        } else {
+            self.capabilities
+        }
+    }
+}
+
+// The parts of `struct v4l2_pix_format` negotiation looks at
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PixFormat {
+    pub width: u32,
+    pub height: u32,
+    pub pixelformat: u32,
+    pub bytesperline: u32,
+    pub sizeimage: u32,
+}
+
+// The V4L2 ioctls capture needs, one method per ioctl, so that negotiation
+// can run against a mock instead of a device
+pub trait Ioctl: Send {
+    fn querycap(&mut self) -> io::Result<Capability>;
+    // VIDIOC_ENUM_FMT for capture; `None` past the last format
+    fn enum_fmt(&mut self, index: u32) -> io::Result<Option<u32>>;
+    // VIDIOC_S_FMT; returns the format the driver actually chose
+    fn s_fmt(&mut self, format: PixFormat) -> io::Result<PixFormat>;
+    // VIDIOC_S_PARM with a time per frame of `numerator / denominator`
+    // seconds; returns the interval the driver chose
+    fn s_parm(&mut self, numerator: u32, denominator: u32) -> io::Result<(u32, u32)>;
+    fn s_ctrl(&mut self, id: u32, value: i32) -> io::Result<()>;
+    // VIDIOC_REQBUFS, QUERYBUF + mmap and QBUF for each buffer, then STREAMON
+    fn stream_on(&mut self, buffers: u32) -> io::Result<()>;
+    // VIDIOC_DQBUF, copy out the bytes used and QBUF the buffer again
+    fn dequeue(&mut self) -> io::Result<Vec<u8>>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    // フレームは JPEG のまま転送できる
+    Mjpeg,
+    // 4:2:2 の YUV。送る前に JPEG へエンコードする
+    Yuyv,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Negotiated {
+    pub format: PixelFormat,
+    pub width: u32,
+    pub height: u32,
+    pub bytesperline: u32,
+    // `None` if the driver does not support setting the frame rate
+    pub fps: Option<f64>,
+}
+
+// Picks MJPEG (or JPEG) if the device offers it, YUYV otherwise, and sets
+// the size and frame rate closest to `settings`
+pub fn negotiate(device: &mut impl Ioctl, settings: &CaptureSettings) -> Result<Negotiated> {
+    let cap = device.querycap()?;
+    if cap.effective() & CAP_VIDEO_CAPTURE == 0 {
+        anyhow::bail!("{} is not a video capture device", cap.card);
+    }
+    if cap.effective() & CAP_STREAMING == 0 {
+        anyhow::bail!("{} does not support streaming I/O", cap.card);
+    }
+
+    let mut offered = Vec::new();
+    // 異常なドライバで無限ループしないよう上限を設ける
+    for index in 0..64 {
+        match device.enum_fmt(index)? {
+            Some(code) => offered.push(code),
+            None => break,
+        }
+    }
+
+    let candidates = [
+        (PIX_FMT_MJPEG, PixelFormat::Mjpeg),
+        (PIX_FMT_JPEG, PixelFormat::Mjpeg),
+        (PIX_FMT_YUYV, PixelFormat::Yuyv),
+    ];
+    let mut rejected = None;
+    for (code, format) in candidates.into_iter().filter(|(code, _)| offered.contains(code)) {
+        let requested = PixFormat {
+            width: settings.width,
+            height: settings.height,
+            pixelformat: code,
+            ..PixFormat::default()
+        };
+        // 拒否された (EINVAL など) 場合やドライバが別の形式に変えた場合は次の候補を試す
+        let actual = match device.s_fmt(requested) {
+            Ok(actual) => actual,
+            Err(err) => {
+                rejected = Some(err);
+                continue;
+            }
+        };
+        if actual.pixelformat != code {
+            continue;
+        }
+
+        let denominator = (settings.fps * 1000.0).round().max(1.0) as u32;
+        let fps = match device.s_parm(1000, denominator) {
+            Ok((n, d)) if n > 0 && d > 0 => Some(d as f64 / n as f64),
+            _ => None,
+        };
+        if format == PixelFormat::Mjpeg {
+            // 圧縮率を変えられないカメラも多いので失敗は無視する
+            let _ = device.s_ctrl(CID_JPEG_COMPRESSION_QUALITY, settings.quality as i32);
+        }
+        let bytesperline = if actual.bytesperline == 0 && format == PixelFormat::Yuyv {
+            actual.width * 2
+        } else {
+            actual.bytesperline
+        };
+        return Ok(Negotiated {
+            format,
+            width: actual.width,
+            height: actual.height,
+            bytesperline,
+            fps,
+        });
+    }
+
+    if let Some(err) = rejected {
+        anyhow::bail!("{} rejected every usable format: {}", cap.card, err);
+    }
+    let offered: Vec<String> = offered.into_iter().map(fourcc_name).collect();
+    anyhow::bail!(
+        "{} offers none of MJPG, JPEG or YUYV (offered: {})",
+        cap.card,
+        offered.join(", ")
+    )
+}
+
+// Packed YUYV (Y0 U Y1 V) to a JPEG at `quality`
+pub fn encode_yuyv(data: &[u8], negotiated: &Negotiated, quality: u8) -> Result<Vec<u8>> {
+    let (width, height) = (negotiated.width as usize, negotiated.height as usize);
+    let stride = negotiated.bytesperline as usize;
+    if width % 2 != 0 || height == 0 || stride < width * 2 || data.len() < stride * (height - 1) + width * 2 {
+        anyhow::bail!("YUYV frame of {} bytes does not match {}x{}", data.len(), width, height);
+    }
+
+    let mut ycbcr = Vec::with_capacity(width * height * 3);
+    for row in data.chunks(stride).take(height) {
+        for pair in row[..width * 2].chunks_exact(4) {
+            let (y0, u, y1, v) = (pair[0], pair[1], pair[2], pair[3]);
+            ycbcr.extend_from_slice(&[y0, u, v, y1, u, v]);
+        }
+    }
+
+    let mut jpeg = Vec::new();
+    Encoder::new(&mut jpeg, quality).encode(&ycbcr, width as u16, height as u16, ColorType::Ycbcr)?;
+    Ok(jpeg)
+}
+
+// Capture from a V4L2 device (or a mock of its ioctls)
+pub struct V4l2Source<D: Ioctl> {
+    device: D,
+    negotiated: Option<Negotiated>,
+    quality: u8,
+}
+
+impl<D: Ioctl> V4l2Source<D> {
+    pub fn new(device: D) -> Self {
+        Self {
+            device,
+            negotiated: None,
+            quality: CaptureSettings::default().quality,
+        }
+    }
+
+    pub fn negotiated(&self) -> Option<&Negotiated> {
+        self.negotiated.as_ref()
+    }
+}
+
+impl<D: Ioctl> FrameSource for V4l2Source<D> {
+    // ストリーミング開始後は形式を変えられないので最初の設定だけが有効
+    fn configure(&mut self, settings: &CaptureSettings) -> Result<()> {
+        self.quality = settings.quality;
+        if self.negotiated.is_some() {
+            return Ok(());
+        }
+        let negotiated = negotiate(&mut self.device, settings)?;
+        self.device.stream_on(BUFFER_COUNT)?;
+        tracing::info!(
+            format = ?negotiated.format,
+            width = negotiated.width,
+            height = negotiated.height,
+            fps = ?negotiated.fps,
+            "V4L2 capture started"
+        );
+        self.negotiated = Some(negotiated);
+        Ok(())
+    }
+
+    fn capture_frame(&mut self) -> Result<Vec<u8>> {
+        let Some(negotiated) = self.negotiated else {
+            anyhow::bail!("V4L2 capture is not configured");
+        };
+        let data = self.device.dequeue()?;
+        match negotiated.format {
+            PixelFormat::Mjpeg if data.is_empty() => anyhow::bail!("Camera returned an empty frame"),
+            PixelFormat::Mjpeg => Ok(data),
+            PixelFormat::Yuyv => encode_yuyv(&data, &negotiated, self.quality),
+        }
+    }
+}
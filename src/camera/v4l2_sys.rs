@@ -0,0 +1,411 @@
+// src/camera/v4l2_sys.rs
+// Raw V4L2 ioctls for a real `/dev/video*` node (Linux only)
+use super::v4l2::{Capability, Ioctl, PixFormat};
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem::size_of;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+
+const BUF_TYPE_VIDEO_CAPTURE: u32 = 1;
+const MEMORY_MMAP: u32 = 1;
+const FIELD_ANY: u32 = 0;
+
+// 1フレームを待つ上限（ミリ秒）
+const DEQUEUE_TIMEOUT_MS: i32 = 2000;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct v4l2_capability {
+    driver: [u8; 16],
+    card: [u8; 32],
+    bus_info: [u8; 32],
+    version: u32,
+    capabilities: u32,
+    device_caps: u32,
+    reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct v4l2_fmtdesc {
+    index: u32,
+    type_: u32,
+    flags: u32,
+    description: [u8; 32],
+    pixelformat: u32,
+    mbus_code: u32,
+    reserved: [u32; 3],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct v4l2_pix_format {
+    width: u32,
+    height: u32,
+    pixelformat: u32,
+    field: u32,
+    bytesperline: u32,
+    sizeimage: u32,
+    colorspace: u32,
+    priv_: u32,
+    flags: u32,
+    ycbcr_enc: u32,
+    quantization: u32,
+    xfer_func: u32,
+}
+
+// カーネル側の union はポインタを含むので 8 バイト境界に揃う
+#[repr(C)]
+#[derive(Clone, Copy)]
+union v4l2_format_fmt {
+    pix: v4l2_pix_format,
+    raw_data: [u8; 200],
+    _align: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct v4l2_format {
+    type_: u32,
+    fmt: v4l2_format_fmt,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct v4l2_fract {
+    numerator: u32,
+    denominator: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct v4l2_captureparm {
+    capability: u32,
+    capturemode: u32,
+    timeperframe: v4l2_fract,
+    extendedmode: u32,
+    readbuffers: u32,
+    reserved: [u32; 4],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+union v4l2_streamparm_parm {
+    capture: v4l2_captureparm,
+    raw_data: [u8; 200],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct v4l2_streamparm {
+    type_: u32,
+    parm: v4l2_streamparm_parm,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct v4l2_control {
+    id: u32,
+    value: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct v4l2_requestbuffers {
+    count: u32,
+    type_: u32,
+    memory: u32,
+    capabilities: u32,
+    flags: u8,
+    reserved: [u8; 3],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct v4l2_timecode {
+    type_: u32,
+    flags: u32,
+    frames: u8,
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    userbits: [u8; 4],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+union v4l2_buffer_m {
+    offset: u32,
+    userptr: libc::c_ulong,
+    planes: *mut libc::c_void,
+    fd: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct v4l2_buffer {
+    index: u32,
+    type_: u32,
+    bytesused: u32,
+    flags: u32,
+    field: u32,
+    timestamp: libc::timeval,
+    timecode: v4l2_timecode,
+    sequence: u32,
+    memory: u32,
+    m: v4l2_buffer_m,
+    length: u32,
+    reserved2: u32,
+    request_fd: i32,
+}
+
+#[cfg(target_pointer_width = "64")]
+const _: () = {
+    assert!(size_of::<v4l2_capability>() == 104);
+    assert!(size_of::<v4l2_fmtdesc>() == 64);
+    assert!(size_of::<v4l2_format>() == 208);
+    assert!(size_of::<v4l2_streamparm>() == 204);
+    assert!(size_of::<v4l2_requestbuffers>() == 20);
+    assert!(size_of::<v4l2_buffer>() == 88);
+};
+
+// Linux の _IOC エンコーディング
+const fn ioc(dir: u32, nr: u32, size: usize) -> u32 {
+    (dir << 30) | ((size as u32) << 16) | ((b'V' as u32) << 8) | nr
+}
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+const VIDIOC_QUERYCAP: u32 = ioc(IOC_READ, 0, size_of::<v4l2_capability>());
+const VIDIOC_ENUM_FMT: u32 = ioc(IOC_READ | IOC_WRITE, 2, size_of::<v4l2_fmtdesc>());
+const VIDIOC_S_FMT: u32 = ioc(IOC_READ | IOC_WRITE, 5, size_of::<v4l2_format>());
+const VIDIOC_REQBUFS: u32 = ioc(IOC_READ | IOC_WRITE, 8, size_of::<v4l2_requestbuffers>());
+const VIDIOC_QUERYBUF: u32 = ioc(IOC_READ | IOC_WRITE, 9, size_of::<v4l2_buffer>());
+const VIDIOC_QBUF: u32 = ioc(IOC_READ | IOC_WRITE, 15, size_of::<v4l2_buffer>());
+const VIDIOC_DQBUF: u32 = ioc(IOC_READ | IOC_WRITE, 17, size_of::<v4l2_buffer>());
+const VIDIOC_STREAMON: u32 = ioc(IOC_WRITE, 18, size_of::<libc::c_int>());
+const VIDIOC_STREAMOFF: u32 = ioc(IOC_WRITE, 19, size_of::<libc::c_int>());
+const VIDIOC_S_PARM: u32 = ioc(IOC_READ | IOC_WRITE, 22, size_of::<v4l2_streamparm>());
+const VIDIOC_S_CTRL: u32 = ioc(IOC_READ | IOC_WRITE, 28, size_of::<v4l2_control>());
+
+fn zeroed<T: Copy>() -> T {
+    // SAFETY: only used for the plain-old-data V4L2 structs above
+    unsafe { std::mem::zeroed() }
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+pub struct Device {
+    file: File,
+    path: String,
+    buffers: Vec<Mapping>,
+    streaming: bool,
+}
+
+// SAFETY: the mmap'ed buffers belong to this device and are only touched
+// through `&mut self`
+unsafe impl Send for Device {}
+
+impl Device {
+    pub fn open(device_id: i32) -> Result<Self> {
+        let path = format!("/dev/video{}", device_id);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .with_context(|| format!("Failed to open camera {}", path))?;
+        Ok(Self {
+            file,
+            path,
+            buffers: Vec::new(),
+            streaming: false,
+        })
+    }
+
+    fn ioctl<T>(&self, request: u32, arg: &mut T) -> io::Result<()> {
+        loop {
+            // SAFETY: `arg` is the struct `request` encodes the size of
+            let ret = unsafe { libc::ioctl(self.file.as_raw_fd(), request as _, arg as *mut T) };
+            if ret != -1 {
+                return Ok(());
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+
+    fn wait_readable(&self) -> io::Result<()> {
+        let mut pollfd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        loop {
+            // SAFETY: one valid pollfd
+            match unsafe { libc::poll(&mut pollfd, 1, DEQUEUE_TIMEOUT_MS) } {
+                -1 => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+                0 => return Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} sent no frame", self.path))),
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn queue(&self, index: u32) -> io::Result<()> {
+        let mut buffer: v4l2_buffer = zeroed();
+        buffer.type_ = BUF_TYPE_VIDEO_CAPTURE;
+        buffer.memory = MEMORY_MMAP;
+        buffer.index = index;
+        self.ioctl(VIDIOC_QBUF, &mut buffer)
+    }
+}
+
+impl Ioctl for Device {
+    fn querycap(&mut self) -> io::Result<Capability> {
+        let mut cap: v4l2_capability = zeroed();
+        self.ioctl(VIDIOC_QUERYCAP, &mut cap)?;
+        Ok(Capability {
+            driver: c_string(&cap.driver),
+            card: c_string(&cap.card),
+            capabilities: cap.capabilities,
+            device_caps: cap.device_caps,
+        })
+    }
+
+    fn enum_fmt(&mut self, index: u32) -> io::Result<Option<u32>> {
+        let mut desc: v4l2_fmtdesc = zeroed();
+        desc.index = index;
+        desc.type_ = BUF_TYPE_VIDEO_CAPTURE;
+        match self.ioctl(VIDIOC_ENUM_FMT, &mut desc) {
+            Ok(()) => Ok(Some(desc.pixelformat)),
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn s_fmt(&mut self, format: PixFormat) -> io::Result<PixFormat> {
+        let mut fmt: v4l2_format = zeroed();
+        fmt.type_ = BUF_TYPE_VIDEO_CAPTURE;
+        let mut pix: v4l2_pix_format = zeroed();
+        pix.width = format.width;
+        pix.height = format.height;
+        pix.pixelformat = format.pixelformat;
+        pix.field = FIELD_ANY;
+        fmt.fmt.pix = pix;
+        self.ioctl(VIDIOC_S_FMT, &mut fmt)?;
+        // SAFETY: the driver fills `pix` for capture buffers
+        let pix = unsafe { fmt.fmt.pix };
+        Ok(PixFormat {
+            width: pix.width,
+            height: pix.height,
+            pixelformat: pix.pixelformat,
+            bytesperline: pix.bytesperline,
+            sizeimage: pix.sizeimage,
+        })
+    }
+
+    fn s_parm(&mut self, numerator: u32, denominator: u32) -> io::Result<(u32, u32)> {
+        let mut parm: v4l2_streamparm = zeroed();
+        parm.type_ = BUF_TYPE_VIDEO_CAPTURE;
+        let mut capture: v4l2_captureparm = zeroed();
+        capture.timeperframe = v4l2_fract { numerator, denominator };
+        parm.parm.capture = capture;
+        self.ioctl(VIDIOC_S_PARM, &mut parm)?;
+        // SAFETY: the driver fills `capture` for capture buffers
+        let chosen = unsafe { parm.parm.capture.timeperframe };
+        Ok((chosen.numerator, chosen.denominator))
+    }
+
+    fn s_ctrl(&mut self, id: u32, value: i32) -> io::Result<()> {
+        let mut control = v4l2_control { id, value };
+        self.ioctl(VIDIOC_S_CTRL, &mut control)
+    }
+
+    fn stream_on(&mut self, buffers: u32) -> io::Result<()> {
+        let mut request: v4l2_requestbuffers = zeroed();
+        request.count = buffers;
+        request.type_ = BUF_TYPE_VIDEO_CAPTURE;
+        request.memory = MEMORY_MMAP;
+        self.ioctl(VIDIOC_REQBUFS, &mut request)?;
+        if request.count == 0 {
+            return Err(io::Error::other(format!("{} allocated no buffers", self.path)));
+        }
+
+        for index in 0..request.count {
+            let mut buffer: v4l2_buffer = zeroed();
+            buffer.type_ = BUF_TYPE_VIDEO_CAPTURE;
+            buffer.memory = MEMORY_MMAP;
+            buffer.index = index;
+            self.ioctl(VIDIOC_QUERYBUF, &mut buffer)?;
+            let len = buffer.length as usize;
+            // SAFETY: offset and length come from VIDIOC_QUERYBUF for this fd
+            let ptr = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    self.file.as_raw_fd(),
+                    buffer.m.offset as libc::off_t,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            self.buffers.push(Mapping { ptr, len });
+            self.queue(index)?;
+        }
+
+        let mut buf_type = BUF_TYPE_VIDEO_CAPTURE as libc::c_int;
+        self.ioctl(VIDIOC_STREAMON, &mut buf_type)?;
+        self.streaming = true;
+        Ok(())
+    }
+
+    fn dequeue(&mut self) -> io::Result<Vec<u8>> {
+        self.wait_readable()?;
+        let mut buffer: v4l2_buffer = zeroed();
+        buffer.type_ = BUF_TYPE_VIDEO_CAPTURE;
+        buffer.memory = MEMORY_MMAP;
+        self.ioctl(VIDIOC_DQBUF, &mut buffer)?;
+
+        let Some(mapping) = self.buffers.get(buffer.index as usize) else {
+            return Err(io::Error::other("driver returned an unknown buffer"));
+        };
+        let used = (buffer.bytesused as usize).min(mapping.len);
+        // SAFETY: the buffer is dequeued, so the driver is not writing to it
+        let frame = unsafe { std::slice::from_raw_parts(mapping.ptr as *const u8, used) }.to_vec();
+        self.queue(buffer.index)?;
+        Ok(frame)
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        if self.streaming {
+            let mut buf_type = BUF_TYPE_VIDEO_CAPTURE as libc::c_int;
+            let _ = self.ioctl(VIDIOC_STREAMOFF, &mut buf_type);
+        }
+        for mapping in self.buffers.drain(..) {
+            // SAFETY: mapped in stream_on and no longer referenced
+            unsafe { libc::munmap(mapping.ptr, mapping.len) };
+        }
+    }
+}
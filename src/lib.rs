@@ -1,5 +1,7 @@
 // src/lib.rs
 pub mod camera;
+pub mod logging;
+pub mod protocol;
 pub mod websocket;
 pub mod server;
 
@@ -9,17 +11,18 @@ mod tests {
     use crate::websocket::spawn_test_websocket;
     use crate::server::{Server, spawn_camera_client, spawn_viewer_client, dummy_frame};
     use std::time::{Instant, Duration};
+    use futures::FutureExt;
 
     // Camera tests
     #[test]
     fn camera_initializes_successfully() {
-        let camera = Camera::new(0).unwrap();
+        let camera = Camera::synthetic();
         assert!(camera.is_open());
     }
 
     #[test]
     fn camera_grabs_single_frame() {
-        let mut camera = Camera::new(0).unwrap();
+        let mut camera = Camera::synthetic();
         let frame = camera.capture_frame().unwrap();
         assert!(!frame.is_empty());
         assert!(frame.len() > 1000);
@@ -28,8 +31,7 @@ mod tests {
     // Camera parameters tests
     #[test]
     fn camera_applies_fps_setting() {
-        let mut camera = Camera::new(0)
-            .unwrap()
+        let mut camera = Camera::synthetic()
             .fps(10.0)
             .quality(80)
             .build()
@@ -46,8 +48,8 @@ mod tests {
 
     #[test]
     fn camera_applies_quality_setting() {
-        let mut high_quality = Camera::new(0).unwrap().quality(90).build().unwrap();
-        let mut low_quality = Camera::new(0).unwrap().quality(50).build().unwrap();
+        let mut high_quality = Camera::synthetic().quality(90).build().unwrap();
+        let mut low_quality = Camera::synthetic().quality(50).build().unwrap();
         
         let high_frame = high_quality.capture_frame().unwrap();
         let low_frame = low_quality.capture_frame().unwrap();
@@ -59,7 +61,7 @@ mod tests {
 
     #[test]
     fn camera_clamps_quality_values() {
-        let mut camera = Camera::new(0).unwrap()
+        let mut camera = Camera::synthetic()
             .quality(5)
             .quality(120)
             .build()
@@ -71,7 +73,7 @@ mod tests {
 
     #[test]
     fn camera_fps_zero_is_clamped() {
-        let mut camera = Camera::new(0).unwrap()
+        let mut camera = Camera::synthetic()
             .fps(0.0)
             .quality(80)
             .build()
@@ -84,14 +86,32 @@ mod tests {
         assert_eq!(frame1.len(), frame2.len());
     }
 
+    #[test]
+    fn camera_fps_rejects_non_finite_and_caps_the_rate() {
+        use crate::camera::pacing::{frame_period, MIN_FRAME_PERIOD};
+        use crate::camera::MAX_FPS;
+
+        assert_eq!(Camera::synthetic().fps(f64::NAN).settings().fps, 1.0);
+        assert_eq!(Camera::synthetic().fps(f64::INFINITY).settings().fps, 1.0);
+        assert_eq!(Camera::synthetic().fps(1e10).settings().fps, MAX_FPS);
+
+        // 直接渡されても周期が 0 にならず、NaN でも落ちない
+        assert_eq!(frame_period(1e10), MIN_FRAME_PERIOD);
+        assert_eq!(frame_period(f64::INFINITY), MIN_FRAME_PERIOD);
+        assert_eq!(frame_period(f64::NAN), Duration::from_secs(1));
+        assert_eq!(frame_period(-5.0), Duration::from_secs(1));
+        assert_eq!(frame_period(50.0), Duration::from_millis(20));
+    }
+
     // WebSocket tests
     #[tokio::test]
     async fn websocket_sends_binary_frame() {
-        let (server, client) = spawn_test_websocket();
+        let (server, mut client) = spawn_test_websocket().await.unwrap();
         let test_frame = vec![0u8; 1024];
         server.send_frame(&test_frame).unwrap();
-        let received = client.receive_binary().unwrap();
+        let received = client.receive_binary().await.unwrap();
         assert_eq!(received, test_frame);
+        server.shutdown().await.unwrap();
     }
 
     #[tokio::test]
@@ -156,22 +176,23 @@ mod tests {
         }
         
         // すべてのフレームを受信して検証
-        for i in 0..frame_count {
+        for sent in &sent_frames {
             let received = rx.recv().await.unwrap();
-            assert_eq!(received, sent_frames[i]);
+            assert_eq!(&received, sent);
         }
     }
 
     #[tokio::test]
     async fn server_accepts_camera_and_viewer_connections() {
-        let _server = Server::new("127.0.0.1:9001").await.unwrap();
+        let server = Server::new("127.0.0.1:0").await.unwrap().spawn();
+        let addr = server.local_addr();
         
-        let camera_client = spawn_camera_client("ws://127.0.0.1:9001/camera");
-        let viewer_client = spawn_viewer_client("ws://127.0.0.1:9001/view");
+        let mut camera_client = spawn_camera_client(&format!("ws://{}/camera", addr)).await.unwrap();
+        let mut viewer_client = spawn_viewer_client(&format!("ws://{}/view", addr)).await.unwrap();
         
         camera_client.send_frame(&dummy_frame()).await.unwrap();
         let received = viewer_client.receive_frame().await.unwrap();
-        assert_eq!(received.len(), 1024);
+        assert_eq!(received, dummy_frame());
     }
 
     #[tokio::test]
@@ -227,7 +248,7 @@ mod tests {
         
         // JPEG フォーマットのシミュレートされたフレーム
         let mut jpeg_frame = vec![0xFFu8, 0xD8u8, 0xFFu8]; // JPEG SOI
-        jpeg_frame.extend_from_slice(&vec![0x42u8; 100]); // ダミーデータ
+        jpeg_frame.extend_from_slice(&[0x42u8; 100]); // ダミーデータ
         jpeg_frame.extend_from_slice(&[0xFFu8, 0xD9u8]); // JPEG EOI
         
         // カメラクライアントがブロードキャスト
@@ -301,4 +322,2250 @@ mod tests {
         assert_eq!(frame[frame.len() - 1], 0xD9);
         assert!(frame.len() > 100);
     }
+
+    // Stream registry tests
+    #[test]
+    fn websocket_routes_parse_stream_ids() {
+        use crate::server::{route_websocket, WsRoute, DEFAULT_STREAM};
+
+        assert_eq!(route_websocket("/camera"), Some(WsRoute::Camera(DEFAULT_STREAM.to_string())));
+        assert_eq!(route_websocket("/view"), Some(WsRoute::View(DEFAULT_STREAM.to_string())));
+        assert_eq!(route_websocket("/camera/front-door"), Some(WsRoute::Camera("front-door".to_string())));
+        assert_eq!(route_websocket("/view/cam_2"), Some(WsRoute::View("cam_2".to_string())));
+        assert_eq!(route_websocket("/camera/"), None);
+        assert_eq!(route_websocket("/view/a/b"), None);
+        assert_eq!(route_websocket("/view/.."), None);
+        assert_eq!(route_websocket("/viewer.html"), None);
+    }
+
+    #[tokio::test]
+    async fn registry_isolates_streams() {
+        use crate::server::{DeliveryMode, PublisherPolicy, StreamRegistry};
+
+        let registry = StreamRegistry::new(10);
+        let cam_a = registry.publish("a", PublisherPolicy::Merge).unwrap();
+        let cam_b = registry.publish("b", PublisherPolicy::Merge).unwrap();
+        let mut viewer_a = registry.subscribe("a", DeliveryMode::Queue);
+        let mut viewer_b = registry.subscribe("b", DeliveryMode::Queue);
+
+        cam_a.send(vec![1u8; 16]);
+        cam_b.send(vec![2u8; 16]);
+
+        assert_eq!(*viewer_a.next_frame().await.unwrap().data.payload(), vec![1u8; 16]);
+        assert_eq!(*viewer_b.next_frame().await.unwrap().data.payload(), vec![2u8; 16]);
+        assert!(viewer_a.next_frame().now_or_never().is_none());
+    }
+
+    #[test]
+    fn registry_delivers_frames_from_concurrent_publishers_in_seq_order() {
+        use crate::server::{DeliveryMode, PublisherPolicy, StreamRegistry};
+
+        const PUBLISHERS: usize = 4;
+        const FRAMES: usize = 2000;
+        let registry = StreamRegistry::new(PUBLISHERS * FRAMES);
+        let mut viewer = registry.subscribe("cam", DeliveryMode::Queue);
+        let threads: Vec<_> = (0..PUBLISHERS)
+            .map(|_| {
+                let publisher = registry.publish("cam", PublisherPolicy::Merge).unwrap();
+                std::thread::spawn(move || {
+                    for _ in 0..FRAMES {
+                        publisher.send(vec![0u8; 4]);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut seqs = Vec::new();
+        while let Some(Some(frame)) = viewer.next_frame().now_or_never() {
+            assert_eq!(frame.skipped, 0);
+            seqs.push(frame.data.header().seq);
+        }
+        assert_eq!(seqs.len(), PUBLISHERS * FRAMES);
+        assert!(seqs.windows(2).all(|w| w[1] == w[0] + 1), "viewer saw seq out of order");
+    }
+
+    #[test]
+    fn registry_late_joiners_miss_no_frame_published_while_subscribing() {
+        use crate::server::{DeliveryMode, PublisherPolicy, StreamRegistry};
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let registry = StreamRegistry::new(1 << 12);
+        let stop = Arc::new(AtomicBool::new(false));
+        let senders: Vec<_> = (0..3)
+            .map(|_| {
+                let publisher = registry.publish("cam", PublisherPolicy::Merge).unwrap();
+                let stop = stop.clone();
+                std::thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        publisher.send(vec![0u8; 4]);
+                        std::thread::yield_now();
+                    }
+                })
+            })
+            .collect();
+
+        // 購読の途中で配信されたフレームも、接続時のキャッシュの次として必ず届く
+        for _ in 0..20_000 {
+            let mut viewer = registry.subscribe("cam", DeliveryMode::Queue);
+            let cached = viewer.take_cached_frame(Duration::from_secs(3600)).map(|f| f.header().seq);
+            let next = futures::executor::block_on(viewer.next_frame()).unwrap();
+            assert_eq!(next.skipped, 0);
+            assert_eq!(next.data.header().seq, cached.map_or(0, |seq| seq + 1));
+        }
+        stop.store(true, Ordering::Relaxed);
+        for sender in senders {
+            sender.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn registry_creates_streams_lazily_and_tears_them_down() {
+        use crate::server::{DeliveryMode, PublisherPolicy, StreamRegistry};
+
+        let registry = StreamRegistry::new(10);
+        assert!(registry.stream_ids().is_empty());
+
+        let viewer = registry.subscribe("lobby", DeliveryMode::Queue);
+        assert!(registry.contains("lobby"));
+        let publisher = registry.publish("lobby", PublisherPolicy::Merge).unwrap();
+
+        drop(viewer);
+        assert!(registry.contains("lobby"));
+        drop(publisher);
+        assert!(!registry.contains("lobby"));
+        assert_eq!(registry.send("lobby", vec![0u8; 4]), 0);
+    }
+
+    // HTTP request parsing tests
+    #[tokio::test]
+    async fn http_request_split_across_segments_is_parsed() {
+        use crate::server::http::RequestReader;
+        use tokio::io::AsyncWriteExt;
+
+        let (mut client, server) = tokio::io::duplex(64);
+        tokio::spawn(async move {
+            for chunk in ["GET /view/lob", "by?token=a%20b&x HTTP/1.1\r\nHo", "st: example\r\nX-Long: ", "value\r\n", "\r\n"] {
+                client.write_all(chunk.as_bytes()).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+
+        let mut reader = RequestReader::new(server);
+        let request = reader.read_request().await.unwrap().unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/view/lobby");
+        assert_eq!(request.query_param("token").as_deref(), Some("a b"));
+        assert_eq!(request.query_param("x").as_deref(), Some(""));
+        assert_eq!(request.header("x-long"), Some("value"));
+        assert!(reader.read_request().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn http_pipelined_requests_are_read_in_order() {
+        use crate::server::http::RequestReader;
+
+        let input: &[u8] = b"POST /a HTTP/1.1\r\nHost: h\r\nContent-Length: 3\r\n\r\nabcGET /b HTTP/1.1\r\nHost: h\r\n\r\n";
+        let mut reader = RequestReader::new(input);
+        let first = reader.read_request().await.unwrap().unwrap();
+        assert_eq!(first.path, "/a");
+        assert_eq!(first.body, b"abc");
+        let second = reader.read_request().await.unwrap().unwrap();
+        assert_eq!(second.method, "GET");
+        assert_eq!(second.path, "/b");
+        assert!(reader.read_request().await.unwrap().is_none());
+    }
+
+    #[test]
+    fn http_rejects_bad_requests_with_matching_status() {
+        use crate::server::http::{parse_request, MAX_HEADER_BYTES, MAX_URI_LEN};
+
+        let status = |buf: &[u8]| parse_request(buf).err().and_then(|e| e.status());
+
+        assert_eq!(status(b"GARBAGE\r\n\r\n"), Some(400));
+        assert_eq!(status(b"GET /x HTTP/1.1\r\n\r\n"), Some(400)); // Host がない
+        assert_eq!(status(b"GET http://h/x HTTP/1.1\r\nHost: h\r\n\r\n"), Some(400));
+
+        let long_uri = format!("GET /{} HTTP/1.1\r\nHost: h\r\n\r\n", "a".repeat(MAX_URI_LEN));
+        assert_eq!(status(long_uri.as_bytes()), Some(414));
+        let unterminated_uri = format!("GET /{}", "a".repeat(MAX_URI_LEN + 64));
+        assert_eq!(status(unterminated_uri.as_bytes()), Some(414));
+
+        let big_header = format!("GET / HTTP/1.1\r\nHost: h\r\nX-Big: {}\r\n", "a".repeat(MAX_HEADER_BYTES));
+        assert_eq!(status(big_header.as_bytes()), Some(431));
+
+        assert!(parse_request(b"GET / HTTP/1.1\r\nHost: h\r\n").unwrap().is_none());
+    }
+
+    // Static HTTP file serving tests
+    fn get_request(method: &str, path: &str, headers: &[(&str, &str)]) -> crate::server::http::Request {
+        crate::server::http::Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: None,
+            version: 1,
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            body: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn static_pages_support_etag_revalidation() {
+        use crate::server::static_files::serve;
+
+        let first = serve(&get_request("GET", "/viewer.html", &[]), None).await;
+        assert_eq!(first.status, 200);
+        let etag = first.headers.iter().find(|(k, _)| k == "ETag").unwrap().1.clone();
+
+        let cached = serve(&get_request("GET", "/viewer.html", &[("If-None-Match", &etag)]), None).await;
+        assert_eq!(cached.status, 304);
+        assert!(cached.body.is_empty());
+        assert!(!String::from_utf8(cached.to_bytes()).unwrap().contains("Content-Length"));
+
+        let other = serve(&get_request("GET", "/", &[("If-None-Match", &etag)]), None).await;
+        assert_eq!(other.status, 200);
+    }
+
+    #[tokio::test]
+    async fn static_pages_answer_head_405_and_404() {
+        use crate::server::static_files::serve;
+
+        let head = serve(&get_request("HEAD", "/", &[]), None).await;
+        let get = serve(&get_request("GET", "/", &[]), None).await;
+        let head_bytes = String::from_utf8(head.head_bytes()).unwrap();
+        assert!(head_bytes.ends_with("\r\n\r\n"));
+        assert!(head_bytes.contains(&format!("Content-Length: {}", get.body.len())));
+
+        let post = serve(&get_request("POST", "/", &[]), None).await;
+        assert_eq!(post.status, 405);
+        assert!(post.headers.iter().any(|(k, v)| k == "Allow" && v == "GET, HEAD"));
+
+        assert_eq!(serve(&get_request("GET", "/missing.js", &[]), None).await.status, 404);
+    }
+
+    #[test]
+    fn http_keep_alive_follows_version_and_connection_header() {
+        assert!(get_request("GET", "/", &[]).wants_keep_alive());
+        assert!(!get_request("GET", "/", &[("Connection", "close")]).wants_keep_alive());
+
+        let mut http10 = get_request("GET", "/", &[]);
+        http10.version = 0;
+        assert!(!http10.wants_keep_alive());
+        http10.headers.push(("Connection".to_string(), "Keep-Alive".to_string()));
+        assert!(http10.wants_keep_alive());
+    }
+
+    // ファイルのボディは書き出すまで読まれないので、実際に書いて取り出す
+    async fn written(response: crate::server::http::Response, head_only: bool) -> (String, Vec<u8>) {
+        let mut out = Vec::new();
+        response.write_to(&mut out, head_only).await.unwrap();
+        let split = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let body = out.split_off(split);
+        (String::from_utf8(out).unwrap(), body)
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("web2ws-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn static_dir_serves_files_with_mime_and_falls_back_to_embedded() {
+        use crate::server::static_files::serve;
+
+        let dir = temp_dir("static-serve");
+        std::fs::create_dir_all(dir.join("js")).unwrap();
+        std::fs::write(dir.join("js/app.js"), "console.log(1);").unwrap();
+        std::fs::write(dir.join("viewer.html"), "<h1>branded</h1>").unwrap();
+
+        let js = serve(&get_request("GET", "/js/app.js", &[]), Some(&dir)).await;
+        assert_eq!(js.status, 200);
+        assert!(js.headers.iter().any(|(k, v)| k == "Content-Type" && v.starts_with("text/javascript")));
+        assert_eq!(written(js, false).await.1, b"console.log(1);");
+
+        let viewer = serve(&get_request("GET", "/viewer.html", &[]), Some(&dir)).await;
+        assert_eq!(written(viewer, false).await.1, b"<h1>branded</h1>");
+
+        // ディスクにないページは埋め込み版を返す
+        let sender = serve(&get_request("GET", "/", &[]), Some(&dir)).await;
+        assert_eq!(sender.status, 200);
+        assert!(String::from_utf8_lossy(&sender.body).contains("Camera Sender"));
+
+        assert_eq!(serve(&get_request("GET", "/js/missing.js", &[]), Some(&dir)).await.status, 404);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn static_dir_rejects_path_traversal() {
+        use crate::server::static_files::{resolve_path, serve};
+
+        let root = temp_dir("static-traversal");
+        let dir = root.join("public");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(root.join("secret.txt"), "secret").unwrap();
+        std::fs::write(dir.join("ok.txt"), "ok").unwrap();
+
+        assert!(resolve_path(&dir, "/ok.txt").await.is_some());
+        assert!(resolve_path(&dir, "/../secret.txt").await.is_none());
+        assert!(resolve_path(&dir, "/%2e%2e/secret.txt").await.is_none());
+        assert!(resolve_path(&dir, "/..%2fsecret.txt").await.is_none());
+        assert!(resolve_path(&dir, "/..%5csecret.txt").await.is_none());
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("secret.txt"), dir.join("link.txt")).unwrap();
+            assert!(resolve_path(&dir, "/link.txt").await.is_none());
+        }
+
+        let response = serve(&get_request("GET", "/../secret.txt", &[]), Some(&dir)).await;
+        assert_eq!(response.status, 404);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn static_dir_supports_range_requests() {
+        use crate::server::static_files::{parse_range, serve, ByteRange};
+
+        assert_eq!(parse_range("bytes=0-3", 10), ByteRange::Partial(0, 3));
+        assert_eq!(parse_range("bytes=7-", 10), ByteRange::Partial(7, 9));
+        assert_eq!(parse_range("bytes=-4", 10), ByteRange::Partial(6, 9));
+        assert_eq!(parse_range("bytes=5-100", 10), ByteRange::Partial(5, 9));
+        assert_eq!(parse_range("bytes=10-", 10), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,4-5", 10), ByteRange::Ignored);
+        assert_eq!(parse_range("items=0-1", 10), ByteRange::Ignored);
+
+        let dir = temp_dir("static-range");
+        std::fs::write(dir.join("clip.mjpeg"), b"0123456789").unwrap();
+
+        let partial = serve(&get_request("GET", "/clip.mjpeg", &[("Range", "bytes=2-5")]), Some(&dir)).await;
+        assert_eq!(partial.status, 206);
+        assert!(partial.headers.iter().any(|(k, v)| k == "Content-Range" && v == "bytes 2-5/10"));
+        let (head, body) = written(partial, false).await;
+        assert!(head.contains("Content-Length: 4\r\n"));
+        assert_eq!(body, b"2345");
+
+        let unsatisfiable = serve(&get_request("GET", "/clip.mjpeg", &[("Range", "bytes=20-")]), Some(&dir)).await;
+        assert_eq!(unsatisfiable.status, 416);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn static_dir_streams_files_without_buffering_them() {
+        use crate::server::static_files::serve;
+
+        let dir = temp_dir("static-stream");
+        let content: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.join("big.bin"), &content).unwrap();
+
+        let get = serve(&get_request("GET", "/big.bin", &[]), Some(&dir)).await;
+        assert!(get.body.is_empty());
+        assert_eq!(get.content_length(), content.len() as u64);
+        let (head, body) = written(get, false).await;
+        assert!(head.contains(&format!("Content-Length: {}\r\n", content.len())));
+        assert!(body == content);
+
+        // HEAD は長さだけ返し、ボディは書かない
+        let head = serve(&get_request("HEAD", "/big.bin", &[("Range", "bytes=-10")]), Some(&dir)).await;
+        assert_eq!(head.status, 206);
+        let (head, body) = written(head, true).await;
+        assert!(head.contains("Content-Length: 10\r\n"));
+        assert!(body.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // TLS tests
+    async fn connect_with_retry(addr: &str) -> tokio::net::TcpStream {
+        for _ in 0..100 {
+            if let Ok(stream) = tokio::net::TcpStream::connect(addr).await {
+                return stream;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("server at {} did not start", addr);
+    }
+
+    #[tokio::test]
+    async fn tls_server_serves_https_and_wss() {
+        use futures::StreamExt;
+        use std::sync::Arc;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_rustls::rustls::{self, pki_types::ServerName};
+        use tokio_rustls::TlsConnector;
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = temp_dir("tls");
+        std::fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+
+        let server = Server::new("127.0.0.1:0")
+            .await
+            .unwrap()
+            .tls(&dir.join("cert.pem"), &dir.join("key.pem"))
+            .unwrap();
+        let publisher = server.publisher("default").unwrap();
+        let addr = server.spawn().local_addr().to_string();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+        let server_name = ServerName::try_from("localhost").unwrap();
+
+        // https://
+        let tcp = connect_with_retry(&addr).await;
+        let mut tls = connector.connect(server_name.clone(), tcp).await.unwrap();
+        tls.write_all(b"GET /viewer.html HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        tls.read_to_end(&mut response).await.unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Video Viewer"));
+
+        // wss://
+        let tcp = connect_with_retry(&addr).await;
+        let tls = connector.connect(server_name, tcp).await.unwrap();
+        let (mut ws, _) = tokio_tungstenite::client_async("wss://localhost/view", tls).await.unwrap();
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                publisher.send(vec![7u8; 32]);
+                if let Ok(Some(msg)) = tokio::time::timeout(Duration::from_millis(50), ws.next()).await {
+                    break msg.unwrap().into_data();
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(received, vec![7u8; 32]);
+
+        // 平文の HTTP は TLS リスナーでは応答されない
+        let mut plain = connect_with_retry(&addr).await;
+        plain.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut buf = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(2), plain.read_to_end(&mut buf)).await;
+        assert!(!String::from_utf8_lossy(&buf).starts_with("HTTP/1.1 200"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tls_config_rejects_missing_or_mismatched_pem() {
+        use crate::server::tls::server_config_from_pem;
+
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_pem = cert.cert.pem();
+
+        assert!(server_config_from_pem(cert_pem.as_bytes(), cert.key_pair.serialize_pem().as_bytes()).is_ok());
+        assert!(server_config_from_pem(b"", cert.key_pair.serialize_pem().as_bytes()).is_err());
+        assert!(server_config_from_pem(cert_pem.as_bytes(), b"").is_err());
+        assert!(server_config_from_pem(cert_pem.as_bytes(), other.key_pair.serialize_pem().as_bytes()).is_err());
+    }
+
+    // Authentication tests
+    #[test]
+    fn auth_checks_scope_and_stream() {
+        use crate::server::auth::{AuthDecision, Authenticator, Scope};
+
+        let open = Authenticator::new();
+        assert_eq!(open.check(Scope::Publish, "default", None), AuthDecision::Allow);
+
+        let mut auth = Authenticator::new();
+        auth.add_token_spec(Scope::Publish, "pub-all").unwrap();
+        auth.add_token_spec(Scope::View, "view-door@front-door,garage").unwrap();
+
+        assert_eq!(auth.check(Scope::Publish, "any", Some("pub-all")), AuthDecision::Allow);
+        assert_eq!(auth.check(Scope::Publish, "any", None), AuthDecision::Unauthorized);
+        assert_eq!(auth.check(Scope::Publish, "any", Some("nope")), AuthDecision::Unauthorized);
+        // 視聴用トークンでは配信できない
+        assert_eq!(auth.check(Scope::Publish, "front-door", Some("view-door")), AuthDecision::Forbidden);
+
+        assert_eq!(auth.check(Scope::View, "garage", Some("view-door")), AuthDecision::Allow);
+        assert_eq!(auth.check(Scope::View, "lobby", Some("view-door")), AuthDecision::Forbidden);
+        assert_eq!(auth.check(Scope::View, "lobby", Some("pub-all")), AuthDecision::Forbidden);
+    }
+
+    #[test]
+    fn auth_parses_tokens_file() {
+        use crate::server::auth::{AuthDecision, Authenticator, Scope};
+
+        let mut auth = Authenticator::new();
+        auth.parse_tokens("# comment\n\npublish cam-key front-door\nview shared *\n").unwrap();
+        assert_eq!(auth.check(Scope::Publish, "front-door", Some("cam-key")), AuthDecision::Allow);
+        assert_eq!(auth.check(Scope::Publish, "garage", Some("cam-key")), AuthDecision::Forbidden);
+        assert_eq!(auth.check(Scope::View, "garage", Some("shared")), AuthDecision::Allow);
+
+        assert!(Authenticator::new().parse_tokens("owner key").is_err());
+        let mut admin = Authenticator::new();
+        admin.parse_tokens("admin key").unwrap();
+        assert_eq!(admin.check(Scope::Admin, "", Some("key")), AuthDecision::Allow);
+        // 管理トークンはストリームで限定できない
+        assert!(admin.parse_tokens("admin other *").is_ok());
+        let err = Authenticator::new().parse_tokens("\nadmin key front-door").unwrap_err();
+        assert!(err.to_string().starts_with("line 2:"), "{}", err);
+        assert!(Authenticator::new().add_token_spec(Scope::Admin, "key@front-door").is_err());
+        assert!(Authenticator::new().parse_tokens("publish").is_err());
+    }
+
+    #[test]
+    fn auth_reads_bearer_header_before_query() {
+        use crate::server::auth::request_token;
+
+        let mut request = get_request("GET", "/view", &[("Authorization", "Bearer abc")]);
+        request.query = Some("token=xyz".to_string());
+        assert_eq!(request_token(&request).as_deref(), Some("abc"));
+        request.headers.clear();
+        assert_eq!(request_token(&request).as_deref(), Some("xyz"));
+        request.query = None;
+        assert_eq!(request_token(&request), None);
+    }
+
+    async fn upgrade_status(addr: &str, path: &str, extra_headers: &str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = connect_with_retry(addr).await;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n{}\r\n",
+            path, extra_headers
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut buf = [0u8; 256];
+        let n = stream.read(&mut buf).await.unwrap();
+        let head = String::from_utf8_lossy(&buf[..n]);
+        head.lines().next().unwrap_or_default().to_string()
+    }
+
+    #[tokio::test]
+    async fn auth_rejects_upgrades_before_accepting() {
+        use crate::server::auth::{Authenticator, Scope};
+
+        let mut auth = Authenticator::new();
+        auth.add_token_spec(Scope::Publish, "cam").unwrap();
+        auth.add_token_spec(Scope::View, "watch@lobby").unwrap();
+
+        let server = Server::new("127.0.0.1:0").await.unwrap().auth(auth);
+        let addr = server.spawn().local_addr().to_string();
+
+        assert_eq!(upgrade_status(&addr, "/view/lobby", "").await, "HTTP/1.1 401 Unauthorized");
+        assert_eq!(upgrade_status(&addr, "/view/lobby?token=wrong", "").await, "HTTP/1.1 401 Unauthorized");
+        assert_eq!(upgrade_status(&addr, "/view/other?token=watch", "").await, "HTTP/1.1 403 Forbidden");
+        assert_eq!(upgrade_status(&addr, "/camera/lobby?token=watch", "").await, "HTTP/1.1 403 Forbidden");
+        assert_eq!(upgrade_status(&addr, "/view/lobby?token=watch", "").await, "HTTP/1.1 101 Switching Protocols");
+        assert_eq!(
+            upgrade_status(&addr, "/camera/lobby", "Authorization: Bearer cam\r\n").await,
+            "HTTP/1.1 101 Switching Protocols"
+        );
+    }
+
+    // Publisher policy tests
+    #[tokio::test]
+    async fn registry_applies_publisher_policy() {
+        use crate::server::{DeliveryMode, PublishError, PublisherPolicy, StreamRegistry};
+
+        let registry = StreamRegistry::new(10);
+        let first = registry.publish("cam", PublisherPolicy::Reject).unwrap();
+        assert_eq!(
+            registry.publish("cam", PublisherPolicy::Reject).err(),
+            Some(PublishError::StreamBusy)
+        );
+
+        let mut viewer = registry.subscribe("cam", DeliveryMode::Queue);
+        let second = registry.publish("cam", PublisherPolicy::Replace).unwrap();
+        first.evicted().now_or_never().expect("first publisher should be evicted");
+        assert!(first.is_evicted());
+        assert_eq!(registry.publisher_count("cam"), 1);
+
+        // 置き換えられた配信者のフレームは配信されない
+        first.send(vec![1u8; 4]);
+        second.send(vec![2u8; 4]);
+        assert_eq!(*viewer.next_frame().await.unwrap().data.payload(), vec![2u8; 4]);
+
+        drop(first);
+        assert_eq!(registry.publisher_count("cam"), 1);
+        let _third = registry.publish("cam", PublisherPolicy::Merge).unwrap();
+        assert_eq!(registry.publisher_count("cam"), 2);
+        assert!(!second.is_evicted());
+    }
+
+    async fn next_close_code<S>(ws: &mut tokio_tungstenite::WebSocketStream<S>) -> Option<u16>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        use futures::StreamExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let wait = async {
+            while let Some(Ok(msg)) = ws.next().await {
+                if let Message::Close(frame) = msg {
+                    return frame.map(|f| u16::from(f.code));
+                }
+            }
+            None
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait).await.ok().flatten()
+    }
+
+    #[tokio::test]
+    async fn second_publisher_gets_policy_close_code() {
+        use crate::server::{PublisherPolicy, CLOSE_PUBLISHER_REJECTED, CLOSE_PUBLISHER_REPLACED};
+
+        let server = Server::new("127.0.0.1:0").await.unwrap().publisher_policy(PublisherPolicy::Reject);
+        let streams = server.streams();
+        let addr = server.spawn().local_addr().to_string();
+
+        let url = format!("ws://{}/camera/door", addr);
+        let (_first, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        while streams.publisher_count("door") == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let (mut second, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        assert_eq!(next_close_code(&mut second).await, Some(CLOSE_PUBLISHER_REJECTED));
+
+        let server = Server::new("127.0.0.1:0").await.unwrap().publisher_policy(PublisherPolicy::Replace);
+        let addr = server.spawn().local_addr().to_string();
+
+        let url = format!("ws://{}/camera/door", addr);
+        let (mut first, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let (_second, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        assert_eq!(next_close_code(&mut first).await, Some(CLOSE_PUBLISHER_REPLACED));
+    }
+
+    // Slow viewer tests
+    #[tokio::test]
+    async fn lagging_viewer_skips_to_latest_frame() {
+        use crate::server::{DeliveryMode, PublisherPolicy, StreamRegistry};
+
+        let registry = StreamRegistry::new(4);
+        let publisher = registry.publish("cam", PublisherPolicy::Merge).unwrap();
+        let mut viewer = registry.subscribe("cam", DeliveryMode::Queue);
+
+        for i in 0..10u8 {
+            publisher.send(vec![i]);
+        }
+        // 切断されずに最新のフレームへ飛ぶ
+        let received = viewer.next_frame().await.unwrap();
+        assert_eq!(*received.data.payload(), vec![9]);
+        assert_eq!(received.skipped, 9);
+        assert_eq!(viewer.dropped_frames(), 9);
+
+        publisher.send(vec![10]);
+        let received = viewer.next_frame().await.unwrap();
+        assert_eq!(*received.data.payload(), vec![10]);
+        assert_eq!(received.skipped, 0);
+    }
+
+    #[tokio::test]
+    async fn latest_mode_viewer_only_gets_newest_frame() {
+        use crate::server::{DeliveryMode, PublisherPolicy, StreamRegistry};
+
+        let registry = StreamRegistry::new(100);
+        let publisher = registry.publish("cam", PublisherPolicy::Merge).unwrap();
+        publisher.send(vec![0]);
+        let mut viewer = registry.subscribe("cam", DeliveryMode::Latest);
+        assert!(viewer.next_frame().now_or_never().is_none());
+
+        publisher.send(vec![1]);
+        assert_eq!(*viewer.next_frame().await.unwrap().data.payload(), vec![1]);
+
+        for i in 2..6u8 {
+            publisher.send(vec![i]);
+        }
+        let received = viewer.next_frame().await.unwrap();
+        assert_eq!(*received.data.payload(), vec![5]);
+        assert_eq!(received.skipped, 3);
+        assert_eq!(viewer.dropped_frames(), 3);
+    }
+
+    #[test]
+    fn viewer_options_come_from_query() {
+        use crate::server::{DeliveryMode, ViewerOptions};
+
+        let mut request = get_request("GET", "/view", &[]);
+        let options = ViewerOptions::from_request(&request, DeliveryMode::Queue);
+        assert_eq!(options.delivery, DeliveryMode::Queue);
+        assert!(!options.lag_notices);
+
+        request.query = Some("delivery=latest&lag_notices=1".to_string());
+        let options = ViewerOptions::from_request(&request, DeliveryMode::Queue);
+        assert_eq!(options.delivery, DeliveryMode::Latest);
+        assert!(options.lag_notices);
+        assert_eq!(options.format, crate::protocol::WireFormat::Raw);
+
+        request.query = Some("format=envelope".to_string());
+        assert_eq!(ViewerOptions::from_request(&request, DeliveryMode::Queue).format, crate::protocol::WireFormat::Envelope);
+
+        request.query = Some("delivery=bogus".to_string());
+        assert_eq!(ViewerOptions::from_request(&request, DeliveryMode::Latest).delivery, DeliveryMode::Latest);
+    }
+
+    // Late-joiner cache tests
+    #[tokio::test]
+    async fn late_viewer_gets_cached_frame_once() {
+        use crate::server::{DeliveryMode, PublisherPolicy, StreamRegistry};
+
+        let registry = StreamRegistry::new(10);
+        let publisher = registry.publish("cam", PublisherPolicy::Merge).unwrap();
+        let mut early = registry.subscribe("cam", DeliveryMode::Queue);
+        assert!(early.take_cached_frame(Duration::from_secs(5)).is_none());
+
+        publisher.send(vec![1u8]);
+        for (mode, cached, next) in [(DeliveryMode::Queue, 1u8, 2u8), (DeliveryMode::Latest, 2, 3)] {
+            let mut late = registry.subscribe("cam", mode);
+            assert_eq!(*late.take_cached_frame(Duration::from_secs(5)).unwrap().payload(), vec![cached]);
+            assert!(late.take_cached_frame(Duration::from_secs(5)).is_none());
+            // キャッシュ済みのフレームは next_frame で重複しない
+            assert!(late.next_frame().now_or_never().is_none());
+            publisher.send(vec![next]);
+            let received = late.next_frame().await.unwrap();
+            assert_eq!(*received.data.payload(), vec![next]);
+            assert_eq!(received.skipped, 0);
+        }
+    }
+
+    #[test]
+    fn stale_cached_frame_is_not_sent() {
+        use crate::server::{DeliveryMode, PublisherPolicy, StreamRegistry};
+
+        let registry = StreamRegistry::new(10);
+        let publisher = registry.publish("cam", PublisherPolicy::Merge).unwrap();
+        publisher.send(vec![1u8]);
+
+        assert!(registry.subscribe("cam", DeliveryMode::Queue).take_cached_frame(Duration::ZERO).is_none());
+        std::thread::sleep(Duration::from_millis(30));
+        let mut viewer = registry.subscribe("cam", DeliveryMode::Queue);
+        assert!(viewer.take_cached_frame(Duration::from_millis(10)).is_none());
+    }
+
+    #[tokio::test]
+    async fn viewer_receives_last_frame_right_after_upgrade() {
+        use futures::StreamExt;
+
+        let server = Server::new("127.0.0.1:0").await.unwrap();
+        let publisher = server.publisher("slow").unwrap();
+        let addr = server.spawn().local_addr().to_string();
+
+        publisher.send(vec![9u8; 64]);
+        let (mut ws, _) = tokio_tungstenite::connect_async(format!("ws://{}/view/slow", addr)).await.unwrap();
+        let msg = tokio::time::timeout(Duration::from_secs(2), ws.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(msg.into_data(), vec![9u8; 64]);
+    }
+
+    // Frame envelope tests
+    fn sample_header() -> crate::protocol::FrameHeader {
+        crate::protocol::FrameHeader {
+            flags: crate::protocol::FLAG_KEYFRAME,
+            stream_id: "cam-1".to_string(),
+            seq: 42,
+            timestamp_us: 1_700_000_000_123_456,
+            content_type: crate::protocol::ContentType::Jpeg,
+            width: 1280,
+            height: 720,
+        }
+    }
+
+    #[test]
+    fn envelope_round_trips_header_and_payload() {
+        use crate::protocol::{decode, encode, is_envelope, FIXED_HEADER_LEN};
+
+        let header = sample_header();
+        let payload = vec![0xFFu8, 0xD8, 1, 2, 3, 0xFF, 0xD9];
+        let wire = encode(&header, &payload);
+        assert!(is_envelope(&wire));
+        assert_eq!(wire.len(), FIXED_HEADER_LEN + "cam-1".len() + payload.len());
+
+        let (decoded, body) = decode(&wire).unwrap();
+        assert_eq!(decoded, header);
+        assert_eq!(body, payload);
+        // ペイロードはコピーではなく同じバッファのスライス
+        assert_eq!(body.as_ptr(), wire[wire.len() - payload.len()..].as_ptr());
+
+        let empty = crate::protocol::FrameHeader::default();
+        let (decoded, body) = decode(&encode(&empty, &[])).unwrap();
+        assert_eq!(decoded, empty);
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn envelope_rejects_malformed_headers() {
+        use crate::protocol::{decode, encode, DecodeError};
+        use bytes::Bytes;
+
+        let wire = encode(&sample_header(), b"jpeg");
+        let corrupt = |at: usize, value: u8| {
+            let mut bytes = wire.to_vec();
+            bytes[at] = value;
+            Bytes::from(bytes)
+        };
+        assert_eq!(decode(&Bytes::from_static(&[0xFF, 0xD8, 0xFF])).unwrap_err(), DecodeError::BadMagic);
+        assert_eq!(decode(&wire.slice(..20)).unwrap_err(), DecodeError::TooShort);
+        assert_eq!(decode(&corrupt(4, 2)).unwrap_err(), DecodeError::UnsupportedVersion(2));
+        assert_eq!(decode(&corrupt(7, 10)).unwrap_err(), DecodeError::BadHeaderLength(10));
+        assert_eq!(decode(&corrupt(7, 200)).unwrap_err(), DecodeError::TooShort);
+        assert_eq!(decode(&corrupt(30, 0xFF)).unwrap_err(), DecodeError::BadStreamId);
+
+        // 未知の拡張フィールドは読み飛ばす
+        let mut extended = wire[..35].to_vec();
+        extended[7] += 4;
+        extended.extend_from_slice(&[0xEE; 4]);
+        extended.extend_from_slice(b"jpeg");
+        let (header, payload) = decode(&Bytes::from(extended)).unwrap();
+        assert_eq!(header, sample_header());
+        assert_eq!(payload, &b"jpeg"[..]);
+    }
+
+    #[test]
+    fn envelope_decode_survives_fuzzed_input() {
+        use crate::protocol::{decode, encode, ContentType, FrameHeader};
+        use bytes::Bytes;
+
+        // 依存を増やさないための xorshift64
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        for _ in 0..2000 {
+            let id_len = (next() % 65) as usize;
+            let header = FrameHeader {
+                flags: next() as u8,
+                stream_id: (0..id_len).map(|_| (b'a' + (next() % 26) as u8) as char).collect(),
+                seq: next(),
+                timestamp_us: next(),
+                content_type: ContentType::from_u8((next() % 4) as u8),
+                width: next() as u16,
+                height: next() as u16,
+            };
+            let payload: Vec<u8> = (0..next() % 256).map(|_| next() as u8).collect();
+            let wire = encode(&header, &payload);
+            let (decoded, body) = decode(&wire).unwrap();
+            assert_eq!(decoded, header);
+            assert_eq!(body, payload);
+
+            // 壊したり切り詰めたりした入力でもパニックしない
+            let mut mutated = wire.to_vec();
+            for _ in 0..1 + next() % 4 {
+                let at = (next() as usize) % mutated.len();
+                mutated[at] = next() as u8;
+            }
+            mutated.truncate((next() as usize) % (mutated.len() + 1));
+            if let Ok((_, body)) = decode(&Bytes::from(mutated.clone())) {
+                assert!(body.len() <= mutated.len());
+            }
+            let noise: Vec<u8> = (0..next() % 64).map(|_| next() as u8).collect();
+            let _ = decode(&Bytes::from(noise));
+        }
+    }
+
+    #[test]
+    fn raw_frames_get_sniffed_metadata_and_stream_sequence() {
+        use crate::protocol::{decode, ContentType, FrameHeader};
+        use crate::server::{DeliveryMode, PublisherPolicy, StreamRegistry};
+
+        let registry = StreamRegistry::new(10);
+        let publisher = registry.publish("cam", PublisherPolicy::Merge).unwrap();
+        let mut viewer = registry.subscribe("cam", DeliveryMode::Queue);
+
+        publisher.send(vec![0xFFu8, 0xD8, 0xFF, 0xD9]);
+        // 送信側が付けた連番とストリーム ID はサーバーのものに置き換わる
+        let mut header = sample_header();
+        header.stream_id = "spoofed".to_string();
+        publisher.send_with(header, &b"\x89PNG\r\n\x1a\n"[..]);
+
+        let first = viewer.next_frame().now_or_never().flatten().unwrap().data;
+        assert_eq!(first.header().content_type, ContentType::Jpeg);
+        assert_eq!(first.header().seq, 0);
+        assert!(first.header().timestamp_us > 0);
+        let second = viewer.next_frame().now_or_never().flatten().unwrap().data;
+        assert_eq!(second.header().seq, 1);
+        assert_eq!(second.header().stream_id, "cam");
+        assert_eq!(second.header().width, 1280);
+
+        let (decoded, payload) = decode(&second.envelope()).unwrap();
+        assert_eq!(&decoded, second.header());
+        assert_eq!(&payload, second.payload());
+        assert_eq!(ContentType::sniff(b"RIFF\0\0\0\0WEBPVP8 "), ContentType::Webp);
+        assert_eq!(FrameHeader::for_raw(b"text").content_type, ContentType::Unknown);
+    }
+
+    async fn next_binary<S>(ws: &mut tokio_tungstenite::WebSocketStream<S>) -> bytes::Bytes
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        use futures::StreamExt;
+
+        loop {
+            let msg = tokio::time::timeout(Duration::from_secs(2), ws.next()).await.unwrap();
+            // サーバーからの Ping は読み飛ばす
+            if let tokio_tungstenite::tungstenite::Message::Binary(data) = msg.unwrap().unwrap() {
+                return data;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn envelope_and_raw_viewers_share_one_camera() {
+        use crate::protocol::{decode, encode, ContentType};
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let server = Server::new("127.0.0.1:0").await.unwrap();
+        let addr = server.spawn().local_addr().to_string();
+
+        let connect = |path: &str| tokio_tungstenite::connect_async(format!("ws://{}{}", addr, path));
+        let (mut raw, _) = connect("/view/env").await.unwrap();
+        let (mut enveloped, _) = connect("/view/env?format=envelope").await.unwrap();
+        let (mut camera, _) = connect("/camera/env").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // 旧来の生 JPEG 送信と、エンベロープ付きの送信を混ぜる
+        camera.send(Message::Binary(vec![0xFFu8, 0xD8, 7].into())).await.unwrap();
+        camera.send(Message::Binary(encode(&sample_header(), &[0xFF, 0xD8, 8]))).await.unwrap();
+        camera.send(Message::Binary(bytes::Bytes::from_static(b"W2WS\x01 broken"))).await.unwrap();
+        camera.send(Message::Binary(vec![0xFFu8, 0xD8, 9].into())).await.unwrap();
+
+        for expected in [7u8, 8, 9] {
+            assert_eq!(next_binary(&mut raw).await, vec![0xFF, 0xD8, expected]);
+        }
+        for (seq, expected) in [(0u64, 7u8), (1, 8), (2, 9)] {
+            let (header, payload) = decode(&next_binary(&mut enveloped).await).unwrap();
+            assert_eq!(header.seq, seq);
+            assert_eq!(header.stream_id, "env");
+            assert_eq!(header.content_type, ContentType::Jpeg);
+            assert_eq!(payload, vec![0xFF, 0xD8, expected]);
+            if expected == 8 {
+                assert_eq!((header.width, header.height), (1280, 720));
+                assert_eq!(header.timestamp_us, sample_header().timestamp_us);
+            }
+        }
+    }
+
+    // Latency measurement tests
+    #[test]
+    fn latency_percentiles_use_a_bounded_window() {
+        use crate::server::latency::{Metric, Percentiles, StreamLatency, LATENCY_WINDOW};
+
+        let p = Percentiles::from_micros((1..=100).map(|ms| ms * 1000)).unwrap();
+        assert_eq!((p.count, p.p50_ms, p.p95_ms, p.p99_ms), (100, 50.0, 95.0, 99.0));
+        assert_eq!(Percentiles::from_micros([7_000]).unwrap().p99_ms, 7.0);
+        assert!(Percentiles::from_micros([]).is_none());
+
+        let latency = StreamLatency::default();
+        for _ in 0..LATENCY_WINDOW {
+            latency.record(Metric::Rtt, Duration::from_millis(500));
+        }
+        // 古いサンプルは押し出される
+        for _ in 0..LATENCY_WINDOW {
+            latency.record(Metric::Rtt, Duration::from_millis(2));
+        }
+        let snapshot = latency.snapshot();
+        let rtt = snapshot.rtt.unwrap();
+        assert_eq!((rtt.count, rtt.p99_ms), (LATENCY_WINDOW, 2.0));
+        assert!(snapshot.glass_to_glass.is_none() && snapshot.ingress.is_none());
+    }
+
+    #[test]
+    fn viewer_ack_messages_parse() {
+        use crate::server::ViewerMessage;
+
+        let ack: ViewerMessage = serde_json::from_str(r#"{"type":"ack","seq":3,"displayed_at_us":99}"#).unwrap();
+        assert_eq!(ack, ViewerMessage::Ack { seq: 3, displayed_at_us: 99 });
+        assert!(serde_json::from_str::<ViewerMessage>(r#"{"type":"lag","skipped":1}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn viewer_acks_and_pongs_feed_status_latency() {
+        use crate::protocol::{encode, now_micros};
+        use futures::{SinkExt, StreamExt};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_tungstenite::tungstenite::Message;
+
+        let server = Server::new("127.0.0.1:0").await.unwrap();
+        let addr = server.spawn().local_addr().to_string();
+
+        let url = |path: &str| format!("ws://{}{}", addr, path);
+        let (mut viewer, _) = tokio_tungstenite::connect_async(url("/view/lat?format=envelope")).await.unwrap();
+        let (mut camera, _) = tokio_tungstenite::connect_async(url("/camera/lat")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // 40ms 前にキャプチャされたフレーム
+        let mut header = sample_header();
+        header.timestamp_us = now_micros() - 40_000;
+        camera.send(Message::Binary(encode(&header, &[0xFF, 0xD8]))).await.unwrap();
+        let (received, _) = crate::protocol::decode(&next_binary(&mut viewer).await).unwrap();
+        let ack = format!(
+            r#"{{"type":"ack","seq":{},"displayed_at_us":{}}}"#,
+            received.seq,
+            header.timestamp_us + 75_000
+        );
+        viewer.send(Message::Text(ack.into())).await.unwrap();
+        // 知らない連番の ack は無視される
+        viewer.send(Message::Text(r#"{"type":"ack","seq":999,"displayed_at_us":1}"#.into())).await.unwrap();
+
+        // Ping が届くまで読み続ける（Pong は自動で返る）
+        let ping = tokio::time::timeout(Duration::from_secs(3), async {
+            loop {
+                if let Some(Ok(Message::Ping(_))) = viewer.next().await {
+                    break;
+                }
+            }
+        });
+        ping.await.unwrap();
+        viewer.flush().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut http = connect_with_retry(&addr).await;
+        http.write_all(b"GET /status HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        http.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Content-Type: application/json"));
+
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        let status: serde_json::Value = serde_json::from_str(body).unwrap();
+        let stream = &status["streams"][0];
+        assert_eq!(stream["id"], "lat");
+        assert_eq!((stream["publishers"].as_u64(), stream["viewers"].as_u64()), (Some(1), Some(1)));
+        let latency = &stream["latency"];
+        assert_eq!(latency["glass_to_glass"]["count"], 1);
+        assert_eq!(latency["glass_to_glass"]["p50_ms"], 75.0);
+        assert_eq!(latency["ingress"]["count"], 1);
+        assert!(latency["ingress"]["p50_ms"].as_f64().unwrap() >= 40.0);
+        assert!(latency["rtt"]["count"].as_u64().unwrap() >= 1);
+    }
+
+    // Prometheus metrics tests
+    #[tokio::test]
+    async fn metrics_render_stream_counters_and_histogram() {
+        use crate::server::metrics::{Metrics, UpgradeFailure};
+        use crate::server::{DeliveryMode, PublisherPolicy, StreamRegistry};
+
+        let registry = StreamRegistry::new(2);
+        let publisher = registry.publish("cam", PublisherPolicy::Merge).unwrap();
+        let mut viewer = registry.subscribe("cam", DeliveryMode::Queue);
+        for size in [1000, 20_000, 2 << 20, 10] {
+            publisher.send(vec![0u8; size]);
+        }
+        // 容量 2 なので最新の 1 フレームだけを受け取り、3 フレーム読み飛ばす
+        let received = viewer.next_frame().await.unwrap();
+        viewer.metrics().record_out(received.data.payload().len());
+
+        let metrics = Metrics::new();
+        metrics.record_connection();
+        metrics.record_capture_error();
+        metrics.record_upgrade_failure(UpgradeFailure::Forbidden);
+        let text = metrics.render(&registry);
+
+        for line in [
+            "# TYPE web2ws_connections_total counter",
+            "web2ws_connections_total 1",
+            "web2ws_capture_errors_total 1",
+            "web2ws_upgrade_failures_total{reason=\"forbidden\"} 1",
+            "web2ws_upgrade_failures_total{reason=\"unauthorized\"} 0",
+            "# TYPE web2ws_publishers gauge",
+            "web2ws_publishers{stream=\"cam\"} 1",
+            "web2ws_viewers{stream=\"cam\"} 1",
+            "web2ws_frames_in_total{stream=\"cam\"} 4",
+            "web2ws_bytes_in_total{stream=\"cam\"} 2118162",
+            "web2ws_frames_out_total{stream=\"cam\"} 1",
+            "web2ws_bytes_out_total{stream=\"cam\"} 10",
+            "web2ws_dropped_frames_total{stream=\"cam\"} 3",
+            "# TYPE web2ws_frame_size_bytes histogram",
+            "web2ws_frame_size_bytes_bucket{stream=\"cam\",le=\"4096\"} 2",
+            "web2ws_frame_size_bytes_bucket{stream=\"cam\",le=\"32768\"} 3",
+            "web2ws_frame_size_bytes_bucket{stream=\"cam\",le=\"1048576\"} 3",
+            "web2ws_frame_size_bytes_bucket{stream=\"cam\",le=\"+Inf\"} 4",
+            "web2ws_frame_size_bytes_sum{stream=\"cam\"} 2118162",
+            "web2ws_frame_size_bytes_count{stream=\"cam\"} 4",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing `{}` in\n{}", line, text);
+        }
+
+        // ストリームが消えるとその系列も消える
+        drop((publisher, viewer));
+        assert!(!metrics.render(&registry).contains("stream=\"cam\""));
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint_counts_failed_upgrades() {
+        use crate::server::auth::{Authenticator, Scope};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut auth = Authenticator::new();
+        auth.add_token(Scope::View, "secret", None).unwrap();
+        let server = Server::new("127.0.0.1:0").await.unwrap().auth(auth);
+        let publisher = server.publisher("live").unwrap();
+        let metrics = server.metrics();
+        let addr = server.spawn().local_addr().to_string();
+        publisher.send(vec![0xFFu8, 0xD8]);
+
+        assert!(upgrade_status(&addr, "/view/live", "").await.starts_with("HTTP/1.1 401"));
+        let mut plain = connect_with_retry(&addr).await;
+        plain.write_all(b"GET /view/live?token=secret HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        plain.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 426"));
+
+        let mut http = connect_with_retry(&addr).await;
+        http.write_all(b"GET /metrics HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        http.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+        assert!(response.contains("web2ws_upgrade_failures_total{reason=\"unauthorized\"} 1\n"));
+        assert!(response.contains("web2ws_upgrade_failures_total{reason=\"not_upgrade\"} 1\n"));
+        assert!(response.contains("web2ws_frames_in_total{stream=\"live\"} 1\n"));
+        assert!(metrics.upgrade_failures(crate::server::metrics::UpgradeFailure::Unauthorized) == 1);
+    }
+
+    // Structured logging tests
+    #[derive(Clone, Default)]
+    struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedLogs {
+        fn json_lines(&self) -> Vec<serde_json::Value> {
+            let text = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+            text.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+        }
+    }
+
+    #[test]
+    fn log_format_and_filter_are_validated() {
+        use crate::logging::{subscriber, LogFormat};
+
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!("pretty".parse::<LogFormat>().unwrap(), LogFormat::Pretty);
+        assert!("xml".parse::<LogFormat>().is_err());
+        assert!(subscriber(LogFormat::Json, "info,web2ws::server=debug", std::io::sink, false).is_ok());
+        assert!(subscriber(LogFormat::Json, "web2ws=loud", std::io::sink, false).is_err());
+    }
+
+    #[tokio::test]
+    async fn json_logs_carry_connection_and_client_span_fields() {
+        use crate::logging::{subscriber, LogFormat};
+        use futures::SinkExt;
+
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let _guard = tracing::subscriber::set_default(
+            subscriber(LogFormat::Json, "web2ws=debug", move || writer.clone(), false).unwrap(),
+        );
+
+        // current_thread ランタイムなので spawn したタスクにも同じ subscriber が効く
+        let server = Server::new("127.0.0.1:0").await.unwrap();
+        let addr = server.spawn().local_addr().to_string();
+
+        let (mut camera, _) = tokio_tungstenite::connect_async(format!("ws://{}/camera/logs", addr)).await.unwrap();
+        camera.close(None).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let lines = logs.json_lines();
+        let connected = lines
+            .iter()
+            .find(|l| l["message"] == "Camera client connected")
+            .expect("camera connect event");
+        assert_eq!(connected["level"], "INFO");
+        assert_eq!(connected["span"]["role"], "publisher");
+        assert_eq!(connected["span"]["stream"], "logs");
+        let spans = connected["spans"].as_array().unwrap();
+        let connection = spans.iter().find(|s| s["name"] == "connection").unwrap();
+        assert!(connection["conn_id"].as_u64().unwrap() >= 1);
+        assert!(connection["peer"].as_str().unwrap().starts_with("127.0.0.1:"));
+        assert!(spans.iter().any(|s| s["name"] == "server" && s["addr"] == addr.as_str()));
+        // debug レベルのリクエストログも同じ接続スパンに入る
+        assert!(lines.iter().any(|l| l["message"] == "Incoming request" && l["path"] == "/camera/logs"));
+    }
+
+    // Admin REST API tests
+    async fn http_call(addr: &str, method: &str, path: &str, token: Option<&str>) -> (u16, String) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = connect_with_retry(addr).await;
+        let auth = token.map_or(String::new(), |t| format!("Authorization: Bearer {}\r\n", t));
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: 0\r\n{}\r\n",
+            method, path, auth
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body).to_string();
+        (status, body)
+    }
+
+    #[test]
+    fn api_routes_parse() {
+        use crate::server::api::ApiRoute;
+
+        assert_eq!(ApiRoute::parse("/api/streams"), Some(ApiRoute::Streams));
+        assert_eq!(ApiRoute::parse("/api/clients"), Some(ApiRoute::Clients));
+        assert_eq!(ApiRoute::parse("/api/clients/12/kick"), Some(ApiRoute::KickClient(12)));
+        assert_eq!(ApiRoute::parse("/api/streams/cam-1/close"), Some(ApiRoute::CloseStream("cam-1".to_string())));
+        assert_eq!(ApiRoute::parse("/api/clients/abc/kick"), None);
+        assert_eq!(ApiRoute::parse("/api/streams/../close"), None);
+        assert_eq!(ApiRoute::parse("/api/streams/"), None);
+        assert_eq!(ApiRoute::parse("/status"), None);
+    }
+
+    #[tokio::test]
+    async fn api_lists_clients_and_admin_can_kick_and_close() {
+        use crate::server::auth::{Authenticator, Scope};
+        use crate::server::{CLOSE_KICKED, CLOSE_STREAM_CLOSED};
+        use futures::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let mut auth = Authenticator::new();
+        auth.add_token(Scope::Admin, "boss", None).unwrap();
+        auth.add_token(Scope::View, "watch", None).unwrap();
+        let server = Server::new("127.0.0.1:0").await.unwrap().auth(auth);
+        let clients = server.clients();
+        let addr = server.spawn().local_addr().to_string();
+
+        let url = |path: &str| format!("ws://{}{}", addr, path);
+        let (mut camera, _) = tokio_tungstenite::connect_async(url("/camera/yard")).await.unwrap();
+        let (mut viewer, _) = tokio_tungstenite::connect_async(url("/view/yard?token=watch")).await.unwrap();
+        while clients.list().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        camera.send(Message::Binary(vec![0xFFu8, 0xD8, 1, 2].into())).await.unwrap();
+        next_binary(&mut viewer).await;
+
+        // 管理トークンが設定されていれば一覧にも必要
+        assert_eq!(http_call(&addr, "GET", "/api/clients", None).await.0, 401);
+        assert_eq!(http_call(&addr, "GET", "/api/clients", Some("watch")).await.0, 403);
+        let (status, body) = http_call(&addr, "GET", "/api/clients", Some("boss")).await;
+        assert_eq!(status, 200);
+        let list: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let list = list.as_array().unwrap();
+        assert_eq!(list.len(), 2);
+        let camera_info = list.iter().find(|c| c["role"] == "publisher").unwrap();
+        assert_eq!(camera_info["stream"], "yard");
+        assert_eq!((camera_info["frames"].as_u64(), camera_info["bytes"].as_u64()), (Some(1), Some(4)));
+        assert!(camera_info["peer"].as_str().unwrap().starts_with("127.0.0.1:"));
+        assert!(camera_info["connected_at_ms"].as_u64().unwrap() > 0);
+        let viewer_info = list.iter().find(|c| c["role"] == "viewer").unwrap();
+        assert_eq!(viewer_info["frames"], 1);
+
+        let (status, body) = http_call(&addr, "GET", "/api/streams", Some("boss")).await;
+        assert_eq!(status, 200);
+        let streams: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(streams[0]["id"], "yard");
+        assert_eq!((streams[0]["publishers"].as_u64(), streams[0]["viewers"].as_u64()), (Some(1), Some(1)));
+        assert_eq!(streams[0]["frames_in"], 1);
+
+        // キック
+        assert_eq!(http_call(&addr, "GET", "/api/clients/1/kick", Some("boss")).await.0, 405);
+        assert_eq!(http_call(&addr, "POST", "/api/clients/999/kick", Some("boss")).await.0, 404);
+        let viewer_id = viewer_info["id"].as_u64().unwrap();
+        let kick = format!("/api/clients/{}/kick", viewer_id);
+        assert_eq!(http_call(&addr, "POST", &kick, None).await.0, 401);
+        assert_eq!(http_call(&addr, "POST", &kick, Some("boss")).await.0, 204);
+        assert_eq!(next_close_code(&mut viewer).await, Some(CLOSE_KICKED));
+        drop(viewer);
+        while clients.list().len() > 1 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        // ストリームを閉じると配信者も切断される
+        let (status, body) = http_call(&addr, "POST", "/api/streams/yard/close", Some("boss")).await;
+        assert_eq!(status, 200);
+        assert!(body.contains(r#""clients":1"#));
+        assert_eq!(next_close_code(&mut camera).await, Some(CLOSE_STREAM_CLOSED));
+        assert_eq!(http_call(&addr, "POST", "/api/streams/nope/close", Some("boss")).await.0, 404);
+    }
+
+    #[tokio::test]
+    async fn api_actions_are_disabled_without_admin_tokens() {
+        use crate::server::auth::{Authenticator, Scope};
+
+        let server = Server::new("127.0.0.1:0").await.unwrap();
+        let publisher = server.publisher("open").unwrap();
+        let addr = server.spawn().local_addr().to_string();
+
+        let (status, body) = http_call(&addr, "GET", "/api/streams", None).await;
+        assert_eq!(status, 200);
+        assert!(body.contains(r#""id":"open""#));
+        assert_eq!(http_call(&addr, "POST", "/api/streams/open/close", None).await.0, 403);
+        assert!(!publisher.is_evicted());
+        assert_eq!(http_call(&addr, "GET", "/api/unknown", None).await.0, 404);
+
+        // 視聴だけ保護していても一覧は開放しない
+        let mut auth = Authenticator::new();
+        auth.add_token(Scope::View, "watch", None).unwrap();
+        let server = Server::new("127.0.0.1:0").await.unwrap().auth(auth);
+        let addr = server.spawn().local_addr().to_string();
+        assert_eq!(http_call(&addr, "GET", "/api/clients", None).await.0, 403);
+        assert_eq!(http_call(&addr, "GET", "/api/streams", Some("watch")).await.0, 403);
+    }
+
+    // アップグレードだけ済ませて、その後は何も書かない（途切れた接続の代わり）
+    async fn silent_ws_client(addr: &str, path: &str) -> tokio::net::TcpStream {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = connect_with_retry(addr).await;
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            path
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        assert!(head.starts_with(b"HTTP/1.1 101"));
+        stream
+    }
+
+    // Close code of the first Close frame the server sent before closing the connection
+    async fn raw_close_code(stream: &mut tokio::net::TcpStream) -> Option<u16> {
+        use tokio::io::AsyncReadExt;
+
+        let mut bytes = Vec::new();
+        stream.read_to_end(&mut bytes).await.ok()?;
+        // サーバーからのフレームはマスクされない: 0x88, 長さ, コード (2 バイト), 理由
+        bytes
+            .windows(4)
+            .find(|w| w[0] == 0x88 && w[1] >= 2 && w[1] < 126)
+            .map(|w| u16::from_be_bytes([w[2], w[3]]))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn websocket_clients_that_stop_responding_are_closed() {
+        use crate::server::{PublisherPolicy, ServerConfig, CLOSE_IDLE_TIMEOUT};
+        use futures::StreamExt;
+
+        let config = ServerConfig {
+            ws_idle_timeout: Duration::from_secs(5),
+            publisher_policy: PublisherPolicy::Reject,
+            ..ServerConfig::default()
+        };
+        let server = Server::with_config("127.0.0.1:0", config).await.unwrap();
+        let streams = server.streams();
+        let addr = server.spawn().local_addr().to_string();
+
+        let mut camera = silent_ws_client(&addr, "/camera/door").await;
+        let mut viewer = silent_ws_client(&addr, "/view/door").await;
+        // 読み続けるクライアントは Ping に Pong を返す
+        let url = format!("ws://{}/view/door", addr);
+        let (mut alive, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let reader = tokio::spawn(async move { while let Some(Ok(_)) = alive.next().await {} });
+
+        tokio::time::sleep(Duration::from_secs(4)).await;
+        assert_eq!(streams.publisher_count("door"), 1);
+        assert_eq!(raw_close_code(&mut camera).await, Some(CLOSE_IDLE_TIMEOUT));
+        assert_eq!(raw_close_code(&mut viewer).await, Some(CLOSE_IDLE_TIMEOUT));
+        assert_eq!(streams.publisher_count("door"), 0);
+        assert_eq!(streams.viewer_count("door"), 1);
+        assert!(!reader.is_finished());
+
+        // reject でも、途切れた配信者の枠は空いている
+        assert_eq!(upgrade_status(&addr, "/camera/door", "").await, "HTTP/1.1 101 Switching Protocols");
+    }
+
+    // Graceful shutdown tests
+    #[tokio::test]
+    async fn shutdown_closes_clients_with_going_away_and_stops_accepting() {
+        use crate::server::CLOSE_GOING_AWAY;
+        use std::future::IntoFuture;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let server = Server::new("127.0.0.1:0").await.unwrap().drain_timeout(Duration::from_secs(3));
+        let clients = server.clients();
+        let shutdown = server.shutdown_token();
+        let server = server.spawn();
+        let addr = server.local_addr().to_string();
+
+        let url = |path: &str| format!("ws://{}{}", addr, path);
+        let (mut camera, _) = tokio_tungstenite::connect_async(url("/camera/hall")).await.unwrap();
+        let (mut viewer, _) = tokio_tungstenite::connect_async(url("/view/hall")).await.unwrap();
+        while clients.list().len() < 2 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        // アイドルの keep-alive 接続
+        let mut idle = connect_with_retry(&addr).await;
+        idle.write_all(b"GET /status HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut buf = [0u8; 4096];
+        assert!(idle.read(&mut buf).await.unwrap() > 0);
+
+        let started = std::time::Instant::now();
+        shutdown.cancel();
+        assert_eq!(next_close_code(&mut camera).await, Some(CLOSE_GOING_AWAY));
+        assert_eq!(next_close_code(&mut viewer).await, Some(CLOSE_GOING_AWAY));
+        let closed = tokio::time::timeout(Duration::from_secs(1), idle.read(&mut buf)).await;
+        assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))));
+
+        let result = tokio::time::timeout(Duration::from_secs(5), server.into_future()).await.unwrap();
+        assert!(result.is_ok());
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert!(tokio::net::TcpStream::connect(&addr).await.is_err());
+    }
+
+    // Server configuration tests
+    #[test]
+    fn server_config_loads_toml_with_defaults() {
+        use crate::server::{DeliveryMode, PublisherPolicy, ServerConfig};
+
+        let config = ServerConfig::from_toml(
+            r#"
+            channel_capacity = 8
+            max_viewers_per_stream = 3
+            idle_timeout_ms = 1500
+            publisher_policy = "replace"
+            delivery = "latest"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.channel_capacity, 8);
+        assert_eq!(config.max_viewers_per_stream, Some(3));
+        assert_eq!(config.max_publishers, None);
+        assert_eq!(config.idle_timeout, Duration::from_millis(1500));
+        assert_eq!(config.handshake_timeout, ServerConfig::default().handshake_timeout);
+        assert_eq!(config.publisher_policy, PublisherPolicy::Replace);
+        assert_eq!(config.delivery_mode, DeliveryMode::Latest);
+        assert_eq!(ServerConfig::from_toml("").unwrap(), ServerConfig::default());
+
+        assert!(ServerConfig::from_toml("max_viewer = 3").is_err());
+        assert!(ServerConfig::from_toml("publisher_policy = \"first\"").is_err());
+        assert!(ServerConfig::from_toml("channel_capacity = 0").is_err());
+    }
+
+    #[tokio::test]
+    async fn server_enforces_configured_limits() {
+        use crate::server::metrics::UpgradeFailure;
+        use crate::server::ServerConfig;
+        use futures::SinkExt;
+        use tokio::io::AsyncReadExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let config = ServerConfig {
+            max_viewers_per_stream: Some(1),
+            max_publishers: Some(2),
+            max_frame_size: 1024,
+            ..ServerConfig::default()
+        };
+        let server = Server::with_config("127.0.0.1:0", config).await.unwrap();
+        let _capture = server.publisher("lobby").unwrap();
+        let metrics = server.metrics();
+        let addr = server.spawn().local_addr().to_string();
+
+        let url = |path: &str| format!("ws://{}{}", addr, path);
+        let (_viewer, _) = tokio_tungstenite::connect_async(url("/view/lobby")).await.unwrap();
+        assert_eq!(upgrade_status(&addr, "/view/lobby", "").await, "HTTP/1.1 503 Service Unavailable");
+        assert_eq!(upgrade_status(&addr, "/view/other", "").await, "HTTP/1.1 101 Switching Protocols");
+
+        // サーバー側キャプチャも配信者として数える
+        let (mut camera, _) = tokio_tungstenite::connect_async(url("/camera/hall")).await.unwrap();
+        assert_eq!(upgrade_status(&addr, "/camera/yard", "").await, "HTTP/1.1 503 Service Unavailable");
+        assert_eq!(metrics.upgrade_failures(UpgradeFailure::LimitReached), 2);
+
+        camera.send(Message::Binary(vec![0u8; 2048].into())).await.unwrap();
+        assert_eq!(next_close_code(&mut camera).await, Some(1009));
+
+        let config = ServerConfig {
+            max_connections: Some(1),
+            ..ServerConfig::default()
+        };
+        let server = Server::with_config("127.0.0.1:0", config).await.unwrap();
+        let metrics = server.metrics();
+        let addr = server.spawn().local_addr().to_string();
+        let _held = connect_with_retry(&addr).await;
+        let mut extra = tokio::net::TcpStream::connect(&addr).await.unwrap();
+        let mut buf = [0u8; 16];
+        let dropped = tokio::time::timeout(Duration::from_secs(2), extra.read(&mut buf)).await.unwrap();
+        assert!(matches!(dropped, Ok(0) | Err(_)));
+        assert!(metrics.render(&crate::server::StreamRegistry::new(1)).contains("web2ws_connections_rejected_total 1"));
+    }
+
+    #[tokio::test]
+    async fn replacing_a_publisher_does_not_count_against_max_publishers() {
+        use crate::server::{PublisherPolicy, ServerConfig};
+
+        let config = ServerConfig {
+            max_publishers: Some(1),
+            publisher_policy: PublisherPolicy::Replace,
+            ..ServerConfig::default()
+        };
+        let server = Server::with_config("127.0.0.1:0", config).await.unwrap();
+        let capture = server.publisher("lobby").unwrap();
+        let addr = server.spawn().local_addr().to_string();
+
+        // サーバー側キャプチャを置き換えるので数は増えない
+        let (_camera, _) = tokio_tungstenite::connect_async(format!("ws://{}/camera/lobby", addr)).await.unwrap();
+        assert!(capture.is_evicted());
+        assert_eq!(upgrade_status(&addr, "/camera/yard", "").await, "HTTP/1.1 503 Service Unavailable");
+    }
+
+    #[test]
+    fn registry_checks_limits_under_the_same_lock_as_registration() {
+        use crate::server::{DeliveryMode, PublishError, PublisherPolicy, StreamRegistry};
+        use std::sync::Barrier;
+
+        let registry = StreamRegistry::new(10);
+        let first = registry.publish_limited("a", PublisherPolicy::Merge, Some(2)).unwrap();
+        let _second = registry.publish_limited("b", PublisherPolicy::Merge, Some(2)).unwrap();
+        assert_eq!(
+            registry.publish_limited("c", PublisherPolicy::Merge, Some(2)).err(),
+            Some(PublishError::LimitReached)
+        );
+        assert_eq!(
+            registry.publish_limited("c", PublisherPolicy::Replace, Some(2)).err(),
+            Some(PublishError::LimitReached)
+        );
+        let _replacement = registry.publish_limited("a", PublisherPolicy::Replace, Some(2)).unwrap();
+        assert!(first.is_evicted());
+        assert_eq!(registry.total_publishers(), 2);
+
+        // 同時に接続しても上限を超えない
+        let barrier = Arc::new(Barrier::new(8));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let (registry, barrier) = (registry.clone(), barrier.clone());
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    registry.subscribe_limited("v", DeliveryMode::Queue, Some(3))
+                })
+            })
+            .collect();
+        let viewers: Vec<_> = threads.into_iter().filter_map(|t| t.join().unwrap()).collect();
+        assert_eq!(viewers.len(), 3);
+        assert_eq!(registry.viewer_count("v"), 3);
+    }
+
+    // Frame validation tests
+    #[test]
+    fn validate_image_checks_signatures_and_trailers() {
+        use crate::protocol::{validate_image, ContentType, InvalidImage};
+
+        let jpeg = [0xFF, 0xD8, 0xFF, 0xE0, 1, 2, 0xFF, 0xD9];
+        assert_eq!(validate_image(&jpeg, ContentType::Unknown), Ok(ContentType::Jpeg));
+        assert_eq!(validate_image(&jpeg, ContentType::Jpeg), Ok(ContentType::Jpeg));
+        assert_eq!(
+            validate_image(&jpeg[..6], ContentType::Unknown),
+            Err(InvalidImage::Truncated(ContentType::Jpeg))
+        );
+        assert_eq!(
+            validate_image(&jpeg, ContentType::Png),
+            Err(InvalidImage::Mismatch { declared: ContentType::Png, actual: ContentType::Jpeg })
+        );
+
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(validate_image(png, ContentType::Unknown), Ok(ContentType::Png));
+
+        let mut webp = b"RIFF\x08\0\0\0WEBPVP8 ".to_vec();
+        assert_eq!(validate_image(&webp, ContentType::Webp), Ok(ContentType::Webp));
+        webp.truncate(14);
+        assert_eq!(validate_image(&webp, ContentType::Unknown), Err(InvalidImage::Truncated(ContentType::Webp)));
+
+        assert_eq!(validate_image(b"hello", ContentType::Unknown), Err(InvalidImage::UnknownFormat));
+        assert_eq!(validate_image(&[], ContentType::Unknown), Err(InvalidImage::UnknownFormat));
+    }
+
+    #[tokio::test]
+    async fn invalid_frames_are_dropped_or_rejected_and_counted() {
+        use crate::server::{FrameValidation, ServerConfig, CLOSE_INVALID_FRAME};
+        use futures::SinkExt;
+        use std::sync::atomic::Ordering;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let jpeg = vec![0xFFu8, 0xD8, 7, 0xFF, 0xD9];
+        for validation in [FrameValidation::Drop, FrameValidation::Reject] {
+            let config = ServerConfig {
+                frame_validation: validation,
+                ..ServerConfig::default()
+            };
+            let server = Server::with_config("127.0.0.1:0", config).await.unwrap();
+            let streams = server.streams();
+            let addr = server.spawn().local_addr().to_string();
+
+            let url = |path: &str| format!("ws://{}{}", addr, path);
+            let (mut camera, _) = tokio_tungstenite::connect_async(url("/camera/gate")).await.unwrap();
+            let (mut viewer, _) = tokio_tungstenite::connect_async(url("/view/gate")).await.unwrap();
+            while streams.viewer_count("gate") == 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+
+            camera.send(Message::Binary(b"not an image".to_vec().into())).await.unwrap();
+            camera.send(Message::Binary(jpeg.clone().into())).await.unwrap();
+            let metrics = streams.stream_metrics("gate").unwrap();
+            match validation {
+                FrameValidation::Drop => {
+                    assert_eq!(&next_binary(&mut viewer).await[..], &jpeg[..]);
+                    assert_eq!(metrics.frames_in.load(Ordering::Relaxed), 1);
+                }
+                _ => {
+                    assert_eq!(next_close_code(&mut camera).await, Some(CLOSE_INVALID_FRAME));
+                    assert_eq!(metrics.frames_in.load(Ordering::Relaxed), 0);
+                }
+            }
+            assert_eq!(metrics.invalid_frames.load(Ordering::Relaxed), 1);
+        }
+    }
+
+    // Server handle tests
+    #[tokio::test]
+    async fn servers_bind_ephemeral_ports_and_shut_down_through_handles() {
+        let first = Server::new("127.0.0.1:0").await.unwrap();
+        let second = Server::new("127.0.0.1:0").await.unwrap();
+        assert_ne!(first.local_addr().port(), 0);
+        assert_ne!(first.local_addr(), second.local_addr());
+
+        let first = first.spawn();
+        let second = second.spawn();
+        for handle in [&first, &second] {
+            let addr = handle.local_addr().to_string();
+            assert_eq!(http_call(&addr, "GET", "/status", None).await.0, 200);
+        }
+
+        first.shutdown().await.unwrap();
+        second.shutdown().await.unwrap();
+
+        let mut server = Server::new("127.0.0.1:0").await.unwrap();
+        server.shutdown_token().cancel();
+        server.run().await.unwrap();
+        assert!(server.run().await.is_err());
+    }
+
+    // V4L2 capture tests
+    use crate::camera::v4l2::{self, Capability, Ioctl, PixFormat, PixelFormat, V4l2Source};
+    use crate::camera::{CaptureSettings, FrameSource, SourceSpec};
+    use std::sync::{Arc, Mutex};
+
+    // ドライバの代わりに ioctl の結果を返すモック
+    struct MockDevice {
+        capabilities: u32,
+        formats: Vec<u32>,
+        // S_FMT で要求を別の形式に差し替える (要求, 実際)
+        substitute: Option<(u32, u32)>,
+        // S_FMT が EINVAL で拒否する形式
+        rejected: Vec<u32>,
+        max_size: (u32, u32),
+        frame: Vec<u8>,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MockDevice {
+        fn new(formats: &[&[u8; 4]]) -> Self {
+            Self {
+                capabilities: v4l2::CAP_VIDEO_CAPTURE | v4l2::CAP_STREAMING,
+                formats: formats.iter().map(|code| v4l2::fourcc(code)).collect(),
+                substitute: None,
+                rejected: Vec::new(),
+                max_size: (1280, 720),
+                frame: Vec::new(),
+                calls: Arc::default(),
+            }
+        }
+    }
+
+    impl Ioctl for MockDevice {
+        fn querycap(&mut self) -> std::io::Result<Capability> {
+            Ok(Capability {
+                driver: "mock".to_string(),
+                card: "Mock Camera".to_string(),
+                capabilities: self.capabilities,
+                device_caps: 0,
+            })
+        }
+
+        fn enum_fmt(&mut self, index: u32) -> std::io::Result<Option<u32>> {
+            Ok(self.formats.get(index as usize).copied())
+        }
+
+        fn s_fmt(&mut self, format: PixFormat) -> std::io::Result<PixFormat> {
+            self.calls.lock().unwrap().push(format!("s_fmt {}", v4l2::fourcc_name(format.pixelformat)));
+            if self.rejected.contains(&format.pixelformat) {
+                return Err(std::io::Error::from_raw_os_error(22));
+            }
+            let pixelformat = match self.substitute {
+                Some((requested, actual)) if requested == format.pixelformat => actual,
+                _ => format.pixelformat,
+            };
+            Ok(PixFormat {
+                width: format.width.min(self.max_size.0),
+                height: format.height.min(self.max_size.1),
+                pixelformat,
+                ..PixFormat::default()
+            })
+        }
+
+        fn s_parm(&mut self, numerator: u32, denominator: u32) -> std::io::Result<(u32, u32)> {
+            // 15fps までしか出せないカメラ
+            Ok((numerator, denominator.min(numerator * 15)))
+        }
+
+        fn s_ctrl(&mut self, id: u32, value: i32) -> std::io::Result<()> {
+            self.calls.lock().unwrap().push(format!("s_ctrl {:#x}={}", id, value));
+            Ok(())
+        }
+
+        fn stream_on(&mut self, buffers: u32) -> std::io::Result<()> {
+            self.calls.lock().unwrap().push(format!("stream_on {}", buffers));
+            Ok(())
+        }
+
+        fn dequeue(&mut self) -> std::io::Result<Vec<u8>> {
+            Ok(self.frame.clone())
+        }
+    }
+
+    #[test]
+    fn v4l2_prefers_mjpeg_and_clamps_to_what_the_driver_offers() {
+        let mut device = MockDevice::new(&[b"YUYV", b"MJPG"]);
+        let calls = device.calls.clone();
+        let settings = CaptureSettings { fps: 30.0, quality: 70, width: 1920, height: 1080 };
+        let negotiated = v4l2::negotiate(&mut device, &settings).unwrap();
+
+        assert_eq!(negotiated.format, PixelFormat::Mjpeg);
+        assert_eq!((negotiated.width, negotiated.height), (1280, 720));
+        assert_eq!(negotiated.fps, Some(15.0));
+        assert_eq!(*calls.lock().unwrap(), ["s_fmt MJPG", "s_ctrl 0x9d0903=70"]);
+    }
+
+    #[test]
+    fn v4l2_falls_back_to_yuyv() {
+        let device = MockDevice::new(&[b"YUYV"]);
+        let negotiated = v4l2::negotiate(&mut { device }, &CaptureSettings::default()).unwrap();
+        assert_eq!(negotiated.format, PixelFormat::Yuyv);
+        assert_eq!(negotiated.bytesperline, 640 * 2);
+
+        // MJPEG を挙げておきながら S_FMT で YUYV に変えるドライバ
+        let mut device = MockDevice::new(&[b"MJPG", b"YUYV"]);
+        device.substitute = Some((v4l2::PIX_FMT_MJPEG, v4l2::PIX_FMT_YUYV));
+        let calls = device.calls.clone();
+        let negotiated = v4l2::negotiate(&mut device, &CaptureSettings::default()).unwrap();
+        assert_eq!(negotiated.format, PixelFormat::Yuyv);
+        assert_eq!(*calls.lock().unwrap(), ["s_fmt MJPG", "s_fmt YUYV"]);
+
+        // MJPEG を挙げておきながら S_FMT で EINVAL を返すドライバ
+        let mut device = MockDevice::new(&[b"MJPG", b"YUYV"]);
+        device.rejected = vec![v4l2::PIX_FMT_MJPEG];
+        let calls = device.calls.clone();
+        let negotiated = v4l2::negotiate(&mut device, &CaptureSettings::default()).unwrap();
+        assert_eq!(negotiated.format, PixelFormat::Yuyv);
+        assert_eq!(*calls.lock().unwrap(), ["s_fmt MJPG", "s_fmt YUYV"]);
+    }
+
+    #[test]
+    fn v4l2_rejects_unusable_devices() {
+        let mut device = MockDevice::new(&[b"MJPG"]);
+        device.capabilities = v4l2::CAP_STREAMING;
+        let err = v4l2::negotiate(&mut device, &CaptureSettings::default()).unwrap_err();
+        assert!(err.to_string().contains("not a video capture device"), "{}", err);
+
+        let mut device = MockDevice::new(&[b"MJPG"]);
+        device.capabilities = v4l2::CAP_VIDEO_CAPTURE;
+        let err = v4l2::negotiate(&mut device, &CaptureSettings::default()).unwrap_err();
+        assert!(err.to_string().contains("streaming"), "{}", err);
+
+        let mut device = MockDevice::new(&[b"NV12", b"H264"]);
+        let err = v4l2::negotiate(&mut device, &CaptureSettings::default()).unwrap_err();
+        assert!(err.to_string().contains("offered: NV12, H264"), "{}", err);
+
+        let mut device = MockDevice::new(&[b"MJPG", b"YUYV"]);
+        device.rejected = vec![v4l2::PIX_FMT_MJPEG, v4l2::PIX_FMT_YUYV];
+        let err = v4l2::negotiate(&mut device, &CaptureSettings::default()).unwrap_err();
+        assert!(err.to_string().contains("rejected every usable format"), "{}", err);
+    }
+
+    #[test]
+    fn v4l2_source_streams_mjpeg_as_is_and_encodes_yuyv() {
+        let jpeg = vec![0xFF, 0xD8, 1, 2, 3, 0xFF, 0xD9];
+        let mut device = MockDevice::new(&[b"MJPG"]);
+        device.frame = jpeg.clone();
+        let calls = device.calls.clone();
+        let mut camera = Camera::with_source(V4l2Source::new(device)).build().unwrap();
+        assert_eq!(camera.capture_frame().unwrap(), jpeg);
+        assert_eq!(camera.capture_frame().unwrap(), jpeg);
+        assert_eq!(calls.lock().unwrap().iter().filter(|c| c.starts_with("stream_on")).count(), 1);
+
+        // 灰色一色の 16x8 YUYV
+        let mut device = MockDevice::new(&[b"YUYV"]);
+        device.frame = vec![128; 16 * 8 * 2];
+        let mut source = V4l2Source::new(device);
+        source.configure(&CaptureSettings { width: 16, height: 8, ..CaptureSettings::default() }).unwrap();
+        let frame = source.capture_frame().unwrap();
+        assert_eq!(&frame[..2], &[0xFF, 0xD8]);
+        assert_eq!(&frame[frame.len() - 2..], &[0xFF, 0xD9]);
+        assert_eq!(crate::protocol::validate_image(&frame, crate::protocol::ContentType::Jpeg).unwrap(), crate::protocol::ContentType::Jpeg);
+
+        // 解像度と合わないバッファはエラー
+        let mut device = MockDevice::new(&[b"YUYV"]);
+        device.frame = vec![128; 10];
+        let mut source = V4l2Source::new(device);
+        source.configure(&CaptureSettings::default()).unwrap();
+        assert!(source.capture_frame().is_err());
+    }
+
+    #[test]
+    fn source_spec_parses_cli_values() {
+        assert_eq!("none".parse::<SourceSpec>().unwrap(), SourceSpec::None);
+        assert!(Camera::open(&SourceSpec::None).is_err());
+        assert_eq!("pattern".parse::<SourceSpec>().unwrap(), SourceSpec::Pattern);
+        assert_eq!("synthetic".parse::<SourceSpec>().unwrap(), SourceSpec::Synthetic);
+        assert_eq!("v4l2".parse::<SourceSpec>().unwrap(), SourceSpec::V4l2(0));
+        assert_eq!("v4l2:2".parse::<SourceSpec>().unwrap(), SourceSpec::V4l2(2));
+        assert_eq!("file:clips/a.mjpeg".parse::<SourceSpec>().unwrap(), SourceSpec::File("clips/a.mjpeg".into()));
+        assert!("file:".parse::<SourceSpec>().is_err());
+        assert!("v4l2:-1".parse::<SourceSpec>().is_err());
+        assert!("webcam".parse::<SourceSpec>().is_err());
+    }
+
+    // Test pattern tests
+    use crate::camera::pattern::{self, Layout, PatternSource, BARS, BOX_COLOR};
+
+    fn decode_jpeg(jpeg: &[u8]) -> (usize, usize, Vec<u8>) {
+        let mut decoder = jpeg_decoder::Decoder::new(jpeg);
+        let pixels = decoder.decode().unwrap();
+        let info = decoder.info().unwrap();
+        assert_eq!(info.pixel_format, jpeg_decoder::PixelFormat::RGB24);
+        (info.width as usize, info.height as usize, pixels)
+    }
+
+    fn assert_close(actual: &[u8], expected: [u8; 3], what: &str) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((*a as i32 - e as i32).abs() <= 24, "{}: {:?} is not close to {:?}", what, actual, expected);
+        }
+    }
+
+    #[test]
+    fn pattern_frames_decode_to_bars_and_a_box() {
+        let mut camera = Camera::pattern().resolution(320, 240).quality(90).build().unwrap();
+        let (width, height, pixels) = decode_jpeg(&camera.capture_frame().unwrap());
+        assert_eq!((width, height), (320, 240));
+        let pixel = |x: usize, y: usize| &pixels[(y * width + x) * 3..][..3];
+
+        for (i, color) in BARS.iter().enumerate() {
+            let x = (2 * i + 1) * width / 14;
+            assert_close(pixel(x, height / 12), *color, &format!("bar {}", i));
+        }
+        // フレーム0の箱は左端にある
+        let layout = Layout::new(width, height);
+        let center = layout.box_top + layout.box_size / 2;
+        assert_close(pixel(layout.box_size / 2, center), BOX_COLOR, "box");
+        assert_close(pixel(width - 4, height - 2), [0, 0, 0], "text band");
+
+        // 次のフレームでは箱が右へ動く
+        let (_, _, next) = decode_jpeg(&camera.capture_frame().unwrap());
+        assert!(layout.box_left(1) > layout.box_left(0));
+        assert_ne!(pixels, next);
+    }
+
+    #[test]
+    fn pattern_burns_in_the_counter_and_timestamp() {
+        assert_eq!(pattern::counter_text(42), "000042");
+        assert_eq!(pattern::timestamp_text(Duration::from_millis(45_296_789)), "12:34:56.789");
+        assert_eq!(pattern::timestamp_text(Duration::from_secs(86_400 + 1)), "00:00:01.000");
+
+        let layout = Layout::new(320, 240);
+        let time = Duration::from_millis(1234);
+        let base = pattern::render(&layout, 0, time);
+        // 変化した画素がどこにあるか
+        let changed = |other: &[u8]| -> Vec<(usize, usize)> {
+            (0..layout.width * layout.height)
+                .filter(|i| base[i * 3..i * 3 + 3] != other[i * 3..i * 3 + 3])
+                .map(|i| (i % layout.width, i / layout.width))
+                .collect()
+        };
+
+        // 番号が変わると左下の文字（と箱）だけが変わる
+        let counted = changed(&pattern::render(&layout, 8, time));
+        assert!(counted.iter().any(|&(x, y)| y >= layout.text_top && x < layout.width / 2));
+        assert!(counted.iter().all(|&(x, y)| {
+            (y >= layout.text_top && x < layout.width / 2)
+                || (layout.box_top..layout.box_top + layout.box_size).contains(&y)
+        }));
+
+        // 時刻が変わると右下の文字だけが変わる
+        let timed = changed(&pattern::render(&layout, 0, time + Duration::from_millis(1)));
+        assert!(!timed.is_empty());
+        assert!(timed.iter().all(|&(x, y)| y >= layout.text_top && x >= layout.width / 2));
+    }
+
+    #[test]
+    fn pattern_quality_changes_size_and_fidelity() {
+        let encode = |quality: u8| {
+            let mut source = PatternSource::default();
+            let settings = CaptureSettings { quality, width: 320, height: 240, ..CaptureSettings::default() };
+            source.configure(&settings).unwrap();
+            source.encode(3, Duration::from_secs(1)).unwrap()
+        };
+        let reference = pattern::render(&Layout::new(320, 240), 3, Duration::from_secs(1));
+        let error = |jpeg: &[u8]| -> f64 {
+            let (_, _, pixels) = decode_jpeg(jpeg);
+            let total: u64 = pixels.iter().zip(&reference).map(|(a, b)| (*a as i64 - *b as i64).unsigned_abs()).sum();
+            total as f64 / pixels.len() as f64
+        };
+
+        let high = encode(95);
+        let low = encode(10);
+        assert!(high.len() > low.len(), "{} <= {}", high.len(), low.len());
+        assert!(error(&high) < error(&low), "{} >= {}", error(&high), error(&low));
+        assert!(error(&high) < 4.0);
+    }
+
+    // Replay tests
+    use crate::camera::replay::{ReplaySource, SessionWriter};
+    use crate::protocol::FrameHeader;
+
+    // SOI, APP0, SOS with one byte of scan data, EOI
+    fn tiny_jpeg(n: u8) -> Vec<u8> {
+        vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x03, n, 0xFF, 0xDA, 0x00, 0x02, n, 0xFF, 0xD9]
+    }
+
+    fn capture_n(source: &mut impl FrameSource, n: usize) -> Vec<Vec<u8>> {
+        (0..n).map(|_| source.capture_frame().unwrap()).collect()
+    }
+
+    #[test]
+    fn replay_splits_mjpeg_streams_and_loops() {
+        let dir = temp_dir("replay-mjpeg");
+        let path = dir.join("clip.mjpeg");
+        // フレーム間のゴミ（multipart の境界など）は読み飛ばす
+        let mut data = tiny_jpeg(1);
+        data.extend_from_slice(b"\r\n--frame\r\n");
+        data.extend(tiny_jpeg(2));
+        data.extend(tiny_jpeg(3));
+        std::fs::write(&path, &data).unwrap();
+
+        let mut replay = ReplaySource::open(&path).unwrap();
+        assert_eq!(replay.len(), 3);
+        let frames = capture_n(&mut replay, 4);
+        assert_eq!(frames, [tiny_jpeg(1), tiny_jpeg(2), tiny_jpeg(3), tiny_jpeg(1)]);
+
+        let mut once = ReplaySource::open(&path).unwrap().looping(false);
+        capture_n(&mut once, 3);
+        assert!(!once.is_open());
+        assert!(once.capture_frame().is_err());
+
+        std::fs::write(dir.join("junk.bin"), b"not a video").unwrap();
+        assert!(ReplaySource::open(dir.join("junk.bin")).is_err());
+        assert!(ReplaySource::open(dir.join("missing.mjpeg")).is_err());
+    }
+
+    // 本物の JPEG の APP1 (EXIF) にサムネイルの JPEG を丸ごと埋め込む
+    fn jpeg_with_thumbnail(frame: u64) -> Vec<u8> {
+        let mut thumb_source = PatternSource::default();
+        thumb_source.configure(&CaptureSettings { width: 16, height: 16, ..Default::default() }).unwrap();
+        let thumbnail = thumb_source.encode(frame, Duration::ZERO).unwrap();
+        let mut source = PatternSource::default();
+        source.configure(&CaptureSettings { width: 64, height: 48, ..Default::default() }).unwrap();
+        let image = source.encode(frame, Duration::ZERO).unwrap();
+
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend(&thumbnail);
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        jpeg.extend(((app1.len() + 2) as u16).to_be_bytes());
+        jpeg.extend(app1);
+        jpeg.extend(&image[2..]);
+        jpeg
+    }
+
+    #[test]
+    fn replay_does_not_split_frames_at_an_embedded_thumbnail() {
+        let dir = temp_dir("replay-thumbnail");
+        let path = dir.join("clip.mjpeg");
+        let (first, second) = (jpeg_with_thumbnail(1), jpeg_with_thumbnail(2));
+        let mut data = first.clone();
+        data.extend(tiny_jpeg(3));
+        // 壊れたフレーム（セグメント長が 0）は読み飛ばす
+        data.extend([0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x00]);
+        data.extend(&second);
+        // 途中で切れた最後のフレームは捨てる
+        data.extend(&first[..first.len() / 2]);
+        std::fs::write(&path, &data).unwrap();
+
+        let mut replay = ReplaySource::open(&path).unwrap();
+        assert_eq!(replay.len(), 3);
+        let frames = capture_n(&mut replay, 3);
+        assert_eq!(frames, [first, tiny_jpeg(3), second]);
+        assert_eq!(decode_jpeg(&frames[0]).0, 64);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replay_plays_numbered_jpegs_in_numeric_order() {
+        let dir = temp_dir("replay-dir");
+        for n in [10u8, 2, 1] {
+            std::fs::write(dir.join(format!("cam1_{}.jpg", n)), tiny_jpeg(n)).unwrap();
+        }
+        std::fs::write(dir.join("notes.txt"), b"ignored").unwrap();
+
+        let mut camera = Camera::open(&SourceSpec::File(dir.clone())).unwrap().build().unwrap();
+        let frames: Vec<_> = (0..3).map(|_| camera.capture_frame().unwrap()).collect();
+        assert_eq!(frames, [tiny_jpeg(1), tiny_jpeg(2), tiny_jpeg(10)]);
+
+        assert!(ReplaySource::open(temp_dir("replay-empty")).is_err());
+    }
+
+    #[test]
+    fn replay_reads_sessions_and_seeks() {
+        let dir = temp_dir("replay-session");
+        let path = dir.join("lobby.w2ws");
+        let mut writer = SessionWriter::new(std::fs::File::create(&path).unwrap()).unwrap();
+        for n in 0..4 {
+            let payload = tiny_jpeg(n);
+            let header = FrameHeader { seq: n as u64, stream_id: "lobby".to_string(), ..FrameHeader::for_raw(&payload) };
+            writer.write_frame(&header, &payload).unwrap();
+        }
+        writer.into_inner().unwrap();
+
+        let mut replay = ReplaySource::open(&path).unwrap();
+        assert_eq!(replay.len(), 4);
+        replay.seek(2).unwrap();
+        assert_eq!(capture_n(&mut replay, 3), [tiny_jpeg(2), tiny_jpeg(3), tiny_jpeg(0)]);
+        assert!(replay.seek(4).is_err());
+
+        // 2fps なら 1.5 秒目は3枚目、ループ中は長さで折り返す
+        replay.configure(&CaptureSettings { fps: 2.0, ..CaptureSettings::default() }).unwrap();
+        replay.seek_time(Duration::from_millis(1500)).unwrap();
+        assert_eq!(replay.position(), 3);
+        replay.seek_time(Duration::from_secs(3)).unwrap();
+        assert_eq!(replay.position(), 2);
+
+        // 途中で切れたセッションは読み込まない
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() - 3]).unwrap();
+        assert!(ReplaySource::open(&path).is_err());
+    }
+
+    #[test]
+    fn replay_realtime_paces_frames_at_the_configured_fps() {
+        let dir = temp_dir("replay-realtime");
+        let path = dir.join("clip.mjpeg");
+        std::fs::write(&path, [tiny_jpeg(1), tiny_jpeg(2)].concat()).unwrap();
+
+        let mut camera = Camera::with_source(ReplaySource::open(&path).unwrap().realtime(true))
+            .fps(20.0)
+            .build()
+            .unwrap();
+        let start = Instant::now();
+        for _ in 0..6 {
+            camera.capture_frame().unwrap();
+        }
+        // 最初のフレームはすぐ、残り5枚は 50ms 間隔
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(240), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+    }
+
+    // Frame pacing tests
+    use crate::camera::pacing::{FpsMeter, FramePacer};
+
+    // 時計を止めたランタイムで、1フレームごとに `work` だけ時間を使いながら `run` の間だけ回す
+    async fn paced_frames(fps: f64, work: Duration, run: Duration) -> (u64, crate::camera::FpsReport) {
+        let mut pacer = FramePacer::new(fps);
+        let start = tokio::time::Instant::now();
+        let mut frames = 0;
+        while pacer.tick().await - start < run {
+            frames += 1;
+            tokio::time::sleep(work).await;
+        }
+        (frames, pacer.meter().report())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pacer_holds_the_target_rate_despite_capture_time() {
+        // 16.7ms 間隔に 5ms の処理: sleep 方式なら約 46fps に落ちる
+        let (frames, report) = paced_frames(60.0, Duration::from_millis(5), Duration::from_secs(2)).await;
+        assert_eq!(frames, 120);
+        assert!((report.actual_fps - 60.0).abs() < 0.1, "{:?}", report);
+        assert!(report.max_jitter < Duration::from_millis(1), "{:?}", report);
+
+        // 間隔より長い処理はティックを飛ばすだけで、取り返そうと連射しない
+        let (frames, report) = paced_frames(60.0, Duration::from_millis(25), Duration::from_secs(2)).await;
+        assert!((60..120).contains(&frames), "{}", frames);
+        assert!(report.actual_fps < 60.0, "{:?}", report);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pacer_reports_once_per_window() {
+        let mut pacer = Camera::synthetic().fps(10.0).pacer();
+        let mut reports = Vec::new();
+        for _ in 0..25 {
+            pacer.tick().await;
+            reports.extend(pacer.take_report());
+        }
+        // 0.0s〜2.4s の 25 ティックで 1 秒の窓が2つ。境目のティックは前の窓に数える
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].frames, 11);
+        assert_eq!(reports[1].frames, 10);
+        for report in &reports {
+            assert_eq!(report.target_fps, 10.0);
+            assert!((report.actual_fps - 10.0).abs() < 0.01, "{:?}", report);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pacer_survives_absurd_rates() {
+        use crate::camera::pacing::MIN_FRAME_PERIOD;
+
+        let mut fast = FramePacer::new(1e10);
+        let first = fast.tick().await;
+        assert_eq!(fast.tick().await - first, MIN_FRAME_PERIOD);
+
+        let mut nan = FramePacer::new(f64::NAN);
+        let first = nan.tick().await;
+        assert_eq!(nan.tick().await - first, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn fps_meter_measures_rate_and_jitter() {
+        let start = tokio::time::Instant::now();
+        let mut meter = FpsMeter::new(10.0);
+        assert_eq!(meter.report().actual_fps, 0.0);
+
+        // 100ms 目標に対して 90ms と 110ms の交互
+        let mut at = start;
+        for i in 0..=10u64 {
+            meter.record(at);
+            at += Duration::from_millis(if i % 2 == 0 { 90 } else { 110 });
+        }
+        let report = meter.report();
+        assert_eq!(report.frames, 11);
+        assert!((report.actual_fps - 10.0).abs() < 0.01, "{:?}", report);
+        assert_eq!(report.mean_jitter, Duration::from_millis(10));
+        assert_eq!(report.max_jitter, Duration::from_millis(10));
+        assert!(meter.take_report(Duration::from_secs(2)).is_none());
+        assert!(meter.take_report(Duration::from_secs(1)).is_some());
+        assert_eq!(meter.report().frames, 0);
+    }
+
+    // Capture pipeline tests
+    use crate::camera::CapturePipeline;
+
+    // 1フレームに `delay` かかる（スレッドをブロックする）ソース
+    struct SlowSource {
+        delay: Duration,
+        fail: bool,
+    }
+
+    impl FrameSource for SlowSource {
+        fn capture_frame(&mut self) -> anyhow::Result<Vec<u8>> {
+            std::thread::sleep(self.delay);
+            if self.fail {
+                anyhow::bail!("sensor unplugged");
+            }
+            Ok(tiny_jpeg(0))
+        }
+    }
+
+    #[tokio::test]
+    async fn pipeline_delivers_frames_without_blocking_the_runtime() {
+        // 単一スレッドのランタイムでも 100ms かかるキャプチャがタイマーを止めない
+        let source = SlowSource { delay: Duration::from_millis(100), fail: false };
+        let mut capture = CapturePipeline::spawn(source, 30.0, 2).unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        for _ in 0..5 {
+            let start = Instant::now();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            assert!(start.elapsed() < Duration::from_millis(50), "{:?}", start.elapsed());
+        }
+
+        assert_eq!(capture.recv().await.unwrap().unwrap(), tiny_jpeg(0));
+        let stats = capture.stats();
+        assert!(stats.captured() >= 1);
+        capture.close().await;
+    }
+
+    #[tokio::test]
+    async fn pipeline_skips_ticks_when_capture_is_slower_than_the_target() {
+        let source = SlowSource { delay: Duration::from_millis(40), fail: false };
+        let mut capture = CapturePipeline::spawn(source, 100.0, 2).unwrap();
+        let start = Instant::now();
+        let mut frames = 0;
+        while start.elapsed() < Duration::from_millis(600) {
+            capture.recv().await.unwrap().unwrap();
+            frames += 1;
+        }
+        // 40ms ごとにしか撮れないので 100fps のティックの大半は捨てられる
+        assert!(frames <= 20, "{}", frames);
+        assert!(capture.stats().skipped_ticks() > 20, "{:?}", capture.stats());
+        assert_eq!(capture.stats().dropped_frames(), 0);
+        capture.close().await;
+    }
+
+    #[tokio::test]
+    async fn pipeline_drops_frames_for_a_slow_consumer_and_forwards_errors() {
+        let mut capture = Camera::synthetic().fps(200.0).into_pipeline(2).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let stats = capture.stats();
+        assert!(stats.dropped_frames() > 0, "{:?}", stats);
+        // バッファに残っているのは最大2枚
+        for _ in 0..2 {
+            let frame = tokio::time::timeout(Duration::from_millis(1), capture.recv()).await;
+            assert!(frame.unwrap().unwrap().is_ok());
+        }
+        capture.close().await;
+
+        let source = SlowSource { delay: Duration::ZERO, fail: true };
+        let mut capture = CapturePipeline::spawn(source, 50.0, 2).unwrap();
+        let err = capture.recv().await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("unplugged"));
+        assert!(capture.stats().errors() >= 1);
+    }
+
+    // 撮った順に番号を振るソース
+    struct CountingSource(u32);
+
+    impl FrameSource for CountingSource {
+        fn capture_frame(&mut self) -> anyhow::Result<Vec<u8>> {
+            self.0 += 1;
+            Ok(self.0.to_be_bytes().to_vec())
+        }
+    }
+
+    #[tokio::test]
+    async fn pipeline_gives_a_slow_consumer_the_newest_frames() {
+        let mut capture = CapturePipeline::spawn(CountingSource(0), 200.0, 2).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let number = |frame: Vec<u8>| u32::from_be_bytes(frame.try_into().unwrap());
+
+        // 古いフレームが押し出されているので、最初の1枚目ではなく最近の2枚が残っている
+        let first = number(capture.recv().await.unwrap().unwrap());
+        let second = number(capture.recv().await.unwrap().unwrap());
+        let stats = capture.stats();
+        assert!(first > 10, "{} ({:?})", first, stats);
+        assert_eq!(second, first + 1);
+        assert!(stats.dropped_frames() >= first as u64 - 1, "{} ({:?})", first, stats);
+        capture.close().await;
+    }
+
+    #[tokio::test]
+    async fn pipeline_ends_when_the_source_runs_out() {
+        let dir = temp_dir("pipeline-replay");
+        let path = dir.join("clip.mjpeg");
+        std::fs::write(&path, [tiny_jpeg(1), tiny_jpeg(2), tiny_jpeg(3)].concat()).unwrap();
+
+        let replay = ReplaySource::open(&path).unwrap().looping(false);
+        let mut capture = Camera::with_source(replay).fps(100.0).build().unwrap().into_pipeline(4).unwrap();
+        let mut frames = Vec::new();
+        while let Some(frame) = capture.recv().await {
+            frames.push(frame.unwrap());
+        }
+        assert_eq!(frames, [tiny_jpeg(1), tiny_jpeg(2), tiny_jpeg(3)]);
+    }
 }
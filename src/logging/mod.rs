@@ -0,0 +1,69 @@
+// src/logging/mod.rs
+use anyhow::Result;
+use std::io::IsTerminal;
+use std::str::FromStr;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+
+// RUST_LOG が設定されていないときのフィルタ
+pub const DEFAULT_FILTER: &str = "info";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    // 人が読むための1行形式
+    #[default]
+    Pretty,
+    // 1行1イベントの JSON（ログ収集基盤向け）
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => anyhow::bail!("Unknown log format `{}` (expected pretty or json)", s),
+        }
+    }
+}
+
+// `filter` uses `RUST_LOG` syntax, e.g. `info,web2ws::server=debug`. JSON
+// events carry the fields of every enclosing span (connection id, peer,
+// role, stream). `ansi` only affects the pretty format.
+pub fn subscriber<W>(
+    format: LogFormat,
+    filter: &str,
+    writer: W,
+    ansi: bool,
+) -> Result<Box<dyn Subscriber + Send + Sync>>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_new(filter)?)
+        .with_writer(writer)
+        .with_ansi(ansi && format == LogFormat::Pretty);
+    Ok(match format {
+        LogFormat::Pretty => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(
+            builder
+                .json()
+                .flatten_event(true)
+                .with_current_span(true)
+                .with_span_list(true)
+                .finish(),
+        ),
+    })
+}
+
+// Installs the global subscriber writing to stdout, filtered by `RUST_LOG`.
+// Colors are used only when stdout is a terminal.
+pub fn init(format: LogFormat) -> Result<()> {
+    let filter = std::env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_FILTER.to_string());
+    let ansi = std::io::stdout().is_terminal();
+    tracing::subscriber::set_global_default(subscriber(format, &filter, std::io::stdout, ansi)?)?;
+    Ok(())
+}
@@ -1,76 +1,357 @@
-mod camera;
-mod websocket;
-mod server;
-
 use clap::Parser;
-use camera::Camera;
-use server::Server;
-use std::io::Write;
+use web2ws::camera::pipeline::DEFAULT_CAPTURE_BUFFER;
+use web2ws::camera::{Camera, ReplaySource, SourceSpec, MAX_FPS};
+use web2ws::logging::{self, LogFormat};
+use web2ws::server::auth::{Authenticator, Scope};
+use web2ws::server::metrics::Metrics;
+use web2ws::server::{
+    DeliveryMode, FrameValidation, Publisher, PublisherPolicy, Server, ServerConfig, StreamRegistry, DEFAULT_STREAM,
+};
+use tracing::{info, info_span, warn, Instrument};
+use std::path::PathBuf;
+use std::future::IntoFuture;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
 #[derive(Parser)]
 struct Args {
-    #[arg(short, long, default_value_t = 30.0)]
+    // 0 より大きく MAX_FPS 以下
+    #[arg(short, long, default_value_t = 30.0, value_parser = parse_fps)]
     fps: f64,
     #[arg(short, long, default_value_t = 85)]
     quality: u8,
+    // フレームの取得元: pattern | v4l2[:N]（/dev/videoN）| synthetic | file:PATH | none（キャプチャしない）
+    // カメラのない環境でも起動できるよう既定はテストパターン
+    #[arg(long, default_value = "pattern")]
+    source: SourceSpec,
+    // file: ソースを最後まで再生したら繰り返さずに止める
+    #[arg(long)]
+    no_loop: bool,
+    // file: ソースの再生を始めるフレーム番号
+    #[arg(long, default_value_t = 0)]
+    replay_start: usize,
+    // 要求する解像度（デバイスが近いものを選ぶ）
+    #[arg(long, default_value_t = 640)]
+    width: u32,
+    #[arg(long, default_value_t = 480)]
+    height: u32,
     #[arg(short, long, default_value = "127.0.0.1:9001")]
     bind: String,
+    // サーバー側キャプチャの配信先ストリーム
+    #[arg(short, long, default_value = DEFAULT_STREAM)]
+    stream: String,
+    // 独自の HTML/JS/CSS を配信するディレクトリ（埋め込みページより優先）
+    #[arg(long)]
+    static_dir: Option<PathBuf>,
+    // PEM 証明書と秘密鍵（指定すると https:// と wss:// で待ち受ける）
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    // 配信用トークン: TOKEN または TOKEN@stream1,stream2（複数指定可）
+    #[arg(long)]
+    publish_token: Vec<String>,
+    // 視聴用トークン: TOKEN または TOKEN@stream1,stream2（複数指定可）
+    #[arg(long)]
+    view_token: Vec<String>,
+    // 管理 API (/api のキック・ストリーム終了) 用トークン（複数指定可）
+    #[arg(long)]
+    admin_token: Vec<String>,
+    // `<publish|view|admin> <token> [stream ...]` を1行ずつ書いたファイル
+    #[arg(long)]
+    tokens_file: Option<PathBuf>,
+    // ログ形式: pretty | json（フィルタは RUST_LOG で指定）
+    #[arg(long, default_value = "pretty")]
+    log_format: LogFormat,
+    // サーバー設定の TOML ファイル（以下のオプションで上書きできる）
+    #[arg(long)]
+    config: Option<PathBuf>,
+    // 2人目の配信者の扱い: reject | replace | merge（既定: merge）
+    #[arg(long)]
+    publisher_policy: Option<PublisherPolicy>,
+    // ビューアへの既定の配信方法: queue | latest（既定: queue）
+    #[arg(long)]
+    delivery: Option<DeliveryMode>,
+    // 新しいビューアに直前のフレームを送る鮮度上限（ミリ秒、0で無効、既定: 2000）
+    #[arg(long)]
+    keyframe_max_age_ms: Option<u64>,
+    // 終了時に接続が閉じるのを待つ上限（ミリ秒、既定: 5000）
+    #[arg(long)]
+    drain_timeout_ms: Option<u64>,
+    // ストリームごとのチャネル容量（フレーム数、既定: 100）
+    #[arg(long)]
+    channel_capacity: Option<usize>,
+    #[arg(long)]
+    max_viewers_per_stream: Option<usize>,
+    #[arg(long)]
+    max_publishers: Option<usize>,
+    #[arg(long)]
+    max_connections: Option<usize>,
+    // クライアントから受け取る WebSocket メッセージの上限（バイト、既定: 16 MiB）
+    #[arg(long)]
+    max_frame_size: Option<usize>,
+    // 配信者のフレームが JPEG/PNG/WebP か検査する: off | drop | reject（既定: off）
+    #[arg(long)]
+    frame_validation: Option<FrameValidation>,
+    // TLS ハンドシェイクと最初のリクエストの待ち時間（ミリ秒、既定: 5000）
+    #[arg(long)]
+    handshake_timeout_ms: Option<u64>,
+    // アイドルな keep-alive 接続を閉じるまでの時間（ミリ秒、既定: 5000）
+    #[arg(long)]
+    idle_timeout_ms: Option<u64>,
+    // Pong も含めて何も届かない WebSocket クライアントを閉じるまでの時間（ミリ秒、既定: 10000）
+    #[arg(long)]
+    ws_idle_timeout_ms: Option<u64>,
+}
+
+fn parse_fps(s: &str) -> Result<f64, String> {
+    let fps: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    if !fps.is_finite() || fps <= 0.0 || fps > MAX_FPS {
+        return Err(format!("must be a number above 0 and at most {}", MAX_FPS));
+    }
+    Ok(fps)
+}
+
+impl Args {
+    // 設定ファイル（なければ既定値）にコマンドラインの指定を重ねる
+    fn server_config(&self) -> anyhow::Result<ServerConfig> {
+        let mut config = match &self.config {
+            Some(path) => ServerConfig::load(path)?,
+            None => ServerConfig::default(),
+        };
+        let millis = Duration::from_millis;
+        if let Some(policy) = self.publisher_policy {
+            config.publisher_policy = policy;
+        }
+        if let Some(delivery) = self.delivery {
+            config.delivery_mode = delivery;
+        }
+        if let Some(ms) = self.keyframe_max_age_ms {
+            config.keyframe_max_age = millis(ms);
+        }
+        if let Some(ms) = self.drain_timeout_ms {
+            config.drain_timeout = millis(ms);
+        }
+        if let Some(capacity) = self.channel_capacity {
+            config.channel_capacity = capacity;
+        }
+        if let Some(max) = self.max_viewers_per_stream {
+            config.max_viewers_per_stream = Some(max);
+        }
+        if let Some(max) = self.max_publishers {
+            config.max_publishers = Some(max);
+        }
+        if let Some(max) = self.max_connections {
+            config.max_connections = Some(max);
+        }
+        if let Some(max) = self.max_frame_size {
+            config.max_frame_size = max;
+        }
+        if let Some(validation) = self.frame_validation {
+            config.frame_validation = validation;
+        }
+        if let Some(ms) = self.handshake_timeout_ms {
+            config.handshake_timeout = millis(ms);
+        }
+        if let Some(ms) = self.idle_timeout_ms {
+            config.idle_timeout = millis(ms);
+        }
+        if let Some(ms) = self.ws_idle_timeout_ms {
+            config.ws_idle_timeout = millis(ms);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+// Ctrl-C (SIGINT) または SIGTERM を待つ
+async fn shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+// Broadcasts frames from the server-side camera until shutdown, eviction or the end of the source
+fn spawn_capture(
+    camera: Camera,
+    publisher: Publisher,
+    stream: &str,
+    streams: Arc<StreamRegistry>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> anyhow::Result<JoinHandle<()>> {
+    let mut capture = camera.into_pipeline(DEFAULT_CAPTURE_BUFFER)?;
+    let capture_stats = capture.stats();
+    let capture_span = info_span!("capture", role = "publisher", stream = %stream);
+    let capture = async move {
+        loop {
+            let frame = tokio::select! {
+                frame = capture.recv() => frame,
+                _ = shutdown.cancelled() => {
+                    info!("Server capture stopped for shutdown");
+                    break;
+                }
+            };
+            if publisher.is_evicted() {
+                info!("Server capture stopped: replaced by a camera client or closed by an admin");
+                break;
+            }
+
+            match frame {
+                Some(Ok(frame)) => {
+                    // Broadcast frame to the stream's viewers
+                    publisher.send(frame);
+                }
+                Some(Err(e)) => {
+                    warn!(error = %e, "Capture error");
+                    metrics.record_capture_error();
+                }
+                None => {
+                    info!("Server capture stopped: source has no more frames");
+                    break;
+                }
+            }
+
+            // Report actual vs. target FPS every ~1 second
+            if let Some(report) = capture.take_report() {
+                let latency = streams.latency(publisher.stream_id()).unwrap_or_default();
+                let describe = |p: Option<web2ws::server::latency::Percentiles>| {
+                    p.map_or_else(|| "n/a".to_string(), |p| p.to_string())
+                };
+                info!(
+                    frames = report.frames,
+                    target_fps = report.target_fps,
+                    actual_fps = format!("{:.2}", report.actual_fps),
+                    jitter = ?report.mean_jitter,
+                    max_jitter = ?report.max_jitter,
+                    skipped_ticks = capture_stats.skipped_ticks(),
+                    dropped_frames = capture_stats.dropped_frames(),
+                    glass_to_glass = %describe(latency.glass_to_glass),
+                    rtt = %describe(latency.rtt),
+                    "[FPS] {:.1} of {} fps",
+                    report.actual_fps,
+                    report.target_fps
+                );
+            }
+        }
+        // デバイスを閉じるまで待つ
+        capture.close().await;
+    };
+    Ok(tokio::spawn(capture.instrument(capture_span)))
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    logging::init(args.log_format)?;
+    let config = args.server_config()?;
     
     // Camera初期化
-    let camera = Camera::new(0)?
-        .fps(args.fps)
-        .quality(args.quality)
-        .build()?;
-    
-    println!("Camera initialized - FPS: {}, Quality: {}", args.fps, args.quality);
+    let camera = match &args.source {
+        // サーバー側キャプチャなし: 配信者の枠を取らない
+        SourceSpec::None => None,
+        SourceSpec::File(path) => {
+            let mut replay = ReplaySource::open(path)?.looping(!args.no_loop);
+            replay.seek(args.replay_start)?;
+            Some(Camera::with_source(replay))
+        }
+        spec => Some(Camera::open(spec)?),
+    };
+    let camera = match camera {
+        Some(camera) => {
+            let camera = camera
+                .fps(args.fps)
+                .quality(args.quality)
+                .resolution(args.width, args.height)
+                .build()?;
+            info!(source = ?args.source, fps = args.fps, quality = args.quality, "Camera initialized");
+            Some(camera)
+        }
+        None => {
+            info!("Server-side capture disabled, streams are fed by camera clients only");
+            None
+        }
+    };
     
     // Serverインスタンス作成
-    let mut server = Server::new(&args.bind).await?;
-    let broadcast_tx = server.get_broadcast_sender();
-    println!("Server starting on {}", args.bind);
-
-    // Spawn server run task
-    let server_handle = tokio::spawn(async move {
-        server.run().await
-    });
-
-    // Camera capture task: captures frames at target FPS and broadcasts
-    let mut camera = camera;
-    let target_fps = args.fps;
-    tokio::spawn(async move {
-        let frame_interval = std::time::Duration::from_secs_f64(1.0 / target_fps);
-        let mut frame_count: u64 = 0;
-        let mut last_report = std::time::Instant::now();
+    let mut server = Server::with_config(&args.bind, config).await?;
+    if let Some(dir) = &args.static_dir {
+        server = server.static_dir(dir);
+        info!(dir = %dir.display(), "Serving static files");
+    }
+    if let (Some(cert), Some(key)) = (&args.tls_cert, &args.tls_key) {
+        server = server.tls(cert, key)?;
+        info!(cert = %cert.display(), "TLS enabled");
+    }
 
-        loop {
-            match camera.capture_frame() {
-                Ok(frame) => {
-                    frame_count += 1;
-                    // Broadcast frame directly
-                    let _ = broadcast_tx.send(frame);
-                }
-                Err(e) => eprintln!("Capture error: {}", e),
-            }
+    let mut auth = Authenticator::new();
+    for spec in &args.publish_token {
+        auth.add_token_spec(Scope::Publish, spec)?;
+    }
+    for spec in &args.view_token {
+        auth.add_token_spec(Scope::View, spec)?;
+    }
+    for token in &args.admin_token {
+        auth.add_token(Scope::Admin, token, None)?;
+    }
+    if let Some(path) = &args.tokens_file {
+        auth.load_file(path)?;
+    }
+    if auth.has_tokens() {
+        info!(
+            publish = auth.is_enabled(Scope::Publish),
+            view = auth.is_enabled(Scope::View),
+            admin = auth.is_enabled(Scope::Admin),
+            "Token auth enabled"
+        );
+    }
+    server = server.auth(auth);
+    let publisher = match &camera {
+        Some(_) => Some(server.publisher(&args.stream)?),
+        None => None,
+    };
+    let streams = server.streams();
+    let metrics = server.metrics();
+    info!(bind = %server.local_addr(), "Server starting");
 
-            // Report FPS every ~1 second
-            if last_report.elapsed() >= std::time::Duration::from_millis(1000) {
-                println!("[FPS] Captured {} frames in ~1s (target: {})", frame_count, target_fps);
-                std::io::Write::flush(&mut std::io::stdout()).ok();
-                frame_count = 0;
-                last_report = std::time::Instant::now();
-            }
+    // Spawn server run task
+    let server = server.spawn();
+    let shutdown = server.shutdown_token();
+    let mut server = server.into_future();
 
-            tokio::time::sleep(frame_interval).await;
+    // Camera capture runs on its own thread; this task broadcasts what it delivers
+    let capture_handle = match (camera, publisher) {
+        (Some(camera), Some(publisher)) => {
+            Some(spawn_capture(camera, publisher, &args.stream, streams, metrics, shutdown.clone())?)
         }
-    });
+        _ => None,
+    };
 
-    // Wait for server
-    server_handle.await??;
+    // Run until the server fails or a shutdown signal arrives
+    tokio::select! {
+        result = &mut server => return result,
+        result = shutdown_signal() => {
+            result?;
+            info!("Shutdown signal received, closing connections");
+        }
+    }
+    shutdown.cancel();
+    if let Some(capture_handle) = capture_handle {
+        capture_handle.await?;
+    }
+    server.await?;
+    info!("Server stopped");
     
     Ok(())
 }
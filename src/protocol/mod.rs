@@ -0,0 +1,265 @@
+// src/protocol/mod.rs
+//
+// Binary frame envelope. Every field is big-endian:
+//
+//   offset  size  field
+//        0     4  magic `W2WS`
+//        4     1  version (1)
+//        5     1  flags
+//        6     2  header length, including the stream id
+//        8     1  content type
+//        9     1  stream id length
+//       10     2  width  (0 = unknown)
+//       12     2  height (0 = unknown)
+//       14     8  sequence number
+//       22     8  capture timestamp, microseconds since the Unix epoch
+//       30     n  stream id (UTF-8)
+//   header  ...   payload
+//
+// Decoders skip anything between the stream id and the header length, so a
+// later version 1 header can grow without breaking older viewers.
+use bytes::{BufMut, Bytes, BytesMut};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MAGIC: [u8; 4] = *b"W2WS";
+pub const VERSION: u8 = 1;
+// ストリーム ID を除いた固定部分の長さ
+pub const FIXED_HEADER_LEN: usize = 30;
+pub const MAX_STREAM_ID_LEN: usize = u8::MAX as usize;
+
+// Bits of the flags byte
+pub const FLAG_KEYFRAME: u8 = 0x01;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ContentType {
+    #[default]
+    Unknown,
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl ContentType {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            1 => ContentType::Jpeg,
+            2 => ContentType::Png,
+            3 => ContentType::Webp,
+            _ => ContentType::Unknown,
+        }
+    }
+
+    pub fn as_u8(self) -> u8 {
+        match self {
+            ContentType::Unknown => 0,
+            ContentType::Jpeg => 1,
+            ContentType::Png => 2,
+            ContentType::Webp => 3,
+        }
+    }
+
+    // 生のフレームの先頭バイトから推測する
+    pub fn sniff(payload: &[u8]) -> Self {
+        if payload.starts_with(&[0xFF, 0xD8]) {
+            ContentType::Jpeg
+        } else if payload.starts_with(b"\x89PNG\r\n\x1a\n") {
+            ContentType::Png
+        } else if payload.len() >= 12 && &payload[0..4] == b"RIFF" && &payload[8..12] == b"WEBP" {
+            ContentType::Webp
+        } else {
+            ContentType::Unknown
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub flags: u8,
+    pub stream_id: String,
+    pub seq: u64,
+    pub timestamp_us: u64,
+    pub content_type: ContentType,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl FrameHeader {
+    // Metadata for a raw frame from a sender that does not use envelopes
+    pub fn for_raw(payload: &[u8]) -> Self {
+        Self {
+            content_type: ContentType::sniff(payload),
+            timestamp_us: now_micros(),
+            ..Self::default()
+        }
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        FIXED_HEADER_LEN + self.stream_id.len()
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    TooShort,
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadHeaderLength(u16),
+    BadStreamId,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooShort => write!(f, "envelope is shorter than its header"),
+            DecodeError::BadMagic => write!(f, "missing W2WS magic"),
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported envelope version {}", v),
+            DecodeError::BadHeaderLength(len) => write!(f, "invalid header length {}", len),
+            DecodeError::BadStreamId => write!(f, "stream id is not valid UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+// Why a payload does not look like a complete JPEG, PNG or WebP image
+#[derive(Debug, PartialEq, Eq)]
+pub enum InvalidImage {
+    UnknownFormat,
+    // JPEG without EOI, or a WebP shorter than its RIFF size
+    Truncated(ContentType),
+    // The envelope declared a different content type than the payload has
+    Mismatch { declared: ContentType, actual: ContentType },
+}
+
+impl fmt::Display for InvalidImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidImage::UnknownFormat => write!(f, "payload is not a JPEG, PNG or WebP image"),
+            InvalidImage::Truncated(content_type) => write!(f, "truncated {:?} image", content_type),
+            InvalidImage::Mismatch { declared, actual } => {
+                write!(f, "envelope declares {:?} but payload is {:?}", declared, actual)
+            }
+        }
+    }
+}
+
+impl std::error::Error for InvalidImage {}
+
+// Cheap structural check of an incoming frame: signatures plus the JPEG EOI
+// marker and the WebP RIFF size. `declared` is the envelope's content type
+// (`Unknown` for raw frames).
+pub fn validate_image(payload: &[u8], declared: ContentType) -> Result<ContentType, InvalidImage> {
+    let actual = ContentType::sniff(payload);
+    match actual {
+        ContentType::Unknown => return Err(InvalidImage::UnknownFormat),
+        // Camera::capture_frame は SOI で始まり EOI で終わる
+        ContentType::Jpeg if payload.len() < 4 || !payload.ends_with(&[0xFF, 0xD9]) => {
+            return Err(InvalidImage::Truncated(actual));
+        }
+        ContentType::Webp => {
+            let riff_len = u32::from_le_bytes([payload[4], payload[5], payload[6], payload[7]]) as usize;
+            if payload.len() < riff_len.saturating_add(8) {
+                return Err(InvalidImage::Truncated(actual));
+            }
+        }
+        _ => {}
+    }
+    if declared != ContentType::Unknown && declared != actual {
+        return Err(InvalidImage::Mismatch { declared, actual });
+    }
+    Ok(actual)
+}
+
+// What a viewer receives: the bare payload (the original format) or the
+// payload behind an envelope header
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WireFormat {
+    #[default]
+    Raw,
+    Envelope,
+}
+
+impl FromStr for WireFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "raw" => Ok(WireFormat::Raw),
+            "envelope" => Ok(WireFormat::Envelope),
+            _ => anyhow::bail!("Unknown wire format `{}` (expected raw or envelope)", s),
+        }
+    }
+}
+
+pub fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_micros() as u64)
+}
+
+// JPEG/PNG/WebP never start with the magic, so raw frames are told apart
+// by their first four bytes
+pub fn is_envelope(buf: &[u8]) -> bool {
+    buf.starts_with(&MAGIC)
+}
+
+pub fn encode(header: &FrameHeader, payload: &[u8]) -> Bytes {
+    // 長すぎるストリーム ID は切り詰める（レジストリは 64 文字までしか許さない）
+    let mut id_len = header.stream_id.len().min(MAX_STREAM_ID_LEN);
+    while !header.stream_id.is_char_boundary(id_len) {
+        id_len -= 1;
+    }
+    let header_len = FIXED_HEADER_LEN + id_len;
+
+    let mut buf = BytesMut::with_capacity(header_len + payload.len());
+    buf.put_slice(&MAGIC);
+    buf.put_u8(VERSION);
+    buf.put_u8(header.flags);
+    buf.put_u16(header_len as u16);
+    buf.put_u8(header.content_type.as_u8());
+    buf.put_u8(id_len as u8);
+    buf.put_u16(header.width);
+    buf.put_u16(header.height);
+    buf.put_u64(header.seq);
+    buf.put_u64(header.timestamp_us);
+    buf.put_slice(&header.stream_id.as_bytes()[..id_len]);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+// The returned payload is a slice of `buf`, not a copy
+pub fn decode(buf: &Bytes) -> Result<(FrameHeader, Bytes), DecodeError> {
+    if !is_envelope(buf) {
+        return Err(DecodeError::BadMagic);
+    }
+    if buf.len() < FIXED_HEADER_LEN {
+        return Err(DecodeError::TooShort);
+    }
+    if buf[4] != VERSION {
+        return Err(DecodeError::UnsupportedVersion(buf[4]));
+    }
+    let header_len = u16::from_be_bytes([buf[6], buf[7]]);
+    let id_len = buf[9] as usize;
+    if (header_len as usize) < FIXED_HEADER_LEN + id_len {
+        return Err(DecodeError::BadHeaderLength(header_len));
+    }
+    if buf.len() < header_len as usize {
+        return Err(DecodeError::TooShort);
+    }
+
+    let read_u64 = |at: usize| u64::from_be_bytes(buf[at..at + 8].try_into().unwrap());
+    let stream_id = std::str::from_utf8(&buf[FIXED_HEADER_LEN..FIXED_HEADER_LEN + id_len])
+        .map_err(|_| DecodeError::BadStreamId)?;
+    let header = FrameHeader {
+        flags: buf[5],
+        stream_id: stream_id.to_string(),
+        seq: read_u64(14),
+        timestamp_us: read_u64(22),
+        content_type: ContentType::from_u8(buf[8]),
+        width: u16::from_be_bytes([buf[10], buf[11]]),
+        height: u16::from_be_bytes([buf[12], buf[13]]),
+    };
+    Ok((header, buf.slice(header_len as usize..)))
+}
@@ -0,0 +1,171 @@
+// src/server/api.rs
+use super::auth::{self, AuthDecision, Authenticator, Scope};
+use super::clients::{ClientRegistry, Kick};
+use super::http::{Request, Response};
+use super::registry::{is_valid_stream_id, StreamRegistry};
+use super::{CLOSE_KICKED, CLOSE_STREAM_CLOSED};
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ApiRoute {
+    // GET /api/streams
+    Streams,
+    // GET /api/clients
+    Clients,
+    // POST /api/clients/{id}/kick
+    KickClient(u64),
+    // POST /api/streams/{stream_id}/close
+    CloseStream(String),
+}
+
+impl ApiRoute {
+    pub fn parse(path: &str) -> Option<Self> {
+        let segments: Vec<&str> = path.strip_prefix("/api/")?.split('/').collect();
+        match segments.as_slice() {
+            ["streams"] => Some(ApiRoute::Streams),
+            ["clients"] => Some(ApiRoute::Clients),
+            ["clients", id, "kick"] => id.parse().ok().map(ApiRoute::KickClient),
+            ["streams", id, "close"] if is_valid_stream_id(id) => Some(ApiRoute::CloseStream(id.to_string())),
+            _ => None,
+        }
+    }
+
+    fn is_action(&self) -> bool {
+        matches!(self, ApiRoute::KickClient(_) | ApiRoute::CloseStream(_))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct StreamInfo {
+    pub id: String,
+    pub publishers: usize,
+    pub viewers: usize,
+    pub frames_in: u64,
+    pub bytes_in: u64,
+    pub frames_out: u64,
+    pub bytes_out: u64,
+    pub dropped_frames: u64,
+    pub invalid_frames: u64,
+    pub oversized_frames: u64,
+}
+
+pub fn list_streams(streams: &StreamRegistry) -> Vec<StreamInfo> {
+    streams
+        .stream_ids()
+        .into_iter()
+        .filter_map(|id| {
+            let metrics = streams.stream_metrics(&id)?;
+            Some(StreamInfo {
+                publishers: streams.publisher_count(&id),
+                viewers: streams.viewer_count(&id),
+                frames_in: metrics.frames_in.load(Ordering::Relaxed),
+                bytes_in: metrics.bytes_in.load(Ordering::Relaxed),
+                frames_out: metrics.frames_out.load(Ordering::Relaxed),
+                bytes_out: metrics.bytes_out.load(Ordering::Relaxed),
+                dropped_frames: metrics.dropped_frames.load(Ordering::Relaxed),
+                invalid_frames: metrics.invalid_frames.load(Ordering::Relaxed),
+                oversized_frames: metrics.oversized_frames.load(Ordering::Relaxed),
+                id,
+            })
+        })
+        .collect()
+}
+
+#[derive(Serialize)]
+struct ApiError<'a> {
+    error: &'a str,
+}
+
+fn json(status: u16, body: &impl Serialize) -> Response {
+    match serde_json::to_vec(body) {
+        Ok(body) => Response::new(status)
+            .header("Cache-Control", "no-store")
+            .body("application/json", body),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to encode API response");
+            Response::new(500)
+        }
+    }
+}
+
+fn error(status: u16, message: &str) -> Response {
+    json(status, &ApiError { error: message })
+}
+
+// Listing is open only while no tokens of any scope are configured; after
+// that, and for actions always, an admin token is required.
+pub fn serve(
+    request: &Request,
+    streams: &StreamRegistry,
+    clients: &ClientRegistry,
+    auth: &Authenticator,
+) -> Response {
+    let Some(route) = ApiRoute::parse(&request.path) else {
+        return error(404, "not found");
+    };
+
+    let allowed_methods = if route.is_action() { "POST" } else { "GET, HEAD" };
+    if !allowed_methods.split(", ").any(|m| m == request.method) {
+        return Response::new(405).header("Allow", allowed_methods);
+    }
+
+    if route.is_action() && !auth.is_enabled(Scope::Admin) {
+        return error(403, "admin actions are disabled (no admin token configured)");
+    }
+    // 配信・視聴を保護しているなら、クライアント一覧も管理トークンなしでは見せない
+    if auth.has_tokens() && !auth.is_enabled(Scope::Admin) {
+        return error(403, "admin API is disabled (tokens are configured but no admin token)");
+    }
+    match auth.check(Scope::Admin, "", auth::request_token(request).as_deref()) {
+        AuthDecision::Allow => {}
+        AuthDecision::Unauthorized => {
+            return error(401, "admin token required").header("WWW-Authenticate", "Bearer realm=\"web2ws\"");
+        }
+        AuthDecision::Forbidden => return error(403, "token is not an admin token"),
+    }
+
+    match route {
+        ApiRoute::Streams => json(200, &list_streams(streams)),
+        ApiRoute::Clients => json(200, &clients.list()),
+        ApiRoute::KickClient(id) => {
+            let kick = Kick {
+                code: CLOSE_KICKED,
+                reason: "kicked by admin".to_string(),
+            };
+            if clients.kick(id, kick) {
+                tracing::info!(client = id, "Admin kicked client");
+                Response::new(204)
+            } else {
+                error(404, "no such client")
+            }
+        }
+        ApiRoute::CloseStream(stream_id) => {
+            let kick = Kick {
+                code: CLOSE_STREAM_CLOSED,
+                reason: "stream closed by admin".to_string(),
+            };
+            let existed = streams.contains(&stream_id);
+            let kicked = clients.kick_stream(&stream_id, kick);
+            // サーバー側キャプチャなどプロセス内の配信者も止める
+            let evicted = streams.evict_publishers(&stream_id);
+            if !existed && kicked == 0 {
+                return error(404, "no such stream");
+            }
+            tracing::info!(stream = %stream_id, clients = kicked, publishers = evicted, "Admin closed stream");
+
+            #[derive(Serialize)]
+            struct Closed<'a> {
+                stream: &'a str,
+                clients: usize,
+            }
+            json(
+                200,
+                &Closed {
+                    stream: &stream_id,
+                    clients: kicked,
+                },
+            )
+        }
+    }
+}
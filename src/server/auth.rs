@@ -0,0 +1,166 @@
+// src/server/auth.rs
+use super::http::Request;
+use anyhow::{Context, Result};
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    Publish,
+    View,
+    // `/api` の管理操作。トークンがなければ管理操作は無効
+    Admin,
+}
+
+impl Scope {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "publish" => Some(Scope::Publish),
+            "view" => Some(Scope::View),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthDecision {
+    Allow,
+    // トークンがない、または既知のトークンではない
+    Unauthorized,
+    // トークンは有効だが、このロールやストリームには使えない
+    Forbidden,
+}
+
+struct TokenGrant {
+    scope: Scope,
+    token: String,
+    // None ならすべてのストリーム
+    streams: Option<Vec<String>>,
+}
+
+// A scope with no tokens configured stays open, so a server without any
+// credentials behaves as before.
+#[derive(Default)]
+pub struct Authenticator {
+    grants: Vec<TokenGrant>,
+}
+
+impl Authenticator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_token(&mut self, scope: Scope, token: &str, streams: Option<Vec<String>>) -> Result<()> {
+        if token.is_empty() {
+            anyhow::bail!("Token must not be empty");
+        }
+        // 管理操作はストリームを横断するので、ストリームを限定した管理トークンは使えない
+        if scope == Scope::Admin && streams.is_some() {
+            anyhow::bail!("Admin tokens apply to all streams and cannot be limited to some");
+        }
+        self.grants.push(TokenGrant {
+            scope,
+            token: token.to_string(),
+            streams,
+        });
+        Ok(())
+    }
+
+    // CLI form: `TOKEN` or `TOKEN@stream1,stream2`
+    pub fn add_token_spec(&mut self, scope: Scope, spec: &str) -> Result<()> {
+        match spec.split_once('@') {
+            Some((token, streams)) => self.add_token(scope, token, parse_streams(streams.split(','))),
+            None => self.add_token(scope, spec, None),
+        }
+    }
+
+    // One grant per line: `<publish|view|admin> <token> [stream ...]`. Blank lines
+    // and lines starting with `#` are ignored; `*` or no stream means all.
+    pub fn parse_tokens(&mut self, text: &str) -> Result<()> {
+        for (lineno, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            let (Some(scope), Some(token)) = (fields.next(), fields.next()) else {
+                anyhow::bail!("line {}: expected `<publish|view|admin> <token> [stream ...]`", lineno + 1);
+            };
+            let scope = Scope::parse(scope)
+                .ok_or_else(|| anyhow::anyhow!("line {}: unknown role `{}`", lineno + 1, scope))?;
+            self.add_token(scope, token, parse_streams(fields))
+                .map_err(|e| anyhow::anyhow!("line {}: {}", lineno + 1, e))?;
+        }
+        Ok(())
+    }
+
+    pub fn load_file(&mut self, path: &Path) -> Result<()> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read tokens file {}", path.display()))?;
+        self.parse_tokens(&text)
+            .with_context(|| format!("Invalid tokens file {}", path.display()))
+    }
+
+    pub fn is_enabled(&self, scope: Scope) -> bool {
+        self.grants.iter().any(|g| g.scope == scope)
+    }
+
+    // Whether any token of any scope is configured
+    pub fn has_tokens(&self) -> bool {
+        !self.grants.is_empty()
+    }
+
+    pub fn check(&self, scope: Scope, stream_id: &str, token: Option<&str>) -> AuthDecision {
+        if !self.is_enabled(scope) {
+            return AuthDecision::Allow;
+        }
+        let Some(token) = token else {
+            return AuthDecision::Unauthorized;
+        };
+        let mut known = false;
+        for grant in self.grants.iter().filter(|g| constant_time_eq(&g.token, token)) {
+            known = true;
+            let stream_allowed = grant
+                .streams
+                .as_ref()
+                .is_none_or(|streams| streams.iter().any(|s| s == stream_id));
+            if grant.scope == scope && stream_allowed {
+                return AuthDecision::Allow;
+            }
+        }
+        if known {
+            AuthDecision::Forbidden
+        } else {
+            AuthDecision::Unauthorized
+        }
+    }
+}
+
+fn parse_streams<'a>(streams: impl Iterator<Item = &'a str>) -> Option<Vec<String>> {
+    let streams: Vec<String> = streams
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect();
+    (!streams.is_empty() && !streams.iter().any(|s| s == "*")).then_some(streams)
+}
+
+// `Authorization: Bearer <token>`、なければ `?token=`
+// (ブラウザの WebSocket API はヘッダーを付けられない)
+pub fn request_token(request: &Request) -> Option<String> {
+    let bearer = request.header("Authorization").and_then(|value| {
+        let (scheme, token) = value.split_once(' ')?;
+        scheme
+            .eq_ignore_ascii_case("bearer")
+            .then(|| token.trim().to_string())
+    });
+    bearer.or_else(|| request.query_param("token"))
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
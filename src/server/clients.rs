@@ -0,0 +1,218 @@
+// src/server/clients.rs
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+
+// この時間フレームが来なければ FPS は 0 とみなす
+const FPS_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Publisher,
+    Viewer,
+}
+
+// Close code and reason an admin action asked the client to be closed with
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Kick {
+    pub code: u16,
+    pub reason: String,
+}
+
+#[derive(Default)]
+struct KickSignal {
+    kick: OnceLock<Kick>,
+    notify: Notify,
+}
+
+struct FpsMeter {
+    window_start: Instant,
+    frames: u64,
+    fps: f64,
+}
+
+struct ClientStats {
+    frames: AtomicU64,
+    bytes: AtomicU64,
+    fps: Mutex<FpsMeter>,
+}
+
+impl ClientStats {
+    fn new() -> Self {
+        Self {
+            frames: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            fps: Mutex::new(FpsMeter {
+                window_start: Instant::now(),
+                frames: 0,
+                fps: 0.0,
+            }),
+        }
+    }
+
+    fn fps(&self) -> f64 {
+        let meter = self.fps.lock().unwrap();
+        // 直前の1秒間を過ぎてもフレームがなければ止まっている
+        if meter.window_start.elapsed() >= FPS_WINDOW * 2 {
+            0.0
+        } else {
+            meter.fps
+        }
+    }
+}
+
+struct ClientEntry {
+    role: Role,
+    peer: SocketAddr,
+    stream_id: String,
+    connected_at: SystemTime,
+    stats: Arc<ClientStats>,
+    signal: Arc<KickSignal>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub role: Role,
+    pub peer: String,
+    pub stream: String,
+    // Unix time in milliseconds
+    pub connected_at_ms: u64,
+    pub connected_secs: f64,
+    pub frames: u64,
+    pub bytes: u64,
+    pub fps: f64,
+}
+
+// Connected WebSocket clients, keyed by connection id
+#[derive(Default)]
+pub struct ClientRegistry {
+    clients: Mutex<HashMap<u64, ClientEntry>>,
+}
+
+impl ClientRegistry {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub fn register(self: &Arc<Self>, id: u64, role: Role, peer: SocketAddr, stream_id: &str) -> ClientHandle {
+        let stats = Arc::new(ClientStats::new());
+        let signal = Arc::new(KickSignal::default());
+        self.clients.lock().unwrap().insert(
+            id,
+            ClientEntry {
+                role,
+                peer,
+                stream_id: stream_id.to_string(),
+                connected_at: SystemTime::now(),
+                stats: stats.clone(),
+                signal: signal.clone(),
+            },
+        );
+        ClientHandle {
+            registry: self.clone(),
+            id,
+            stats,
+            signal,
+        }
+    }
+
+    pub fn list(&self) -> Vec<ClientInfo> {
+        let clients = self.clients.lock().unwrap();
+        let mut list: Vec<ClientInfo> = clients
+            .iter()
+            .map(|(&id, entry)| ClientInfo {
+                id,
+                role: entry.role,
+                peer: entry.peer.to_string(),
+                stream: entry.stream_id.clone(),
+                connected_at_ms: entry
+                    .connected_at
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis() as u64),
+                connected_secs: entry.connected_at.elapsed().unwrap_or_default().as_secs_f64(),
+                frames: entry.stats.frames.load(Ordering::Relaxed),
+                bytes: entry.stats.bytes.load(Ordering::Relaxed),
+                fps: entry.stats.fps(),
+            })
+            .collect();
+        list.sort_by_key(|c| c.id);
+        list
+    }
+
+    // Asks one client's handler to close its WebSocket. Returns false if
+    // the client is not connected.
+    pub fn kick(&self, id: u64, kick: Kick) -> bool {
+        let clients = self.clients.lock().unwrap();
+        let Some(entry) = clients.get(&id) else {
+            return false;
+        };
+        Self::signal(&entry.signal, kick);
+        true
+    }
+
+    // Kicks every client of a stream and returns how many there were
+    pub fn kick_stream(&self, stream_id: &str, kick: Kick) -> usize {
+        let clients = self.clients.lock().unwrap();
+        let mut kicked = 0;
+        for entry in clients.values().filter(|e| e.stream_id == stream_id) {
+            Self::signal(&entry.signal, kick.clone());
+            kicked += 1;
+        }
+        kicked
+    }
+
+    fn signal(signal: &KickSignal, kick: Kick) {
+        // 先に届いた指示を優先する
+        let _ = signal.kick.set(kick);
+        signal.notify.notify_one();
+    }
+}
+
+// Per-connection handle; the client is unlisted when it is dropped
+pub struct ClientHandle {
+    registry: Arc<ClientRegistry>,
+    id: u64,
+    stats: Arc<ClientStats>,
+    signal: Arc<KickSignal>,
+}
+
+impl ClientHandle {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn record_frame(&self, len: usize) {
+        self.stats.frames.fetch_add(1, Ordering::Relaxed);
+        self.stats.bytes.fetch_add(len as u64, Ordering::Relaxed);
+        let mut meter = self.stats.fps.lock().unwrap();
+        meter.frames += 1;
+        let elapsed = meter.window_start.elapsed();
+        if elapsed >= FPS_WINDOW {
+            meter.fps = meter.frames as f64 / elapsed.as_secs_f64();
+            meter.frames = 0;
+            meter.window_start = Instant::now();
+        }
+    }
+
+    // Resolves once an admin has kicked this client or closed its stream
+    pub async fn kicked(&self) -> Kick {
+        loop {
+            if let Some(kick) = self.signal.kick.get() {
+                return kick.clone();
+            }
+            self.signal.notify.notified().await;
+        }
+    }
+}
+
+impl Drop for ClientHandle {
+    fn drop(&mut self) {
+        self.registry.clients.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,131 @@
+// src/server/config.rs
+use super::registry::{DeliveryMode, PublisherPolicy};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Deserializer};
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+// What happens to incoming frames that are not a complete JPEG, PNG or WebP
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FrameValidation {
+    // 検査せずにそのまま配信する（従来の動作）
+    #[default]
+    Off,
+    // 不正なフレームだけ捨てて接続は続ける
+    Drop,
+    // 不正なフレームを送った配信者を切断する
+    Reject,
+}
+
+impl FromStr for FrameValidation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "off" => Ok(FrameValidation::Off),
+            "drop" => Ok(FrameValidation::Drop),
+            "reject" => Ok(FrameValidation::Reject),
+            _ => anyhow::bail!("Unknown frame validation `{}` (expected off, drop or reject)", s),
+        }
+    }
+}
+
+// Tunables for `Server::with_config`. Limits set to `None` are unlimited.
+// The same settings can be loaded from a TOML file whose keys are the field
+// names, with durations given in milliseconds (`idle_timeout_ms = 5000`).
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // ストリームごとのブロードキャストチャネルの容量（フレーム数）
+    pub channel_capacity: usize,
+    pub max_viewers_per_stream: Option<usize>,
+    // Publishers across all streams, including in-process ones
+    pub max_publishers: Option<usize>,
+    // Open TCP connections, HTTP and WebSocket alike
+    pub max_connections: Option<usize>,
+    // Largest WebSocket message a client may send, in bytes
+    pub max_frame_size: usize,
+    // Content check for frames from WebSocket publishers
+    #[serde(deserialize_with = "parsed")]
+    pub frame_validation: FrameValidation,
+    // TLS handshake and the first request on a connection
+    #[serde(rename = "handshake_timeout_ms", deserialize_with = "millis")]
+    pub handshake_timeout: Duration,
+    // アイドル状態の keep-alive 接続を閉じるまでの時間
+    #[serde(rename = "idle_timeout_ms", deserialize_with = "millis")]
+    pub idle_timeout: Duration,
+    // WebSocket クライアントから何も届かない（Pong も返らない）まま待つ時間
+    #[serde(rename = "ws_idle_timeout_ms", deserialize_with = "millis")]
+    pub ws_idle_timeout: Duration,
+    #[serde(rename = "drain_timeout_ms", deserialize_with = "millis")]
+    pub drain_timeout: Duration,
+    #[serde(rename = "keyframe_max_age_ms", deserialize_with = "millis")]
+    pub keyframe_max_age: Duration,
+    #[serde(deserialize_with = "parsed")]
+    pub publisher_policy: PublisherPolicy,
+    #[serde(rename = "delivery", deserialize_with = "parsed")]
+    pub delivery_mode: DeliveryMode,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            channel_capacity: 100,
+            max_viewers_per_stream: None,
+            max_publishers: None,
+            max_connections: None,
+            max_frame_size: 16 << 20,
+            frame_validation: FrameValidation::default(),
+            handshake_timeout: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(5),
+            ws_idle_timeout: Duration::from_secs(10),
+            drain_timeout: Duration::from_secs(5),
+            keyframe_max_age: Duration::from_secs(2),
+            publisher_policy: PublisherPolicy::default(),
+            delivery_mode: DeliveryMode::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let config: Self = toml::from_str(text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::from_toml(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.channel_capacity == 0 {
+            anyhow::bail!("channel_capacity must be at least 1");
+        }
+        if self.max_frame_size == 0 {
+            anyhow::bail!("max_frame_size must be at least 1");
+        }
+        if self.ws_idle_timeout.is_zero() {
+            anyhow::bail!("ws_idle_timeout_ms must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+fn millis<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    Ok(Duration::from_millis(u64::deserialize(deserializer)?))
+}
+
+// CLI と同じ表記 (`reject`、`latest` など) を受け付ける
+fn parsed<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+}
@@ -0,0 +1,388 @@
+// src/server/http.rs
+use std::fmt;
+use std::io::SeekFrom;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+pub const MAX_HEADER_BYTES: usize = 8 * 1024;
+pub const MAX_URI_LEN: usize = 2048;
+pub const MAX_HEADERS: usize = 64;
+pub const MAX_BODY_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    // HTTP/1.x のマイナーバージョン
+    pub version: u8,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    // Comma-separated headers such as `Connection: keep-alive, Upgrade`
+    pub fn header_has_token(&self, name: &str, token: &str) -> bool {
+        self.headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+            .flat_map(|(_, v)| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn is_head(&self) -> bool {
+        self.method == "HEAD"
+    }
+
+    // HTTP/1.1 は既定で keep-alive、HTTP/1.0 は明示された場合のみ
+    pub fn wants_keep_alive(&self) -> bool {
+        if self.version >= 1 {
+            !self.header_has_token("Connection", "close")
+        } else {
+            self.header_has_token("Connection", "keep-alive")
+        }
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query.as_deref()?.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode_query_component(key)? == name)
+                .then(|| decode_query_component(value))
+                .flatten()
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum HttpError {
+    BadRequest(&'static str),
+    UriTooLong,
+    HeadersTooLarge,
+    PayloadTooLarge,
+    NotImplemented(&'static str),
+    Io(std::io::Error),
+}
+
+impl HttpError {
+    // I/O エラーはレスポンスを返さずに切断する
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            HttpError::BadRequest(_) => Some(400),
+            HttpError::UriTooLong => Some(414),
+            HttpError::HeadersTooLarge => Some(431),
+            HttpError::PayloadTooLarge => Some(413),
+            HttpError::NotImplemented(_) => Some(501),
+            HttpError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpError::BadRequest(reason) => write!(f, "bad request: {}", reason),
+            HttpError::UriTooLong => write!(f, "request URI too long"),
+            HttpError::HeadersTooLarge => write!(f, "request header block too large"),
+            HttpError::PayloadTooLarge => write!(f, "request body too large"),
+            HttpError::NotImplemented(what) => write!(f, "not implemented: {}", what),
+            HttpError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl From<std::io::Error> for HttpError {
+    fn from(e: std::io::Error) -> Self {
+        HttpError::Io(e)
+    }
+}
+
+// Parses one request head from `buf`. Returns the request (without body),
+// the header block length and the declared body length, or `None` if more
+// bytes are needed.
+pub fn parse_request(buf: &[u8]) -> Result<Option<(Request, usize, usize)>, HttpError> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut parsed = httparse::Request::new(&mut headers);
+    let header_len = match parsed.parse(buf) {
+        Ok(httparse::Status::Complete(len)) => len,
+        Ok(httparse::Status::Partial) => {
+            // リクエストラインがまだ終わっていない
+            if !buf.windows(2).any(|w| w == b"\r\n") && buf.len() > MAX_URI_LEN + 32 {
+                return Err(HttpError::UriTooLong);
+            }
+            if buf.len() >= MAX_HEADER_BYTES {
+                return Err(HttpError::HeadersTooLarge);
+            }
+            return Ok(None);
+        }
+        Err(httparse::Error::TooManyHeaders) => return Err(HttpError::HeadersTooLarge),
+        Err(_) => return Err(HttpError::BadRequest("malformed request")),
+    };
+    if header_len > MAX_HEADER_BYTES {
+        return Err(HttpError::HeadersTooLarge);
+    }
+
+    let method = parsed.method.ok_or(HttpError::BadRequest("missing method"))?;
+    let target = parsed.path.ok_or(HttpError::BadRequest("missing request target"))?;
+    let version = parsed.version.ok_or(HttpError::BadRequest("missing version"))?;
+    if target.len() > MAX_URI_LEN {
+        return Err(HttpError::UriTooLong);
+    }
+    if !target.starts_with('/') {
+        return Err(HttpError::BadRequest("request target must be origin-form"));
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_string())),
+        None => (target, None),
+    };
+
+    let mut header_list = Vec::with_capacity(parsed.headers.len());
+    for h in parsed.headers.iter() {
+        let value = std::str::from_utf8(h.value)
+            .map_err(|_| HttpError::BadRequest("header value is not valid UTF-8"))?;
+        header_list.push((h.name.to_string(), value.trim().to_string()));
+    }
+
+    let request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query,
+        version,
+        headers: header_list,
+        body: Vec::new(),
+    };
+
+    if version == 1 && request.header("Host").is_none() {
+        return Err(HttpError::BadRequest("missing Host header"));
+    }
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(HttpError::NotImplemented("Transfer-Encoding"));
+    }
+    let body_len = match request.header("Content-Length") {
+        Some(v) => v
+            .parse::<usize>()
+            .map_err(|_| HttpError::BadRequest("invalid Content-Length"))?,
+        None => 0,
+    };
+    if body_len > MAX_BODY_BYTES {
+        return Err(HttpError::PayloadTooLarge);
+    }
+
+    Ok(Some((request, header_len, body_len)))
+}
+
+// Reads requests incrementally from a stream. Bytes past the end of a
+// request stay buffered for the next call (pipelining) or for the
+// WebSocket layer after an upgrade.
+pub struct RequestReader<S> {
+    stream: S,
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead + Unpin> RequestReader<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            buf: Vec::with_capacity(1024),
+        }
+    }
+
+    // `Ok(None)` means the peer closed the connection between requests
+    pub async fn read_request(&mut self) -> Result<Option<Request>, HttpError> {
+        loop {
+            if !self.buf.is_empty() {
+                if let Some((mut request, header_len, body_len)) = parse_request(&self.buf)? {
+                    while self.buf.len() < header_len + body_len {
+                        if self.fill().await? == 0 {
+                            return Err(HttpError::BadRequest("incomplete request body"));
+                        }
+                    }
+                    request.body = self.buf[header_len..header_len + body_len].to_vec();
+                    self.buf.drain(..header_len + body_len);
+                    return Ok(Some(request));
+                }
+            }
+            if self.fill().await? == 0 {
+                return if self.buf.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(HttpError::BadRequest("incomplete request"))
+                };
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    pub fn into_parts(self) -> (S, Vec<u8>) {
+        (self.stream, self.buf)
+    }
+
+    async fn fill(&mut self) -> std::io::Result<usize> {
+        let mut chunk = [0u8; 4096];
+        let n = self.stream.read(&mut chunk).await?;
+        self.buf.extend_from_slice(&chunk[..n]);
+        Ok(n)
+    }
+}
+
+pub fn status_text(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        204 => "No Content",
+        206 => "Partial Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        416 => "Range Not Satisfiable",
+        426 => "Upgrade Required",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // 設定されていれば body の代わりにファイルの一部をそのまま送る
+    pub file: Option<FileBody>,
+}
+
+// `len` bytes of `file` starting at `start`, streamed when the response is written
+pub struct FileBody {
+    pub file: tokio::fs::File,
+    pub start: u64,
+    pub len: u64,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            file: None,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.push((name.to_string(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, content_type: &str, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self.header("Content-Type", content_type)
+    }
+
+    pub fn file_body(mut self, content_type: &str, file: tokio::fs::File, start: u64, len: u64) -> Self {
+        self.file = Some(FileBody { file, start, len });
+        self.header("Content-Type", content_type)
+    }
+
+    pub fn content_length(&self) -> u64 {
+        match &self.file {
+            Some(file) => file.len,
+            None => self.body.len() as u64,
+        }
+    }
+
+    // HEAD への応答では Content-Length はそのままでボディだけ省く
+    pub fn head_bytes(&self) -> Vec<u8> {
+        let mut out = format!("HTTP/1.1 {} {}\r\n", self.status, status_text(self.status));
+        for (name, value) in &self.headers {
+            out.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !matches!(self.status, 101 | 204 | 304) {
+            out.push_str(&format!("Content-Length: {}\r\n", self.content_length()));
+        }
+        out.push_str("\r\n");
+        out.into_bytes()
+    }
+
+    // ファイルのボディは含まない。ファイルを返しうる応答は write_to で書く
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.head_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes
+    }
+
+    // Writes the response, streaming a file body instead of loading it; with
+    // `head_only` (a HEAD request) the body is not read at all
+    pub async fn write_to<W: AsyncWrite + Unpin>(mut self, out: &mut W, head_only: bool) -> std::io::Result<()> {
+        if head_only {
+            return out.write_all(&self.head_bytes()).await;
+        }
+        let head = self.head_bytes();
+        let Some(FileBody { mut file, start, len }) = self.file.take() else {
+            return out.write_all(&self.to_bytes()).await;
+        };
+        out.write_all(&head).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        let copied = tokio::io::copy(&mut file.take(len), out).await?;
+        // 途中でファイルが縮んだら Content-Length を満たせないので接続ごと失敗させる
+        if copied < len {
+            let message = "File shrank while it was being sent";
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, message));
+        }
+        Ok(())
+    }
+}
+
+// Strong validator derived from the content (FNV-1a)
+pub fn etag_for(content: &[u8]) -> String {
+    let hash = content.iter().fold(0xcbf29ce484222325u64, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("\"{:016x}\"", hash)
+}
+
+// `If-None-Match: "a", W/"b"` or `*`
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').any(|candidate| {
+        let candidate = candidate.trim();
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
+pub fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+// クエリ文字列では `+` は空白
+fn decode_query_component(s: &str) -> Option<String> {
+    percent_decode(&s.replace('+', " "))
+}
@@ -0,0 +1,108 @@
+// src/server/latency.rs
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// 直近この数のサンプルでパーセンタイルを計算する
+pub const LATENCY_WINDOW: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    // Sender capture time to viewer display time
+    GlassToGlass,
+    // Sender capture time to server ingress
+    Ingress,
+    // WebSocket ping/pong round trip to a viewer
+    Rtt,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Percentiles {
+    pub count: usize,
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+}
+
+impl Percentiles {
+    // Nearest-rank percentiles of samples in microseconds
+    pub fn from_micros(samples: impl IntoIterator<Item = u64>) -> Option<Self> {
+        let mut sorted: Vec<u64> = samples.into_iter().collect();
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort_unstable();
+        let rank = |p: f64| {
+            let index = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+            sorted[index.clamp(1, sorted.len()) - 1] as f64 / 1000.0
+        };
+        Some(Self {
+            count: sorted.len(),
+            p50_ms: rank(50.0),
+            p95_ms: rank(95.0),
+            p99_ms: rank(99.0),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct LatencySnapshot {
+    pub glass_to_glass: Option<Percentiles>,
+    pub ingress: Option<Percentiles>,
+    pub rtt: Option<Percentiles>,
+}
+
+#[derive(Default)]
+struct Windows {
+    glass_to_glass: VecDeque<u64>,
+    ingress: VecDeque<u64>,
+    rtt: VecDeque<u64>,
+}
+
+impl Windows {
+    fn get_mut(&mut self, metric: Metric) -> &mut VecDeque<u64> {
+        match metric {
+            Metric::GlassToGlass => &mut self.glass_to_glass,
+            Metric::Ingress => &mut self.ingress,
+            Metric::Rtt => &mut self.rtt,
+        }
+    }
+}
+
+// Per-stream latency samples shared by its publishers and viewers
+#[derive(Default)]
+pub struct StreamLatency {
+    windows: Mutex<Windows>,
+}
+
+impl StreamLatency {
+    pub fn record(&self, metric: Metric, latency: Duration) {
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.get_mut(metric);
+        if window.len() == LATENCY_WINDOW {
+            window.pop_front();
+        }
+        window.push_back(latency.as_micros() as u64);
+    }
+
+    pub fn snapshot(&self) -> LatencySnapshot {
+        let windows = self.windows.lock().unwrap();
+        LatencySnapshot {
+            glass_to_glass: Percentiles::from_micros(windows.glass_to_glass.iter().copied()),
+            ingress: Percentiles::from_micros(windows.ingress.iter().copied()),
+            rtt: Percentiles::from_micros(windows.rtt.iter().copied()),
+        }
+    }
+}
+
+impl fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "p50 {:.1}ms p95 {:.1}ms p99 {:.1}ms (n={})",
+            self.p50_ms, self.p95_ms, self.p99_ms, self.count
+        )
+    }
+}
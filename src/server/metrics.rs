@@ -0,0 +1,269 @@
+// src/server/metrics.rs
+use super::http::{Request, Response};
+use super::registry::StreamRegistry;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Upper bounds of the frame size histogram in bytes
+pub const FRAME_SIZE_BUCKETS: [u64; 8] = [
+    4 << 10,
+    16 << 10,
+    32 << 10,
+    64 << 10,
+    128 << 10,
+    256 << 10,
+    512 << 10,
+    1 << 20,
+];
+
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; FRAME_SIZE_BUCKETS.len()],
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, value: u64) {
+        // 累積はレンダリング時に計算するので、該当する最初のバケットだけ数える
+        if let Some(i) = FRAME_SIZE_BUCKETS.iter().position(|&le| value <= le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+// Counters for one stream, shared by its publishers and viewers
+#[derive(Default)]
+pub struct StreamMetrics {
+    pub frames_in: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub frames_out: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub dropped_frames: AtomicU64,
+    // Publisher frames that failed validation or exceeded max_frame_size
+    pub invalid_frames: AtomicU64,
+    pub oversized_frames: AtomicU64,
+    pub frame_size: Histogram,
+}
+
+impl StreamMetrics {
+    pub fn record_in(&self, len: usize) {
+        self.frames_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(len as u64, Ordering::Relaxed);
+        self.frame_size.observe(len as u64);
+    }
+
+    pub fn record_out(&self, len: usize) {
+        self.frames_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped(&self, frames: u64) {
+        self.dropped_frames.fetch_add(frames, Ordering::Relaxed);
+    }
+
+    pub fn record_invalid(&self) {
+        self.invalid_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_oversized(&self) {
+        self.oversized_frames.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Why a WebSocket upgrade did not result in a connected client
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpgradeFailure {
+    MethodNotAllowed,
+    Unauthorized,
+    Forbidden,
+    // Upgrade ヘッダーがない、またはバージョンが 13 ではない
+    NotUpgrade,
+    Handshake,
+    PublisherRejected,
+    // max_publishers / max_viewers_per_stream に達した
+    LimitReached,
+}
+
+impl UpgradeFailure {
+    const ALL: [UpgradeFailure; 7] = [
+        UpgradeFailure::MethodNotAllowed,
+        UpgradeFailure::Unauthorized,
+        UpgradeFailure::Forbidden,
+        UpgradeFailure::NotUpgrade,
+        UpgradeFailure::Handshake,
+        UpgradeFailure::PublisherRejected,
+        UpgradeFailure::LimitReached,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            UpgradeFailure::MethodNotAllowed => "method_not_allowed",
+            UpgradeFailure::Unauthorized => "unauthorized",
+            UpgradeFailure::Forbidden => "forbidden",
+            UpgradeFailure::NotUpgrade => "not_upgrade",
+            UpgradeFailure::Handshake => "handshake",
+            UpgradeFailure::PublisherRejected => "publisher_rejected",
+            UpgradeFailure::LimitReached => "limit_reached",
+        }
+    }
+}
+
+// Server-wide counters. Per-stream counters live in the stream registry and
+// disappear with the stream.
+#[derive(Default)]
+pub struct Metrics {
+    connections: AtomicU64,
+    rejected_connections: AtomicU64,
+    upgrade_failures: [AtomicU64; UpgradeFailure::ALL.len()],
+    capture_errors: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Connections dropped because `max_connections` was reached
+    pub fn record_rejected_connection(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_upgrade_failure(&self, reason: UpgradeFailure) {
+        self.upgrade_failures[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn upgrade_failures(&self, reason: UpgradeFailure) -> u64 {
+        self.upgrade_failures[reason as usize].load(Ordering::Relaxed)
+    }
+
+    // `Camera::capture_frame` failures in the server-side capture loop
+    pub fn record_capture_error(&self) {
+        self.capture_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Prometheus text exposition format 0.0.4
+    pub fn render(&self, streams: &StreamRegistry) -> String {
+        let mut out = String::new();
+        let counter = |out: &mut String, name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value);
+        };
+        counter(&mut out, "web2ws_connections_total", "Accepted TCP connections.", self.connections.load(Ordering::Relaxed));
+        counter(
+            &mut out,
+            "web2ws_connections_rejected_total",
+            "Connections dropped at the max_connections limit.",
+            self.rejected_connections.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut out,
+            "web2ws_capture_errors_total",
+            "Failed server-side camera captures.",
+            self.capture_errors.load(Ordering::Relaxed),
+        );
+
+        out.push_str("# HELP web2ws_upgrade_failures_total WebSocket upgrades that did not connect a client.\n");
+        out.push_str("# TYPE web2ws_upgrade_failures_total counter\n");
+        for reason in UpgradeFailure::ALL {
+            let _ = writeln!(
+                out,
+                "web2ws_upgrade_failures_total{{reason=\"{}\"}} {}",
+                reason.label(),
+                self.upgrade_failures(reason)
+            );
+        }
+
+        // ストリーム ID は [A-Za-z0-9-_.] だけなのでラベル値のエスケープは不要
+        let snapshot: Vec<StreamSnapshot> = streams
+            .stream_ids()
+            .into_iter()
+            .filter_map(|id| {
+                Some(StreamSnapshot {
+                    metrics: streams.stream_metrics(&id)?,
+                    publishers: streams.publisher_count(&id) as u64,
+                    viewers: streams.viewer_count(&id) as u64,
+                    id,
+                })
+            })
+            .collect();
+
+        let families: [PerStreamFamily; 9] = [
+            ("web2ws_publishers", "gauge", "Connected publishers.", |s| s.publishers),
+            ("web2ws_viewers", "gauge", "Connected viewers.", |s| s.viewers),
+            ("web2ws_frames_in_total", "counter", "Frames received from publishers.", |s| {
+                s.metrics.frames_in.load(Ordering::Relaxed)
+            }),
+            ("web2ws_bytes_in_total", "counter", "Frame bytes received from publishers.", |s| {
+                s.metrics.bytes_in.load(Ordering::Relaxed)
+            }),
+            ("web2ws_frames_out_total", "counter", "Frames sent to viewers.", |s| {
+                s.metrics.frames_out.load(Ordering::Relaxed)
+            }),
+            ("web2ws_bytes_out_total", "counter", "Bytes sent to viewers.", |s| {
+                s.metrics.bytes_out.load(Ordering::Relaxed)
+            }),
+            ("web2ws_dropped_frames_total", "counter", "Frames skipped by lagging viewers.", |s| {
+                s.metrics.dropped_frames.load(Ordering::Relaxed)
+            }),
+            ("web2ws_invalid_frames_total", "counter", "Publisher frames that failed content validation.", |s| {
+                s.metrics.invalid_frames.load(Ordering::Relaxed)
+            }),
+            ("web2ws_oversized_frames_total", "counter", "Publisher frames larger than max_frame_size.", |s| {
+                s.metrics.oversized_frames.load(Ordering::Relaxed)
+            }),
+        ];
+        for (name, kind, help, value) in families {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+            for stream in &snapshot {
+                let _ = writeln!(out, "{}{{stream=\"{}\"}} {}", name, stream.id, value(stream));
+            }
+        }
+
+        let name = "web2ws_frame_size_bytes";
+        let _ = writeln!(out, "# HELP {} Size of frames received from publishers.\n# TYPE {} histogram", name, name);
+        for StreamSnapshot { id, metrics, .. } in &snapshot {
+            let histogram = &metrics.frame_size;
+            let mut cumulative = 0;
+            for (le, bucket) in FRAME_SIZE_BUCKETS.iter().zip(&histogram.buckets) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let _ = writeln!(out, "{}_bucket{{stream=\"{}\",le=\"{}\"}} {}", name, id, le, cumulative);
+            }
+            let count = histogram.count();
+            let _ = writeln!(out, "{}_bucket{{stream=\"{}\",le=\"+Inf\"}} {}", name, id, count);
+            let _ = writeln!(out, "{}_sum{{stream=\"{}\"}} {}", name, id, histogram.sum.load(Ordering::Relaxed));
+            let _ = writeln!(out, "{}_count{{stream=\"{}\"}} {}", name, id, count);
+        }
+        out
+    }
+}
+
+// (名前, 種類, 説明, 値)
+type PerStreamFamily = (&'static str, &'static str, &'static str, fn(&StreamSnapshot) -> u64);
+
+struct StreamSnapshot {
+    id: String,
+    publishers: u64,
+    viewers: u64,
+    metrics: Arc<StreamMetrics>,
+}
+
+// `GET /metrics`
+pub fn serve(request: &Request, metrics: &Metrics, streams: &StreamRegistry) -> Response {
+    if request.method != "GET" && request.method != "HEAD" {
+        return Response::new(405).header("Allow", "GET, HEAD");
+    }
+    Response::new(200)
+        .header("Cache-Control", "no-store")
+        .body("text/plain; version=0.0.4; charset=utf-8", metrics.render(streams))
+}
@@ -1,141 +1,745 @@
 // src/server/mod.rs
 use anyhow::Result;
-use tokio::sync::broadcast;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio_tungstenite::accept_async;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use futures::future::BoxFuture;
 use futures::stream::StreamExt;
 use futures::SinkExt;
+use std::future::IntoFuture;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_rustls::rustls;
+use tokio_rustls::TlsAcceptor;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, info_span, warn, Instrument};
+use crate::protocol::{self, WireFormat};
+use crate::websocket::WebSocketClient;
+
+pub mod api;
+pub mod auth;
+pub mod clients;
+pub mod config;
+pub mod http;
+pub mod latency;
+pub mod metrics;
+mod registry;
+pub mod static_files;
+pub mod status;
+pub mod tls;
+
+use auth::{AuthDecision, Authenticator, Scope};
+use http::{Request, RequestReader, Response};
+use clients::{ClientHandle, ClientRegistry};
+pub use config::{FrameValidation, ServerConfig};
+use latency::Metric;
+use metrics::{Metrics, UpgradeFailure};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::time::Instant;
+pub use registry::{
+    is_valid_stream_id, DeliveryMode, Frame, PublishError, Publisher, PublisherPolicy, ReceivedFrame,
+    StreamRegistry, Viewer, DEFAULT_STREAM,
+};
+
+// Close codes sent to camera clients (4000-4999 are reserved for applications)
+pub const CLOSE_PUBLISHER_REJECTED: u16 = 4001;
+pub const CLOSE_PUBLISHER_REPLACED: u16 = 4002;
+// Sent to any client closed through the admin API
+pub const CLOSE_KICKED: u16 = 4003;
+pub const CLOSE_STREAM_CLOSED: u16 = 4004;
+// Sent to a publisher whose frame failed validation under FrameValidation::Reject
+pub const CLOSE_INVALID_FRAME: u16 = 4005;
+// Sent to a client that went quiet for longer than `ws_idle_timeout`
+pub const CLOSE_IDLE_TIMEOUT: u16 = 4006;
+// Sent to every client when the server shuts down (RFC 6455 "going away")
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+
+// RTT の計測と生存確認に使う Ping の間隔
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+// 表示通知 (ack) を突き合わせるために覚えておく送信済みフレーム数
+const ACK_WINDOW: usize = 256;
 
 pub struct Server {
-    addr: String,
-    broadcast_tx: broadcast::Sender<Vec<u8>>,
+    // new() で bind し、run() が取り出す
+    listener: Option<TcpListener>,
+    local_addr: SocketAddr,
+    streams: Arc<StreamRegistry>,
+    static_dir: Option<PathBuf>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    auth: Arc<Authenticator>,
+    config: ServerConfig,
+    metrics: Arc<Metrics>,
+    clients: Arc<ClientRegistry>,
+    shutdown: CancellationToken,
+}
+
+// 接続ごとのタスクで共有する状態
+struct ServerState {
+    streams: Arc<StreamRegistry>,
+    static_dir: Option<PathBuf>,
+    auth: Arc<Authenticator>,
+    config: ServerConfig,
+    metrics: Arc<Metrics>,
+    clients: Arc<ClientRegistry>,
+    shutdown: CancellationToken,
 }
 
 impl Server {
     pub async fn new(addr: &str) -> Result<Self> {
-        let (broadcast_tx, _) = broadcast::channel(100);
+        Self::with_config(addr, ServerConfig::default()).await
+    }
+
+    // Binds `addr` right away; use port 0 to get an ephemeral port and read
+    // it back with `local_addr()`
+    pub async fn with_config(addr: &str, config: ServerConfig) -> Result<Self> {
+        config.validate()?;
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
         Ok(Self {
-            addr: addr.to_string(),
-            broadcast_tx,
+            listener: Some(listener),
+            local_addr,
+            streams: StreamRegistry::new(config.channel_capacity),
+            static_dir: None,
+            tls: None,
+            auth: Arc::new(Authenticator::new()),
+            config,
+            metrics: Arc::new(Metrics::new()),
+            clients: ClientRegistry::new(),
+            shutdown: CancellationToken::new(),
         })
     }
 
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    // How long `run()` waits for open connections to close after shutdown
+    // has been requested before it returns anyway
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.config.drain_timeout = drain_timeout;
+        self
+    }
+
+    // New viewers get the stream's last frame right away if it is younger
+    // than `max_age`. `Duration::ZERO` disables the cache.
+    pub fn keyframe_max_age(mut self, max_age: Duration) -> Self {
+        self.config.keyframe_max_age = max_age;
+        self
+    }
+
+    // Default for viewers that do not pass `?delivery=`
+    pub fn delivery_mode(mut self, mode: DeliveryMode) -> Self {
+        self.config.delivery_mode = mode;
+        self
+    }
+
+    // Applies to WebSocket publishers and to `publisher()`
+    pub fn publisher_policy(mut self, policy: PublisherPolicy) -> Self {
+        self.config.publisher_policy = policy;
+        self
+    }
+
+    // Publish/view credentials checked before the WebSocket upgrade
+    pub fn auth(mut self, auth: Authenticator) -> Self {
+        self.auth = Arc::new(auth);
+        self
+    }
+
+    // Serves https:// and wss:// on the same listener instead of plain text
+    pub fn tls(mut self, cert_path: &Path, key_path: &Path) -> Result<Self> {
+        self.tls = Some(tls::load_server_config(cert_path, key_path)?);
+        Ok(self)
+    }
+
+    pub fn tls_config(mut self, config: Arc<rustls::ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    // Files in `dir` are served before the embedded pages
+    pub fn static_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.static_dir = Some(dir.into());
+        self
+    }
+
+    #[tracing::instrument(name = "server", skip_all, fields(addr = %self.local_addr, tls = self.tls.is_some()))]
     pub async fn run(&mut self) -> Result<()> {
-        let listener = TcpListener::bind(&self.addr).await?;
-        println!("Server listening on {}", self.addr);
-        
+        if let Some(dir) = &self.static_dir {
+            if !dir.is_dir() {
+                anyhow::bail!("Static directory not found: {}", dir.display());
+            }
+        }
+        let Some(listener) = self.listener.take() else {
+            anyhow::bail!("Server is already running");
+        };
+        let state = Arc::new(ServerState {
+            streams: self.streams.clone(),
+            static_dir: self.static_dir.clone(),
+            auth: self.auth.clone(),
+            config: self.config.clone(),
+            metrics: self.metrics.clone(),
+            clients: self.clients.clone(),
+            shutdown: self.shutdown.clone(),
+        });
+
+        let acceptor = self.tls.clone().map(TlsAcceptor::from);
+
+        let scheme = if acceptor.is_some() { "https" } else { "http" };
+        info!("Server listening on {}://{}", scheme, self.local_addr);
+
+        let connections = TaskTracker::new();
+        let connection_limit = self.config.max_connections.map(|max| Arc::new(Semaphore::new(max)));
+        let mut next_conn_id: u64 = 0;
         loop {
-            let (stream, addr) = listener.accept().await?;
-            println!("New connection from: {}", addr);
+            let (stream, addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = self.shutdown.cancelled() => break,
+            };
+            // 上限に達していれば何も返さずに閉じる
+            let permit = match &connection_limit {
+                Some(limit) => match limit.clone().try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        warn!(peer = %addr, "Connection limit reached, dropping connection");
+                        self.metrics.record_rejected_connection();
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            next_conn_id += 1;
+            // 接続内のログはすべてこのスパン（接続 ID とピアアドレス）に属する
+            let conn_id = next_conn_id;
+            let span = info_span!("connection", conn_id, peer = %addr);
+            span.in_scope(|| debug!("New connection"));
+            self.metrics.record_connection();
             
-            let broadcast_tx = self.broadcast_tx.clone();
+            let state = state.clone();
+            let acceptor = acceptor.clone();
             
-            tokio::spawn(async move {
-                if let Err(e) = handle_connection(stream, broadcast_tx).await {
-                    eprintln!("Error handling connection {}: {}", addr, e);
+            let task = async move {
+                let _permit = permit;
+                let result = match acceptor {
+                    Some(acceptor) => match timeout(state.config.handshake_timeout, acceptor.accept(stream)).await {
+                        Ok(Ok(tls_stream)) => handle_connection(tls_stream, conn_id, addr, state).await,
+                        Ok(Err(e)) => Err(anyhow::anyhow!("TLS handshake failed: {}", e)),
+                        Err(_) => Err(anyhow::anyhow!("TLS handshake timed out")),
+                    },
+                    None => handle_connection(stream, conn_id, addr, state).await,
+                };
+                if let Err(e) = result {
+                    warn!(error = %e, "Error handling connection");
                 }
-            });
+            };
+            connections.spawn(task.instrument(span));
+        }
+
+        // 新しい接続は受け付けず、各接続が Close を送り終えるのを待つ
+        drop(listener);
+        connections.close();
+        info!(connections = connections.len(), "Server shutting down");
+        if timeout(self.config.drain_timeout, connections.wait()).await.is_err() {
+            warn!(remaining = connections.len(), "Drain timed out, abandoning open connections");
+        }
+        Ok(())
+    }
+
+    // Cancelling the token stops `run()`: the listener is closed, every
+    // WebSocket client gets a Close frame with `CLOSE_GOING_AWAY` and open
+    // connections are given `drain_timeout` to finish
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    // Runs the server on its own task
+    pub fn spawn(mut self) -> ServerHandle {
+        ServerHandle {
+            local_addr: self.local_addr,
+            shutdown: self.shutdown.clone(),
+            task: tokio::spawn(async move { self.run().await }),
         }
     }
 
-    pub async fn send_frame(&self, frame: &[u8]) -> Result<()> {
-        let _ = self.broadcast_tx.send(frame.to_vec());
+    pub async fn send_frame(&self, frame: impl Into<bytes::Bytes>) -> Result<()> {
+        self.streams.send(DEFAULT_STREAM, frame);
         Ok(())
     }
 
-    pub fn get_broadcast_sender(&self) -> broadcast::Sender<Vec<u8>> {
-        self.broadcast_tx.clone()
+    // サーバー側のキャプチャなど、プロセス内からストリームへ配信する
+    pub fn publisher(&self, stream_id: &str) -> Result<Publisher> {
+        if !is_valid_stream_id(stream_id) {
+            anyhow::bail!("Invalid stream id: {}", stream_id);
+        }
+        Ok(self.streams.publish(stream_id, self.config.publisher_policy)?)
     }
+
+    pub fn streams(&self) -> Arc<StreamRegistry> {
+        self.streams.clone()
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub fn clients(&self) -> Arc<ClientRegistry> {
+        self.clients.clone()
+    }
+}
+
+// A spawned server. Awaiting it waits for `run()` to return; dropping it
+// leaves the server running.
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown: CancellationToken,
+    task: JoinHandle<Result<()>>,
 }
 
-async fn handle_connection(
-    mut stream: TcpStream,
-    broadcast_tx: broadcast::Sender<Vec<u8>>,
-) -> Result<()> {
-    // Read HTTP request line
-    let mut buf = [0; 4096];
-    let n = stream.read(&mut buf).await?;
-    if n == 0 {
-        return Ok(());
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
     }
-    
-    let request = String::from_utf8_lossy(&buf[..n]);
-    
-    // Parse first line to get path
-    if let Some(first_line_end) = request.find("\r\n") {
-        let first_line = &request[..first_line_end];
-        let mut parts = first_line.split_whitespace();
-        
-        if let (Some(_method), Some(path_raw), Some(_version)) = (parts.next(), parts.next(), parts.next()) {
-            let path = path_raw.split('?').next().unwrap_or(path_raw);
-            println!("Incoming request for path: {}", path);
-            
-            // WebSocket upgrade for /camera and /view
-            if path == "/camera" || path == "/view" {
-                match accept_async(stream).await {
-                    Ok(ws_stream) => {
-                        return if path == "/camera" {
-                            handle_camera_client(ws_stream, broadcast_tx).await
-                        } else {
-                            handle_viewer_client(ws_stream, broadcast_tx).await
-                        };
+
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    // Closes every client with `CLOSE_GOING_AWAY` and waits for the drain
+    pub async fn shutdown(self) -> Result<()> {
+        self.shutdown.cancel();
+        self.await
+    }
+}
+
+impl IntoFuture for ServerHandle {
+    type Output = Result<()>;
+    type IntoFuture = BoxFuture<'static, Result<()>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(async move { self.task.await? })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WsRoute {
+    Camera(String),
+    View(String),
+}
+
+impl WsRoute {
+    pub fn stream_id(&self) -> &str {
+        match self {
+            WsRoute::Camera(stream_id) | WsRoute::View(stream_id) => stream_id,
+        }
+    }
+
+    pub fn scope(&self) -> Scope {
+        match self {
+            WsRoute::Camera(_) => Scope::Publish,
+            WsRoute::View(_) => Scope::View,
+        }
+    }
+}
+
+// `/camera`, `/camera/{stream_id}`, `/view`, `/view/{stream_id}`
+pub fn route_websocket(path: &str) -> Option<WsRoute> {
+    let (endpoint, stream_id) = match path.strip_prefix('/')?.split_once('/') {
+        Some((endpoint, stream_id)) => (endpoint, stream_id),
+        None => (&path[1..], DEFAULT_STREAM),
+    };
+    if !is_valid_stream_id(stream_id) {
+        return None;
+    }
+    match endpoint {
+        "camera" => Some(WsRoute::Camera(stream_id.to_string())),
+        "view" => Some(WsRoute::View(stream_id.to_string())),
+        _ => None,
+    }
+}
+
+async fn handle_connection<S>(stream: S, conn_id: u64, peer: SocketAddr, state: Arc<ServerState>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut reader = RequestReader::new(stream);
+
+    // HTTP/1.1 keep-alive: serve requests until the client closes, asks to
+    // close, goes idle or upgrades to WebSocket
+    let mut read_timeout = state.config.handshake_timeout;
+    loop {
+        let read = tokio::select! {
+            read = timeout(read_timeout, reader.read_request()) => read,
+            // アイドルの keep-alive 接続はシャットダウン時にすぐ閉じる
+            _ = state.shutdown.cancelled() => return Ok(()),
+        };
+        let request = match read {
+            Err(_) => return Ok(()),
+            Ok(Ok(Some(request))) => request,
+            Ok(Ok(None)) => return Ok(()),
+            Ok(Err(e)) => {
+                if let Some(status) = e.status() {
+                    warn!(error = %e, status, "Rejecting malformed request");
+                    let response = Response::new(status).header("Connection", "close");
+                    reader.get_mut().write_all(&response.to_bytes()).await?;
+                }
+                return Ok(());
+            }
+        };
+        debug!(method = %request.method, path = %request.path, "Incoming request");
+        read_timeout = state.config.idle_timeout;
+
+        // WebSocket upgrade for /camera[/{stream_id}] and /view[/{stream_id}]
+        if let Some(route) = route_websocket(&request.path) {
+            if request.method != "GET" {
+                state.metrics.record_upgrade_failure(UpgradeFailure::MethodNotAllowed);
+                let response = Response::new(405)
+                    .header("Allow", "GET")
+                    .header("Connection", "close");
+                reader.get_mut().write_all(&response.to_bytes()).await?;
+                return Ok(());
+            }
+
+            // 認証はアップグレード前に HTTP のステータスで返す
+            let token = auth::request_token(&request);
+            let decision = state.auth.check(route.scope(), route.stream_id(), token.as_deref());
+            if decision != AuthDecision::Allow {
+                warn!(path = %request.path, stream = route.stream_id(), ?decision, "Rejecting WebSocket upgrade");
+                let response = match decision {
+                    AuthDecision::Unauthorized => {
+                        state.metrics.record_upgrade_failure(UpgradeFailure::Unauthorized);
+                        Response::new(401).header("WWW-Authenticate", "Bearer realm=\"web2ws\"")
                     }
-                    Err(e) => {
-                        eprintln!("WebSocket upgrade failed: {}", e);
-                        return Ok(());
+                    _ => {
+                        state.metrics.record_upgrade_failure(UpgradeFailure::Forbidden);
+                        Response::new(403)
                     }
-                }
+                };
+                reader
+                    .get_mut()
+                    .write_all(&response.header("Connection", "close").to_bytes())
+                    .await?;
+                return Ok(());
             }
-            
-            // HTTP file serving
-            let (content, is_html) = match path {
-                "/" | "/sender.html" | "/static/sender.html" => {
-                    (include_str!("../../static/sender.html"), true)
+
+            // 枠を取る前に不正なアップグレードを断る（置き換えで既存の配信者を追い出さないように）
+            if let Some(response) = upgrade_rejection(&request) {
+                state.metrics.record_upgrade_failure(UpgradeFailure::NotUpgrade);
+                reader.get_mut().write_all(&response.to_bytes()).await?;
+                return Ok(());
+            }
+
+            // 上限の確認と登録はレジストリの同じロックの中で行い、503 で返せるようハンドシェイクの前に済ませる
+            let config = &state.config;
+            let client = match &route {
+                WsRoute::Camera(stream_id) => {
+                    match state.streams.publish_limited(stream_id, config.publisher_policy, config.max_publishers) {
+                        Err(PublishError::LimitReached) => Err("max_publishers"),
+                        publisher => Ok(WsClient::Camera(publisher)),
+                    }
                 }
-                "/viewer.html" | "/static/viewer.html" => {
-                    (include_str!("../../static/viewer.html"), true)
+                WsRoute::View(stream_id) => {
+                    let options = ViewerOptions::from_request(&request, config.delivery_mode);
+                    state
+                        .streams
+                        .subscribe_limited(stream_id, options.delivery, config.max_viewers_per_stream)
+                        .map(|viewer| WsClient::View(viewer, options))
+                        .ok_or("max_viewers_per_stream")
                 }
-                _ => {
-                    let response = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
-                    stream.write_all(response.as_bytes()).await?;
+            };
+            let client = match client {
+                Ok(client) => client,
+                Err(reason) => {
+                    warn!(stream = route.stream_id(), reason, "Rejecting WebSocket upgrade");
+                    state.metrics.record_upgrade_failure(UpgradeFailure::LimitReached);
+                    let response = Response::new(503).header("Connection", "close");
+                    reader.get_mut().write_all(&response.to_bytes()).await?;
                     return Ok(());
                 }
             };
-            
-            if is_html {
-                let response = format!(
-                    "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\n\r\n{}",
-                    content.len(),
-                    content
-                );
-                stream.write_all(response.as_bytes()).await?;
+
+            let ws_config = WebSocketConfig::default()
+                .max_message_size(Some(state.config.max_frame_size))
+                .max_frame_size(Some(state.config.max_frame_size));
+            let ws_stream = match accept_websocket(reader, &request, ws_config).await {
+                Ok(Some(ws_stream)) => ws_stream,
+                Ok(None) => {
+                    state.metrics.record_upgrade_failure(UpgradeFailure::NotUpgrade);
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!(error = %e, "WebSocket upgrade failed");
+                    state.metrics.record_upgrade_failure(UpgradeFailure::Handshake);
+                    return Ok(());
+                }
+            };
+            let stream_id = route.stream_id();
+            return match client {
+                WsClient::Camera(Ok(publisher)) => {
+                    let client = state.clients.register(conn_id, clients::Role::Publisher, peer, stream_id);
+                    let (validation, idle_timeout) = (state.config.frame_validation, state.config.ws_idle_timeout);
+                    let shutdown = state.shutdown.clone();
+                    handle_camera_client(ws_stream, publisher, client, validation, idle_timeout, shutdown).await
+                }
+                WsClient::Camera(Err(e)) => {
+                    warn!(stream = %stream_id, error = %e, "Rejecting camera client");
+                    state.metrics.record_upgrade_failure(UpgradeFailure::PublisherRejected);
+                    let mut ws_stream = ws_stream;
+                    close_with(&mut ws_stream, CLOSE_PUBLISHER_REJECTED, &e.to_string()).await;
+                    Ok(())
+                }
+                WsClient::View(viewer, options) => {
+                    let client = state.clients.register(conn_id, clients::Role::Viewer, peer, stream_id);
+                    let (max_age, idle_timeout) = (state.config.keyframe_max_age, state.config.ws_idle_timeout);
+                    let shutdown = state.shutdown.clone();
+                    handle_viewer_client(ws_stream, viewer, client, options, max_age, idle_timeout, shutdown).await
+                }
+            };
+        }
+
+        // HTTP file serving
+        let keep_alive = request.wants_keep_alive() && !state.shutdown.is_cancelled();
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        let response = match request.path.as_str() {
+            "/status" => status::serve(&request, &state.streams),
+            "/metrics" => metrics::serve(&request, &state.metrics, &state.streams),
+            path if path.starts_with("/api/") => {
+                api::serve(&request, &state.streams, &state.clients, &state.auth)
             }
+            _ => static_files::serve(&request, state.static_dir.as_deref()).await,
+        };
+        response
+            .header("Connection", connection)
+            .write_to(reader.get_mut(), request.is_head())
+            .await?;
+
+        if !keep_alive {
+            // TLS では close_notify を送ってから閉じる
+            reader.get_mut().shutdown().await?;
+            return Ok(());
         }
     }
-    
-    Ok(())
 }
 
-async fn handle_camera_client(
-    mut ws_stream: tokio_tungstenite::WebSocketStream<TcpStream>,
-    broadcast_tx: broadcast::Sender<Vec<u8>>,
-) -> Result<()> {
-    println!("📹 Camera client connected");
+// A publisher or viewer slot taken before the WebSocket handshake
+enum WsClient {
+    // 配信者ポリシーで断られた場合もアップグレードしてから close code で知らせる
+    Camera(std::result::Result<Publisher, PublishError>),
+    View(Viewer, ViewerOptions),
+}
+
+// Completes the RFC 6455 handshake for an already parsed request. Bytes the
+// client sent after the request head are handed to the WebSocket layer.
+// Returns `None` after answering a request that is not a valid upgrade.
+async fn accept_websocket<S>(
+    reader: RequestReader<S>,
+    request: &Request,
+    config: WebSocketConfig,
+) -> Result<Option<WebSocketStream<S>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut stream, leftover) = reader.into_parts();
+
+    if let Some(response) = upgrade_rejection(request) {
+        stream.write_all(&response.to_bytes()).await?;
+        return Ok(None);
+    }
+    let key = request.header("Sec-WebSocket-Key").unwrap_or_default();
+
+    let response = Response::new(101)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", derive_accept_key(key.as_bytes()));
+    stream.write_all(&response.to_bytes()).await?;
+
+    Ok(Some(
+        WebSocketStream::from_partially_read(stream, leftover, Role::Server, Some(config)).await,
+    ))
+}
+
+// The `426` answer for a request that is not a valid WebSocket upgrade
+fn upgrade_rejection(request: &Request) -> Option<Response> {
+    let is_upgrade = request.method == "GET"
+        && request.header_has_token("Connection", "upgrade")
+        && request.header_has_token("Upgrade", "websocket");
+    if !is_upgrade || request.header("Sec-WebSocket-Key").is_none() {
+        return Some(
+            Response::new(426)
+                .header("Upgrade", "websocket")
+                .header("Connection", "close"),
+        );
+    }
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        return Some(
+            Response::new(426)
+                .header("Sec-WebSocket-Version", "13")
+                .header("Connection", "close"),
+        );
+    }
+    None
+}
+
+// Sends a Close frame with an application close code and waits for the
+// client to acknowledge it
+async fn close_with<S>(ws_stream: &mut WebSocketStream<S>, code: u16, reason: &str)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let frame = CloseFrame {
+        code: CloseCode::from(code),
+        reason: reason.to_string().into(),
+    };
+    // 相手が読んでいなければ Close も送れないので待ち続けない
+    if let Ok(Ok(())) = timeout(Duration::from_secs(1), ws_stream.close(Some(frame))).await {
+        while let Ok(Some(Ok(_))) = timeout(Duration::from_secs(1), ws_stream.next()).await {}
+    }
+}
+
+// Sends `message`, giving up at `deadline` on a client that stopped reading
+async fn send_before<S>(
+    ws_stream: &mut WebSocketStream<S>,
+    message: Message,
+    deadline: tokio::time::Instant,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match tokio::time::timeout_at(deadline, ws_stream.send(message)).await {
+        Ok(result) => Ok(result?),
+        Err(_) => anyhow::bail!("client stopped reading"),
+    }
+}
+
+#[tracing::instrument(name = "camera", skip_all, fields(role = "publisher", stream = %publisher.stream_id()))]
+async fn handle_camera_client<S>(
+    mut ws_stream: WebSocketStream<S>,
+    publisher: Publisher,
+    client: ClientHandle,
+    validation: FrameValidation,
+    idle_timeout: Duration,
+    shutdown: CancellationToken,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    info!("Camera client connected");
+    // 途切れた接続（Wi-Fi から外れた端末など）が配信者の枠を持ち続けないよう、
+    // Ping を送って応答も届かなくなったら閉じる
+    let mut ping_interval = tokio::time::interval_at(
+        tokio::time::Instant::now() + PING_INTERVAL,
+        PING_INTERVAL,
+    );
+    let mut idle_deadline = tokio::time::Instant::now() + idle_timeout;
     
-    while let Some(msg_result) = ws_stream.next().await {
+    loop {
+        let msg_result = tokio::select! {
+            // ストリームを閉じたときは置き換えより管理操作の close code を優先する
+            biased;
+            _ = shutdown.cancelled() => {
+                info!("Closing camera client for shutdown");
+                close_with(&mut ws_stream, CLOSE_GOING_AWAY, "server shutting down").await;
+                break;
+            }
+            kick = client.kicked() => {
+                info!(code = kick.code, reason = %kick.reason, "Camera client kicked");
+                close_with(&mut ws_stream, kick.code, &kick.reason).await;
+                break;
+            }
+            msg = ws_stream.next() => match msg {
+                Some(msg) => {
+                    idle_deadline = tokio::time::Instant::now() + idle_timeout;
+                    msg
+                }
+                None => break,
+            },
+            _ = publisher.evicted() => {
+                info!("Camera client replaced by a newer publisher");
+                close_with(&mut ws_stream, CLOSE_PUBLISHER_REPLACED, "replaced by a newer publisher").await;
+                break;
+            }
+            _ = tokio::time::sleep_until(idle_deadline) => {
+                warn!(timeout = ?idle_timeout, "Closing camera client that stopped responding");
+                close_with(&mut ws_stream, CLOSE_IDLE_TIMEOUT, "idle timeout").await;
+                break;
+            }
+            _ = ping_interval.tick() => {
+                if let Err(e) = send_before(&mut ws_stream, Message::Ping(Vec::new().into()), idle_deadline).await {
+                    warn!(error = %e, "Error pinging camera client");
+                    break;
+                }
+                continue;
+            }
+        };
         match msg_result {
             Ok(Message::Binary(data)) => {
-                // Broadcast frame to all viewers
-                let _ = broadcast_tx.send(data);
+                client.record_frame(data.len());
+                // Senders without the envelope header are treated as raw
+                // JPEG and stamped with the ingress time as their capture time.
+                let (header, payload) = if protocol::is_envelope(&data) {
+                    let ingress_us = protocol::now_micros();
+                    match protocol::decode(&data) {
+                        Ok((header, payload)) => {
+                            // 送信側の時計が進んでいる場合はサンプルにしない
+                            if header.timestamp_us != 0 && header.timestamp_us <= ingress_us {
+                                let ingress = Duration::from_micros(ingress_us - header.timestamp_us);
+                                publisher.latency().record(Metric::Ingress, ingress);
+                            }
+                            (header, payload)
+                        }
+                        Err(e) => {
+                            warn!(error = %e, "Dropping malformed frame envelope");
+                            publisher.metrics().record_invalid();
+                            continue;
+                        }
+                    }
+                } else {
+                    (protocol::FrameHeader::for_raw(&data), data)
+                };
+
+                if validation != FrameValidation::Off {
+                    if let Err(e) = protocol::validate_image(&payload, header.content_type) {
+                        publisher.metrics().record_invalid();
+                        if validation == FrameValidation::Reject {
+                            warn!(error = %e, "Closing camera client for an invalid frame");
+                            close_with(&mut ws_stream, CLOSE_INVALID_FRAME, &e.to_string()).await;
+                            break;
+                        }
+                        debug!(error = %e, "Dropping invalid frame");
+                        continue;
+                    }
+                }
+                // Broadcast frame to all viewers of this stream
+                publisher.send_with(header, payload);
             }
             Ok(Message::Close(_)) => {
-                println!("Camera client disconnected");
+                info!("Camera client disconnected");
+                break;
+            }
+            Err(tokio_tungstenite::tungstenite::Error::Capacity(e)) => {
+                warn!(error = %e, "Closing camera client for an oversized frame");
+                publisher.metrics().record_oversized();
+                close_with(&mut ws_stream, u16::from(CloseCode::Size), "frame too large").await;
                 break;
             }
             Err(e) => {
-                eprintln!("Camera client error: {}", e);
+                warn!(error = %e, "Camera client error");
                 break;
             }
             _ => {}
@@ -145,60 +749,232 @@ async fn handle_camera_client(
     Ok(())
 }
 
-async fn handle_viewer_client(
-    mut ws_stream: tokio_tungstenite::WebSocketStream<TcpStream>,
-    broadcast_tx: broadcast::Sender<Vec<u8>>,
-) -> Result<()> {
-    println!("📺 Viewer client connected");
-    let mut broadcast_rx = broadcast_tx.subscribe();
+// `?delivery=queue|latest`、`?lag_notices=1`、`?format=raw|envelope` で接続ごとに指定できる
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ViewerOptions {
+    pub delivery: DeliveryMode,
+    // 読み飛ばしが起きたときにテキストメッセージで通知する
+    pub lag_notices: bool,
+    pub format: WireFormat,
+}
+
+impl ViewerOptions {
+    pub fn from_request(request: &Request, default_delivery: DeliveryMode) -> Self {
+        let delivery = request
+            .query_param("delivery")
+            .and_then(|mode| mode.parse().ok())
+            .unwrap_or(default_delivery);
+        let lag_notices = request
+            .query_param("lag_notices")
+            .is_some_and(|v| v == "1" || v == "true");
+        let format = request
+            .query_param("format")
+            .and_then(|format| format.parse().ok())
+            .unwrap_or_default();
+        Self {
+            delivery,
+            lag_notices,
+            format,
+        }
+    }
+}
+
+// Control messages a viewer may send as Text frames
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ViewerMessage {
+    // Frame `seq` was drawn at `displayed_at_us` (µs since the Unix epoch)
+    Ack { seq: u64, displayed_at_us: u64 },
+}
+
+// 送信したフレームの (連番, キャプチャ時刻)。ack の遅延計算に使う
+#[derive(Default)]
+struct SentFrames(VecDeque<(u64, u64)>);
+
+impl SentFrames {
+    fn track(&mut self, frame: Frame, format: WireFormat) -> Message {
+        if self.0.len() == ACK_WINDOW {
+            self.0.pop_front();
+        }
+        self.0.push_back((frame.header().seq, frame.header().timestamp_us));
+        match format {
+            WireFormat::Raw => Message::Binary(frame.payload().clone()),
+            WireFormat::Envelope => Message::Binary(frame.envelope()),
+        }
+    }
+
+    fn capture_time(&self, seq: u64) -> Option<u64> {
+        self.0.iter().find(|(s, _)| *s == seq).map(|(_, ts)| *ts)
+    }
+}
+
+#[tracing::instrument(
+    name = "viewer",
+    skip_all,
+    fields(role = "viewer", stream = %viewer.stream_id(), delivery = ?options.delivery, format = ?options.format)
+)]
+async fn handle_viewer_client<S>(
+    mut ws_stream: WebSocketStream<S>,
+    mut viewer: Viewer,
+    client: ClientHandle,
+    options: ViewerOptions,
+    keyframe_max_age: Duration,
+    idle_timeout: Duration,
+    shutdown: CancellationToken,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    info!("Viewer client connected");
+    let mut sent = SentFrames::default();
+    // Pong などが届くたびに延びる。過ぎたら応答のないビューアとして閉じる
+    let mut idle_deadline = tokio::time::Instant::now() + idle_timeout;
+
+    // 次のフレームを待たずに直前のフレームを表示させる
+    if let Some(frame) = viewer.take_cached_frame(keyframe_max_age) {
+        let message = sent.track(frame, options.format);
+        let len = message.len();
+        if let Err(e) = send_before(&mut ws_stream, message, idle_deadline).await {
+            warn!(error = %e, "Error sending to viewer");
+            return Ok(());
+        }
+        viewer.metrics().record_out(len);
+        client.record_frame(len);
+    }
+
+    let mut ping_interval = tokio::time::interval_at(
+        tokio::time::Instant::now() + PING_INTERVAL,
+        PING_INTERVAL,
+    );
+    let mut next_ping_id: u64 = 0;
+    let mut pending_ping: Option<(u64, Instant)> = None;
     
-    while let Ok(frame) = broadcast_rx.recv().await {
-        if let Err(e) = ws_stream.send(Message::Binary(frame)).await {
-            eprintln!("Error sending to viewer: {}", e);
-            break;
+    loop {
+        tokio::select! {
+            received = viewer.next_frame() => {
+                let Some(received) = received else {
+                    break;
+                };
+                if received.skipped > 0 && options.lag_notices {
+                    let notice = format!(
+                        r#"{{"type":"lag","skipped":{},"dropped_total":{}}}"#,
+                        received.skipped,
+                        viewer.dropped_frames()
+                    );
+                    if send_before(&mut ws_stream, Message::Text(notice.into()), idle_deadline).await.is_err() {
+                        break;
+                    }
+                }
+                let message = sent.track(received.data, options.format);
+                let len = message.len();
+                if let Err(e) = send_before(&mut ws_stream, message, idle_deadline).await {
+                    warn!(error = %e, "Error sending to viewer");
+                    break;
+                }
+                viewer.metrics().record_out(len);
+                client.record_frame(len);
+            }
+            _ = shutdown.cancelled() => {
+                info!("Closing viewer client for shutdown");
+                close_with(&mut ws_stream, CLOSE_GOING_AWAY, "server shutting down").await;
+                break;
+            }
+            kick = client.kicked() => {
+                info!(code = kick.code, reason = %kick.reason, "Viewer client kicked");
+                close_with(&mut ws_stream, kick.code, &kick.reason).await;
+                break;
+            }
+            _ = ping_interval.tick() => {
+                pending_ping = Some((next_ping_id, Instant::now()));
+                let payload = next_ping_id.to_be_bytes().to_vec();
+                next_ping_id += 1;
+                if send_before(&mut ws_stream, Message::Ping(payload.into()), idle_deadline).await.is_err() {
+                    break;
+                }
+            }
+            _ = tokio::time::sleep_until(idle_deadline) => {
+                warn!(timeout = ?idle_timeout, "Closing viewer that stopped responding");
+                close_with(&mut ws_stream, CLOSE_IDLE_TIMEOUT, "idle timeout").await;
+                break;
+            }
+            msg = ws_stream.next() => match msg {
+                Some(Ok(Message::Pong(payload))) => {
+                    idle_deadline = tokio::time::Instant::now() + idle_timeout;
+                    if let Some((id, sent_at)) = pending_ping {
+                        if payload[..] == id.to_be_bytes() {
+                            viewer.latency().record(Metric::Rtt, sent_at.elapsed());
+                            pending_ping = None;
+                        }
+                    }
+                }
+                Some(Ok(Message::Text(text))) => {
+                    idle_deadline = tokio::time::Instant::now() + idle_timeout;
+                    // 表示時刻は送信側のキャプチャ時刻と同じ時計 (NTP 同期) を前提にする
+                    if let Ok(ViewerMessage::Ack { seq, displayed_at_us }) = serde_json::from_str(&text) {
+                        let capture_us = sent.capture_time(seq);
+                        if let Some(capture_us) = capture_us.filter(|ts| *ts != 0 && *ts <= displayed_at_us) {
+                            let latency = Duration::from_micros(displayed_at_us - capture_us);
+                            viewer.latency().record(Metric::GlassToGlass, latency);
+                        }
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                _ => {}
+            },
         }
     }
     
-    println!("Viewer client disconnected");
+    info!(dropped_frames = viewer.dropped_frames(), "Viewer client disconnected");
     Ok(())
 }
 
 
-// Test helper structures
+// Test helper structures: real WebSocket clients for a running `Server`
 pub struct TestCameraClient {
-    #[allow(dead_code)]
-    frame: Vec<u8>,
+    client: WebSocketClient,
 }
 
 impl TestCameraClient {
-    pub async fn send_frame(&self, _frame: &[u8]) -> Result<()> {
-        Ok(())
+    pub async fn send_frame(&mut self, frame: &[u8]) -> Result<()> {
+        self.client.send_frame(frame).await
+    }
+
+    pub async fn close(self) -> Result<()> {
+        self.client.close().await
     }
 }
 
 pub struct TestViewerClient {
-    frame: Vec<u8>,
+    client: WebSocketClient,
 }
 
 impl TestViewerClient {
-    pub async fn receive_frame(&self) -> Result<Vec<u8>> {
-        Ok(self.frame.clone())
+    pub async fn receive_frame(&mut self) -> Result<Vec<u8>> {
+        self.client.receive_binary().await
     }
-}
 
-pub fn spawn_camera_client(_url: &str) -> TestCameraClient {
-    TestCameraClient {
-        frame: vec![0u8; 1024],
+    pub async fn close(self) -> Result<()> {
+        self.client.close().await
     }
 }
 
-pub fn spawn_viewer_client(_url: &str) -> TestViewerClient {
-    TestViewerClient {
-        frame: vec![0u8; 1024],
-    }
+// `url` is a `ws://` URL such as `ws://127.0.0.1:9001/camera/front-door`
+pub async fn spawn_camera_client(url: &str) -> Result<TestCameraClient> {
+    Ok(TestCameraClient {
+        client: WebSocketClient::connect(url).await?,
+    })
 }
 
-pub fn dummy_frame() -> Vec<u8> {
-    vec![0u8; 1024]
+pub async fn spawn_viewer_client(url: &str) -> Result<TestViewerClient> {
+    Ok(TestViewerClient {
+        client: WebSocketClient::connect(url).await?,
+    })
 }
 
+// 1 KiB の JPEG 形式（SOI で始まり EOI で終わる）のダミーフレーム
+pub fn dummy_frame() -> Vec<u8> {
+    let mut frame = vec![0u8; 1024];
+    frame[..2].copy_from_slice(&[0xFF, 0xD8]);
+    frame[1022..].copy_from_slice(&[0xFF, 0xD9]);
+    frame
+}
@@ -0,0 +1,594 @@
+// src/server/registry.rs
+use super::latency::{LatencySnapshot, StreamLatency};
+use super::metrics::StreamMetrics;
+use crate::protocol::{self, FrameHeader};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::{broadcast, watch, Notify};
+
+// Reference-counted so fan-out to each viewer is a pointer copy, not a
+// copy of the JPEG
+#[derive(Clone, Debug)]
+pub struct Frame(Arc<FrameInner>);
+
+#[derive(Debug)]
+struct FrameInner {
+    header: FrameHeader,
+    payload: Bytes,
+    // エンベロープ形式のビューアが現れたときに一度だけ組み立てる
+    envelope: OnceLock<Bytes>,
+}
+
+impl Frame {
+    pub fn new(header: FrameHeader, payload: Bytes) -> Self {
+        Self(Arc::new(FrameInner {
+            header,
+            payload,
+            envelope: OnceLock::new(),
+        }))
+    }
+
+    pub fn header(&self) -> &FrameHeader {
+        &self.0.header
+    }
+
+    pub fn payload(&self) -> &Bytes {
+        &self.0.payload
+    }
+
+    // Header and payload in the envelope wire format, encoded on first use
+    // and shared by every envelope viewer
+    pub fn envelope(&self) -> Bytes {
+        self.0
+            .envelope
+            .get_or_init(|| protocol::encode(&self.0.header, &self.0.payload))
+            .clone()
+    }
+}
+
+// `/camera` と `/view` はこのストリームに接続される
+pub const DEFAULT_STREAM: &str = "default";
+
+const MAX_STREAM_ID_LEN: usize = 64;
+
+pub fn is_valid_stream_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_STREAM_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+        && id != "."
+        && id != ".."
+}
+
+// What happens when a second publisher joins a stream
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PublisherPolicy {
+    // 後から来た配信者を拒否する
+    Reject,
+    // 新しい配信者に置き換え、古い配信者を切断する
+    Replace,
+    // すべての配信者のフレームを混ぜる（従来の動作）
+    #[default]
+    Merge,
+}
+
+impl FromStr for PublisherPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "reject" => Ok(PublisherPolicy::Reject),
+            "replace" => Ok(PublisherPolicy::Replace),
+            "merge" => Ok(PublisherPolicy::Merge),
+            _ => anyhow::bail!("Unknown publisher policy `{}` (expected reject, replace or merge)", s),
+        }
+    }
+}
+
+// How frames reach a viewer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeliveryMode {
+    // すべてのフレームを順番に届け、遅れたら最新まで読み飛ばす
+    #[default]
+    Queue,
+    // 常に最新のフレームだけを届ける（遅い回線向け）
+    Latest,
+}
+
+impl FromStr for DeliveryMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "queue" => Ok(DeliveryMode::Queue),
+            "latest" => Ok(DeliveryMode::Latest),
+            _ => anyhow::bail!("Unknown delivery mode `{}` (expected queue or latest)", s),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PublishError {
+    StreamBusy,
+    // すべてのストリームを合わせた配信者数の上限
+    LimitReached,
+}
+
+impl fmt::Display for PublishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PublishError::StreamBusy => write!(f, "stream already has a publisher"),
+            PublishError::LimitReached => write!(f, "too many publishers"),
+        }
+    }
+}
+
+impl std::error::Error for PublishError {}
+
+#[derive(Default)]
+struct Eviction {
+    evicted: AtomicBool,
+    notify: Notify,
+}
+
+struct PublisherSlot {
+    id: u64,
+    eviction: Arc<Eviction>,
+}
+
+// Newest frame of a stream, kept for latest-mode viewers and late joiners
+#[derive(Clone)]
+struct LatestFrame {
+    frame: Frame,
+    published_at: Instant,
+}
+
+impl LatestFrame {
+    fn seq(&self) -> u64 {
+        self.frame.header().seq
+    }
+}
+
+struct StreamEntry {
+    tx: broadcast::Sender<Frame>,
+    latest: watch::Sender<Option<LatestFrame>>,
+    publishers: Vec<PublisherSlot>,
+    viewers: usize,
+    latency: Arc<StreamLatency>,
+    metrics: Arc<StreamMetrics>,
+}
+
+// Streams are created on first use and removed once the last publisher
+// and viewer have gone.
+pub struct StreamRegistry {
+    capacity: usize,
+    streams: Mutex<HashMap<String, StreamEntry>>,
+    next_publisher_id: AtomicU64,
+}
+
+impl StreamRegistry {
+    pub fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(Self {
+            capacity,
+            streams: Mutex::new(HashMap::new()),
+            next_publisher_id: AtomicU64::new(1),
+        })
+    }
+
+    pub fn publish(
+        self: &Arc<Self>,
+        stream_id: &str,
+        policy: PublisherPolicy,
+    ) -> Result<Publisher, PublishError> {
+        self.publish_limited(stream_id, policy, None)
+    }
+
+    // Like `publish`, but fails once `max_publishers` publishers exist across
+    // all streams. A publisher that replaces others does not add to the count.
+    pub fn publish_limited(
+        self: &Arc<Self>,
+        stream_id: &str,
+        policy: PublisherPolicy,
+        max_publishers: Option<usize>,
+    ) -> Result<Publisher, PublishError> {
+        let mut streams = self.streams.lock().unwrap();
+        // 上限の確認と登録を同じロックの中で行うので、同時に接続しても上限を超えない
+        if let Some(max) = max_publishers {
+            let existing = streams.get(stream_id).map_or(0, |e| e.publishers.len());
+            let replaced = if policy == PublisherPolicy::Replace { existing } else { 0 };
+            let total: usize = streams.values().map(|e| e.publishers.len()).sum();
+            if total - replaced >= max {
+                return Err(PublishError::LimitReached);
+            }
+        }
+        if policy == PublisherPolicy::Reject
+            && streams.get(stream_id).is_some_and(|e| !e.publishers.is_empty())
+        {
+            return Err(PublishError::StreamBusy);
+        }
+        let entry = self.entry(&mut streams, stream_id);
+        if policy == PublisherPolicy::Replace {
+            Self::evict(entry);
+        }
+        let id = self.next_publisher_id.fetch_add(1, Ordering::Relaxed);
+        let eviction = Arc::new(Eviction::default());
+        entry.publishers.push(PublisherSlot {
+            id,
+            eviction: eviction.clone(),
+        });
+        Ok(Publisher {
+            registry: self.clone(),
+            stream_id: stream_id.to_string(),
+            id,
+            tx: entry.tx.clone(),
+            latest: entry.latest.clone(),
+            latency: entry.latency.clone(),
+            metrics: entry.metrics.clone(),
+            eviction,
+        })
+    }
+
+    pub fn subscribe(self: &Arc<Self>, stream_id: &str, mode: DeliveryMode) -> Viewer {
+        let mut streams = self.streams.lock().unwrap();
+        self.add_viewer(&mut streams, stream_id, mode)
+    }
+
+    // Like `subscribe`, but returns `None` once the stream has `max_viewers`
+    pub fn subscribe_limited(
+        self: &Arc<Self>,
+        stream_id: &str,
+        mode: DeliveryMode,
+        max_viewers: Option<usize>,
+    ) -> Option<Viewer> {
+        let mut streams = self.streams.lock().unwrap();
+        let viewers = streams.get(stream_id).map_or(0, |e| e.viewers);
+        if max_viewers.is_some_and(|max| viewers >= max) {
+            return None;
+        }
+        Some(self.add_viewer(&mut streams, stream_id, mode))
+    }
+
+    fn add_viewer(
+        self: &Arc<Self>,
+        streams: &mut HashMap<String, StreamEntry>,
+        stream_id: &str,
+        mode: DeliveryMode,
+    ) -> Viewer {
+        let entry = self.entry(streams, stream_id);
+        entry.viewers += 1;
+        // latest の読み取りロックを持ったまま購読する。push_frame は同じロックの中で配信するので、
+        // 間に配信されたフレームが cached にも受信側にも入らないということがない
+        let latest = entry.latest.borrow();
+        let cached = latest.clone();
+        let rx = match mode {
+            DeliveryMode::Queue => FrameReceiver::Queue(entry.tx.subscribe()),
+            DeliveryMode::Latest => {
+                let mut rx = entry.latest.subscribe();
+                // 接続前のフレームは送らない
+                rx.mark_unchanged();
+                FrameReceiver::Latest(rx)
+            }
+        };
+        drop(latest);
+        Viewer {
+            registry: self.clone(),
+            stream_id: stream_id.to_string(),
+            latency: entry.latency.clone(),
+            metrics: entry.metrics.clone(),
+            rx,
+            last_seq: cached.as_ref().map(LatestFrame::seq),
+            cached,
+            dropped_frames: 0,
+        }
+    }
+
+    // Sends a raw frame to an existing stream without keeping it alive
+    pub fn send(&self, stream_id: &str, payload: impl Into<Bytes>) -> usize {
+        let payload = payload.into();
+        let streams = self.streams.lock().unwrap();
+        match streams.get(stream_id) {
+            Some(entry) => push_frame(
+                &entry.tx,
+                &entry.latest,
+                &entry.metrics,
+                stream_id,
+                FrameHeader::for_raw(&payload),
+                payload,
+            ),
+            None => 0,
+        }
+    }
+
+    // Evicts every publisher of a stream, including in-process ones such as
+    // the server-side capture loop. Returns how many there were.
+    pub fn evict_publishers(&self, stream_id: &str) -> usize {
+        let mut streams = self.streams.lock().unwrap();
+        let Some(entry) = streams.get_mut(stream_id) else {
+            return 0;
+        };
+        let evicted = Self::evict(entry);
+        Self::remove_if_unused(&mut streams, stream_id);
+        evicted
+    }
+
+    fn evict(entry: &mut StreamEntry) -> usize {
+        let count = entry.publishers.len();
+        for slot in entry.publishers.drain(..) {
+            slot.eviction.evicted.store(true, Ordering::SeqCst);
+            slot.eviction.notify.notify_one();
+        }
+        count
+    }
+
+    pub fn contains(&self, stream_id: &str) -> bool {
+        self.streams.lock().unwrap().contains_key(stream_id)
+    }
+
+    pub fn stream_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.streams.lock().unwrap().keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    pub fn publisher_count(&self, stream_id: &str) -> usize {
+        let streams = self.streams.lock().unwrap();
+        streams.get(stream_id).map_or(0, |e| e.publishers.len())
+    }
+
+    // Publishers of every stream, including in-process ones
+    pub fn total_publishers(&self) -> usize {
+        self.streams.lock().unwrap().values().map(|e| e.publishers.len()).sum()
+    }
+
+    pub fn viewer_count(&self, stream_id: &str) -> usize {
+        let streams = self.streams.lock().unwrap();
+        streams.get(stream_id).map_or(0, |e| e.viewers)
+    }
+
+    pub fn latency(&self, stream_id: &str) -> Option<LatencySnapshot> {
+        let latency = self.streams.lock().unwrap().get(stream_id)?.latency.clone();
+        Some(latency.snapshot())
+    }
+
+    pub fn stream_metrics(&self, stream_id: &str) -> Option<Arc<StreamMetrics>> {
+        Some(self.streams.lock().unwrap().get(stream_id)?.metrics.clone())
+    }
+
+    fn entry<'a>(
+        &self,
+        streams: &'a mut HashMap<String, StreamEntry>,
+        stream_id: &str,
+    ) -> &'a mut StreamEntry {
+        streams.entry(stream_id.to_string()).or_insert_with(|| {
+            let (tx, _) = broadcast::channel(self.capacity);
+            let (latest, _) = watch::channel(None);
+            StreamEntry {
+                tx,
+                latest,
+                publishers: Vec::new(),
+                viewers: 0,
+                latency: Arc::default(),
+                metrics: Arc::default(),
+            }
+        })
+    }
+
+    // 置き換えられた配信者は既にエントリから外れている
+    fn release_publisher(&self, stream_id: &str, id: u64) {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(entry) = streams.get_mut(stream_id) {
+            entry.publishers.retain(|slot| slot.id != id);
+        }
+        Self::remove_if_unused(&mut streams, stream_id);
+    }
+
+    fn release_viewer(&self, stream_id: &str) {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(entry) = streams.get_mut(stream_id) {
+            entry.viewers = entry.viewers.saturating_sub(1);
+        }
+        Self::remove_if_unused(&mut streams, stream_id);
+    }
+
+    fn remove_if_unused(streams: &mut HashMap<String, StreamEntry>, stream_id: &str) {
+        if streams
+            .get(stream_id)
+            .is_some_and(|e| e.publishers.is_empty() && e.viewers == 0)
+        {
+            streams.remove(stream_id);
+        }
+    }
+}
+
+pub struct Publisher {
+    registry: Arc<StreamRegistry>,
+    stream_id: String,
+    id: u64,
+    tx: broadcast::Sender<Frame>,
+    latest: watch::Sender<Option<LatestFrame>>,
+    latency: Arc<StreamLatency>,
+    metrics: Arc<StreamMetrics>,
+    eviction: Arc<Eviction>,
+}
+
+impl Publisher {
+    pub fn stream_id(&self) -> &str {
+        &self.stream_id
+    }
+
+    pub fn latency(&self) -> &StreamLatency {
+        &self.latency
+    }
+
+    pub fn metrics(&self) -> &StreamMetrics {
+        &self.metrics
+    }
+
+    // 受信したビューア数を返す。置き換えられた後は何も送らない
+    pub fn send(&self, payload: impl Into<Bytes>) -> usize {
+        let payload = payload.into();
+        self.send_with(FrameHeader::for_raw(&payload), payload)
+    }
+
+    // Publishes with sender-supplied metadata. The stream id and sequence
+    // number in `header` are replaced by the stream's own.
+    pub fn send_with(&self, header: FrameHeader, payload: impl Into<Bytes>) -> usize {
+        if self.is_evicted() {
+            return 0;
+        }
+        push_frame(&self.tx, &self.latest, &self.metrics, &self.stream_id, header, payload.into())
+    }
+
+    pub fn is_evicted(&self) -> bool {
+        self.eviction.evicted.load(Ordering::SeqCst)
+    }
+
+    // Resolves once a newer publisher has taken over the stream
+    pub async fn evicted(&self) {
+        if !self.is_evicted() {
+            self.eviction.notify.notified().await;
+        }
+    }
+}
+
+// 連番の採番と配信を latest の同じロックの中で行うので、複数の配信者がいても
+// 連番は重複せず、ビューアには連番の順に届く
+fn push_frame(
+    tx: &broadcast::Sender<Frame>,
+    latest: &watch::Sender<Option<LatestFrame>>,
+    metrics: &StreamMetrics,
+    stream_id: &str,
+    mut header: FrameHeader,
+    payload: Bytes,
+) -> usize {
+    metrics.record_in(payload.len());
+    let mut receivers = 0;
+    latest.send_modify(|latest| {
+        header.seq = latest.as_ref().map_or(0, |l| l.seq() + 1);
+        header.stream_id = stream_id.to_string();
+        let frame = Frame::new(header, payload);
+        *latest = Some(LatestFrame {
+            frame: frame.clone(),
+            published_at: Instant::now(),
+        });
+        // broadcast::send は待たないのでロック中に呼んでよい
+        receivers = tx.send(frame).unwrap_or(0);
+    });
+    receivers
+}
+
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        self.registry.release_publisher(&self.stream_id, self.id);
+    }
+}
+
+enum FrameReceiver {
+    Queue(broadcast::Receiver<Frame>),
+    Latest(watch::Receiver<Option<LatestFrame>>),
+}
+
+pub struct ReceivedFrame {
+    pub data: Frame,
+    // このフレームの直前に読み飛ばしたフレーム数
+    pub skipped: u64,
+}
+
+pub struct Viewer {
+    registry: Arc<StreamRegistry>,
+    stream_id: String,
+    latency: Arc<StreamLatency>,
+    metrics: Arc<StreamMetrics>,
+    rx: FrameReceiver,
+    // 接続時点での最新フレーム（後から来たビューア向け）
+    cached: Option<LatestFrame>,
+    last_seq: Option<u64>,
+    dropped_frames: u64,
+}
+
+impl Viewer {
+    pub fn stream_id(&self) -> &str {
+        &self.stream_id
+    }
+
+    pub fn latency(&self) -> &StreamLatency {
+        &self.latency
+    }
+
+    pub fn metrics(&self) -> &StreamMetrics {
+        &self.metrics
+    }
+
+    // The stream's newest frame at subscribe time, if it is younger than
+    // `max_age`. Frames from `next_frame` always come after it.
+    pub fn take_cached_frame(&mut self, max_age: Duration) -> Option<Frame> {
+        let cached = self.cached.take()?;
+        (cached.published_at.elapsed() <= max_age).then_some(cached.frame)
+    }
+
+    // Total frames this viewer never received because it fell behind
+    pub fn dropped_frames(&self) -> u64 {
+        self.dropped_frames
+    }
+
+    // Returns `None` once the stream is gone. A viewer that falls behind
+    // skips straight to the newest frame instead of being disconnected.
+    pub async fn next_frame(&mut self) -> Option<ReceivedFrame> {
+        let received = match &mut self.rx {
+            FrameReceiver::Queue(rx) => {
+                let mut skipped = 0;
+                let mut data = loop {
+                    match rx.recv().await {
+                        Ok(data) => break data,
+                        Err(RecvError::Lagged(n)) => skipped += n,
+                        Err(RecvError::Closed) => return None,
+                    }
+                };
+                if skipped > 0 {
+                    // バッファに残っている古いフレームも捨てて最新だけを返す
+                    loop {
+                        match rx.try_recv() {
+                            Ok(newer) => {
+                                data = newer;
+                                skipped += 1;
+                            }
+                            Err(TryRecvError::Lagged(n)) => skipped += n,
+                            Err(_) => break,
+                        }
+                    }
+                }
+                ReceivedFrame { data, skipped }
+            }
+            FrameReceiver::Latest(rx) => {
+                rx.changed().await.ok()?;
+                let latest = rx.borrow_and_update().clone()?;
+                let skipped = self
+                    .last_seq
+                    .map_or(0, |last| latest.seq().saturating_sub(last + 1));
+                self.last_seq = Some(latest.seq());
+                ReceivedFrame {
+                    data: latest.frame,
+                    skipped,
+                }
+            }
+        };
+        self.dropped_frames += received.skipped;
+        if received.skipped > 0 {
+            self.metrics.record_dropped(received.skipped);
+        }
+        Some(received)
+    }
+}
+
+impl Drop for Viewer {
+    fn drop(&mut self) {
+        self.registry.release_viewer(&self.stream_id);
+    }
+}
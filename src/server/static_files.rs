@@ -0,0 +1,200 @@
+// src/server/static_files.rs
+use super::http::{etag_for, etag_matches, percent_decode, Request, Response};
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+const SENDER_HTML: &str = include_str!("../../static/sender.html");
+const VIEWER_HTML: &str = include_str!("../../static/viewer.html");
+
+fn embedded_page(path: &str) -> Option<&'static str> {
+    match path {
+        "/" | "/sender.html" | "/static/sender.html" => Some(SENDER_HTML),
+        "/viewer.html" | "/static/viewer.html" => Some(VIEWER_HTML),
+        _ => None,
+    }
+}
+
+pub fn mime_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .unwrap_or_default();
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "wasm" => "application/wasm",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mjpeg" | "mjpg" => "video/x-motion-jpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+// Maps a URL path onto `root`. Rejects `..`, absolute components and
+// anything that escapes the root through a symlink.
+pub async fn resolve_path(root: &Path, url_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode(url_path)?;
+    if decoded.contains('\0') || decoded.contains('\\') {
+        return None;
+    }
+    let mut relative = PathBuf::new();
+    for component in Path::new(decoded.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    // ファイルシステムへの問い合わせはランタイムのスレッドを止めないよう tokio::fs で行う
+    let mut candidate = root.join(relative);
+    let is_dir = tokio::fs::metadata(&candidate).await.is_ok_and(|m| m.is_dir());
+    if decoded.ends_with('/') || is_dir {
+        candidate.push("index.html");
+    }
+
+    let root = tokio::fs::canonicalize(root).await.ok()?;
+    let candidate = tokio::fs::canonicalize(candidate).await.ok()?;
+    let is_file = tokio::fs::metadata(&candidate).await.is_ok_and(|m| m.is_file());
+    (candidate.starts_with(&root) && is_file).then_some(candidate)
+}
+
+pub async fn serve(request: &Request, static_dir: Option<&Path>) -> Response {
+    let file = match static_dir {
+        Some(root) => resolve_path(root, &request.path).await,
+        None => None,
+    };
+    if file.is_none() && embedded_page(&request.path).is_none() {
+        return Response::new(404);
+    }
+    if request.method != "GET" && request.method != "HEAD" {
+        return Response::new(405).header("Allow", "GET, HEAD");
+    }
+
+    // ディスク上のファイルが埋め込みページより優先される
+    match file {
+        Some(file) => match serve_file(request, &file).await {
+            Ok(response) => response,
+            Err(e) => {
+                tracing::error!(path = %file.display(), error = %e, "Failed to read static file");
+                Response::new(500)
+            }
+        },
+        None => serve_embedded(request),
+    }
+}
+
+fn serve_embedded(request: &Request) -> Response {
+    let content = embedded_page(&request.path).unwrap_or_default();
+
+    // ページはバイナリに埋め込まれているので、再検証させて 304 で返す
+    let etag = etag_for(content.as_bytes());
+    if is_not_modified(request, &etag) {
+        return not_modified(etag);
+    }
+    Response::new(200)
+        .header("ETag", etag)
+        .header("Cache-Control", "no-cache")
+        .body("text/html; charset=utf-8", content)
+}
+
+async fn serve_file(request: &Request, path: &Path) -> std::io::Result<Response> {
+    let file = tokio::fs::File::open(path).await?;
+    let metadata = file.metadata().await?;
+    let len = metadata.len();
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let etag = format!("\"{:x}-{:x}\"", len, mtime);
+    if is_not_modified(request, &etag) {
+        return Ok(not_modified(etag));
+    }
+
+    let (status, start, end) = match request.header("Range").map(|r| parse_range(r, len)) {
+        None | Some(ByteRange::Ignored) => (200, 0, len),
+        Some(ByteRange::Partial(start, end)) => (206, start, end + 1),
+        Some(ByteRange::Unsatisfiable) => {
+            return Ok(Response::new(416)
+                .header("Content-Range", format!("bytes */{}", len))
+                .header("Accept-Ranges", "bytes"));
+        }
+    };
+
+    // ボディは書き出すときにファイルから直接送る（HEAD なら読まない）
+    let mut response = Response::new(status)
+        .header("ETag", etag)
+        .header("Cache-Control", "no-cache")
+        .header("Accept-Ranges", "bytes");
+    if status == 206 {
+        response = response.header("Content-Range", format!("bytes {}-{}/{}", start, end - 1, len));
+    }
+    Ok(response.file_body(mime_type(path), file, start, end - start))
+}
+
+fn is_not_modified(request: &Request, etag: &str) -> bool {
+    request
+        .header("If-None-Match")
+        .is_some_and(|value| etag_matches(value, etag))
+}
+
+fn not_modified(etag: String) -> Response {
+    Response::new(304)
+        .header("ETag", etag)
+        .header("Cache-Control", "no-cache")
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    // 開始と終了（終了を含む）
+    Partial(u64, u64),
+    Unsatisfiable,
+    // 複数範囲や解釈できない指定は全体を返す
+    Ignored,
+}
+
+pub fn parse_range(header: &str, len: u64) -> ByteRange {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return ByteRange::Ignored;
+    };
+    if spec.contains(',') {
+        return ByteRange::Ignored;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Ignored;
+    };
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", "") => return ByteRange::Ignored,
+        ("", suffix) => match suffix.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(n) => (len.saturating_sub(n), len.saturating_sub(1)),
+            Err(_) => return ByteRange::Ignored,
+        },
+        (start, "") => match start.parse::<u64>() {
+            Ok(s) => (s, len.saturating_sub(1)),
+            Err(_) => return ByteRange::Ignored,
+        },
+        (start, end) => match (start.parse::<u64>(), end.parse::<u64>()) {
+            (Ok(s), Ok(e)) if s <= e => (s, e.min(len.saturating_sub(1))),
+            _ => return ByteRange::Ignored,
+        },
+    };
+    if len == 0 || start >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(start, end)
+}
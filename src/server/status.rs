@@ -0,0 +1,52 @@
+// src/server/status.rs
+use super::http::{Request, Response};
+use super::latency::LatencySnapshot;
+use super::registry::StreamRegistry;
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub struct StreamStatus {
+    pub id: String,
+    pub publishers: usize,
+    pub viewers: usize,
+    pub latency: LatencySnapshot,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ServerStatus {
+    pub streams: Vec<StreamStatus>,
+}
+
+pub fn collect(streams: &StreamRegistry) -> ServerStatus {
+    let streams = streams
+        .stream_ids()
+        .into_iter()
+        .filter_map(|id| {
+            // 一覧を取った後に消えたストリームは飛ばす
+            let latency = streams.latency(&id)?;
+            Some(StreamStatus {
+                publishers: streams.publisher_count(&id),
+                viewers: streams.viewer_count(&id),
+                latency,
+                id,
+            })
+        })
+        .collect();
+    ServerStatus { streams }
+}
+
+// `GET /status`: per-stream clients and latency percentiles as JSON
+pub fn serve(request: &Request, streams: &StreamRegistry) -> Response {
+    if request.method != "GET" && request.method != "HEAD" {
+        return Response::new(405).header("Allow", "GET, HEAD");
+    }
+    match serde_json::to_vec(&collect(streams)) {
+        Ok(body) => Response::new(200)
+            .header("Cache-Control", "no-store")
+            .body("application/json", body),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to encode status");
+            Response::new(500)
+        }
+    }
+}
@@ -0,0 +1,38 @@
+// src/server/tls.rs
+use anyhow::{Context, Result};
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::{self, ServerConfig};
+
+// Builds a rustls config from PEM files: a certificate chain and a
+// PKCS#8, PKCS#1 or SEC1 private key.
+pub fn load_server_config(cert_path: &Path, key_path: &Path) -> Result<Arc<ServerConfig>> {
+    let cert_pem = std::fs::read(cert_path)
+        .with_context(|| format!("Failed to read TLS certificate {}", cert_path.display()))?;
+    let key_pem = std::fs::read(key_path)
+        .with_context(|| format!("Failed to read TLS key {}", key_path.display()))?;
+    server_config_from_pem(&cert_pem, &key_pem)
+}
+
+pub fn server_config_from_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<Arc<ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(cert_pem))
+        .collect::<Result<Vec<_>, _>>()
+        .context("Invalid TLS certificate PEM")?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in TLS certificate PEM");
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(key_pem))
+        .context("Invalid TLS key PEM")?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in TLS key PEM"))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("TLS certificate and key do not match")?;
+    // HTTP/2 には対応していないので HTTP/1.1 だけを広告する
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
@@ -1,81 +1,105 @@
 // src/websocket/mod.rs
 use anyhow::Result;
-use std::sync::{Arc, Mutex};
-use tokio::sync::mpsc;
+use crate::server::{Server, ServerHandle, StreamRegistry, DEFAULT_STREAM};
+use futures::{SinkExt, StreamExt};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
 
+// receive_binary がフレームを待つ上限（テストが止まらないように）
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// A WebSocket client connected with `connect_async`
 pub struct WebSocketClient {
-    tx: mpsc::Sender<Vec<u8>>,
-    rx: Arc<Mutex<mpsc::Receiver<Vec<u8>>>>,
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
 }
 
 impl WebSocketClient {
-    pub fn new() -> (Self, mpsc::Receiver<Vec<u8>>, mpsc::Sender<Vec<u8>>) {
-        let (tx_client, rx_server) = mpsc::channel(100);
-        let (tx_server, rx_client) = mpsc::channel(100);
-        
-        let client = Self {
-            tx: tx_server,
-            rx: Arc::new(Mutex::new(rx_client)),
-        };
-        
-        (client, rx_server, tx_client)
+    pub async fn connect(url: &str) -> Result<Self> {
+        let (ws, _) = tokio_tungstenite::connect_async(url).await?;
+        Ok(Self { ws })
     }
 
     pub async fn send_frame(&mut self, frame: &[u8]) -> Result<()> {
-        self.tx.send(frame.to_vec()).await?;
+        self.ws.send(Message::Binary(frame.to_vec().into())).await?;
         Ok(())
     }
 
+    // Next binary message. Pings are answered and text messages skipped;
+    // fails if the server closes the connection or nothing arrives in time.
     pub async fn receive_binary(&mut self) -> Result<Vec<u8>> {
-        let mut rx = self.rx.lock().unwrap();
-        rx.recv().await.ok_or_else(|| anyhow::anyhow!("Channel closed"))
+        let next = async {
+            while let Some(msg) = self.ws.next().await {
+                match msg? {
+                    Message::Binary(data) => return Ok(data.to_vec()),
+                    Message::Close(frame) => {
+                        let code = frame.map(|f| u16::from(f.code));
+                        anyhow::bail!("Connection closed by server (code {:?})", code)
+                    }
+                    _ => {}
+                }
+            }
+            anyhow::bail!("Connection closed")
+        };
+        tokio::time::timeout(RECEIVE_TIMEOUT, next)
+            .await
+            .map_err(|_| anyhow::anyhow!("Timed out waiting for a frame"))?
+    }
+
+    // Sends a Close frame and waits for the server to answer it
+    pub async fn close(mut self) -> Result<()> {
+        self.ws.close(None).await?;
+        let drain = async { while let Some(Ok(_)) = self.ws.next().await {} };
+        let _ = tokio::time::timeout(RECEIVE_TIMEOUT, drain).await;
+        Ok(())
     }
 }
 
+// A real server on an ephemeral port. Frames sent here go to the default
+// stream.
 pub struct TestWebSocketServer {
-    frames: Arc<Mutex<Vec<Vec<u8>>>>,
+    handle: ServerHandle,
+    streams: Arc<StreamRegistry>,
 }
 
 impl TestWebSocketServer {
-    pub fn new() -> Self {
-        Self {
-            frames: Arc::new(Mutex::new(Vec::new())),
-        }
+    pub fn local_addr(&self) -> SocketAddr {
+        self.handle.local_addr()
     }
 
     pub fn send_frame(&self, frame: &[u8]) -> Result<()> {
-        let mut frames = self.frames.lock().unwrap();
-        frames.push(frame.to_vec());
+        self.streams.send(DEFAULT_STREAM, frame.to_vec());
         Ok(())
     }
 
-    pub fn get_frames(&self) -> Vec<Vec<u8>> {
-        let frames = self.frames.lock().unwrap();
-        frames.clone()
+    pub async fn shutdown(self) -> Result<()> {
+        self.handle.shutdown().await
     }
 }
 
+// A viewer of the test server's default stream
 pub struct TestWebSocketClientConn {
-    frames: Arc<Mutex<Vec<Vec<u8>>>>,
+    client: WebSocketClient,
 }
 
 impl TestWebSocketClientConn {
-    pub fn receive_binary(&self) -> Result<Vec<u8>> {
-        let frames = self.frames.lock().unwrap();
-        if frames.is_empty() {
-            anyhow::bail!("No frames available")
-        }
-        Ok(frames[0].clone())
+    pub async fn receive_binary(&mut self) -> Result<Vec<u8>> {
+        self.client.receive_binary().await
     }
 }
 
-pub fn spawn_test_websocket() -> (TestWebSocketServer, TestWebSocketClientConn) {
-    let frames = Arc::new(Mutex::new(Vec::new()));
-    let server = TestWebSocketServer {
-        frames: frames.clone(),
-    };
-    let client = TestWebSocketClientConn { frames };
-    (server, client)
+// Starts a server and connects one viewer. Returns once the server has
+// subscribed the viewer, so no frame sent afterwards is missed.
+pub async fn spawn_test_websocket() -> Result<(TestWebSocketServer, TestWebSocketClientConn)> {
+    let server = Server::new("127.0.0.1:0").await?;
+    let streams = server.streams();
+    let handle = server.spawn();
+    let client = WebSocketClient::connect(&format!("ws://{}/view", handle.local_addr())).await?;
+    while streams.viewer_count(DEFAULT_STREAM) == 0 {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    Ok((TestWebSocketServer { handle, streams }, TestWebSocketClientConn { client }))
 }
-
-
@@ -1,16 +1,143 @@
-use web2ws::server::{Server, spawn_camera_client, spawn_viewer_client, dummy_frame};
+use std::sync::Arc;
+use std::time::Duration;
+use web2ws::server::{
+    dummy_frame, spawn_camera_client, spawn_viewer_client, Server, ServerHandle, StreamRegistry,
+};
+
+// エフェメラルポートで起動するので並列に実行しても衝突しない
+async fn start_server() -> (ServerHandle, Arc<StreamRegistry>) {
+    let server = Server::new("127.0.0.1:0").await.unwrap();
+    let streams = server.streams();
+    (server.spawn(), streams)
+}
+
+fn url(server: &ServerHandle, path: &str) -> String {
+    format!("ws://{}{}", server.local_addr(), path)
+}
+
+async fn wait_until(mut condition: impl FnMut() -> bool) {
+    for _ in 0..500 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    panic!("condition not reached in time");
+}
+
+fn numbered_frame(n: u8) -> Vec<u8> {
+    let mut frame = dummy_frame();
+    frame[2] = n;
+    frame
+}
 
 #[tokio::test]
 async fn server_accepts_camera_and_viewer_connections() {
-    let _server = Server::new("127.0.0.1:9001").await.unwrap();
-    
+    let (server, streams) = start_server().await;
+
     // カメラクライアントとして接続
-    let camera_client = spawn_camera_client("ws://127.0.0.1:9001/camera");
-    // ビューアクライアントとして接続  
-    let viewer_client = spawn_viewer_client("ws://127.0.0.1:9001/view");
-    
+    let mut camera_client = spawn_camera_client(&url(&server, "/camera")).await.unwrap();
+    // ビューアクライアントとして接続
+    let mut viewer_client = spawn_viewer_client(&url(&server, "/view")).await.unwrap();
+    wait_until(|| streams.publisher_count("default") == 1 && streams.viewer_count("default") == 1).await;
+
     // カメラがフレーム送信 → ビューアが受信
     camera_client.send_frame(&dummy_frame()).await.unwrap();
     let received = viewer_client.receive_frame().await.unwrap();
-    assert_eq!(received.len(), 1024);
+    assert_eq!(received, dummy_frame());
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn frames_fan_out_to_every_viewer_in_order() {
+    let (server, streams) = start_server().await;
+
+    let mut camera = spawn_camera_client(&url(&server, "/camera/lobby")).await.unwrap();
+    let mut viewers = Vec::new();
+    for _ in 0..3 {
+        viewers.push(spawn_viewer_client(&url(&server, "/view/lobby")).await.unwrap());
+    }
+    wait_until(|| streams.viewer_count("lobby") == 3).await;
+
+    for n in 0..5 {
+        camera.send_frame(&numbered_frame(n)).await.unwrap();
+    }
+    for viewer in &mut viewers {
+        for n in 0..5 {
+            assert_eq!(viewer.receive_frame().await.unwrap(), numbered_frame(n));
+        }
+    }
+}
+
+#[tokio::test]
+async fn streams_are_isolated() {
+    let (server, streams) = start_server().await;
+
+    let mut camera = spawn_camera_client(&url(&server, "/camera/front")).await.unwrap();
+    let mut front = spawn_viewer_client(&url(&server, "/view/front")).await.unwrap();
+    let mut back = spawn_viewer_client(&url(&server, "/view/back")).await.unwrap();
+    wait_until(|| streams.viewer_count("front") == 1 && streams.viewer_count("back") == 1).await;
+
+    camera.send_frame(&numbered_frame(1)).await.unwrap();
+    assert_eq!(front.receive_frame().await.unwrap(), numbered_frame(1));
+    let other = tokio::time::timeout(Duration::from_millis(200), back.receive_frame()).await;
+    assert!(other.is_err());
+}
+
+#[tokio::test]
+async fn viewers_survive_camera_disconnect_and_reconnect() {
+    let (server, streams) = start_server().await;
+
+    let mut viewer = spawn_viewer_client(&url(&server, "/view/yard")).await.unwrap();
+    let mut camera = spawn_camera_client(&url(&server, "/camera/yard")).await.unwrap();
+    wait_until(|| streams.publisher_count("yard") == 1 && streams.viewer_count("yard") == 1).await;
+    camera.send_frame(&numbered_frame(1)).await.unwrap();
+    assert_eq!(viewer.receive_frame().await.unwrap(), numbered_frame(1));
+
+    camera.close().await.unwrap();
+    wait_until(|| streams.publisher_count("yard") == 0).await;
+    assert_eq!(streams.viewer_count("yard"), 1);
+
+    let mut camera = spawn_camera_client(&url(&server, "/camera/yard")).await.unwrap();
+    wait_until(|| streams.publisher_count("yard") == 1).await;
+    camera.send_frame(&numbered_frame(2)).await.unwrap();
+    assert_eq!(viewer.receive_frame().await.unwrap(), numbered_frame(2));
+
+    // ビューアが抜けても配信者は接続したまま
+    viewer.close().await.unwrap();
+    wait_until(|| streams.viewer_count("yard") == 0).await;
+    camera.send_frame(&numbered_frame(3)).await.unwrap();
+    assert_eq!(streams.publisher_count("yard"), 1);
+
+    // 全員いなくなるとストリームも消える
+    camera.close().await.unwrap();
+    wait_until(|| streams.stream_ids().is_empty()).await;
+}
+
+#[tokio::test]
+async fn unknown_paths_get_404() {
+    use tokio_tungstenite::tungstenite::Error;
+
+    let (server, _streams) = start_server().await;
+
+    for path in ["/nope", "/viewer/lobby", "/view/bad%20id", "/camera/a/b"] {
+        match tokio_tungstenite::connect_async(url(&server, path)).await {
+            Err(Error::Http(response)) => assert_eq!(response.status(), 404, "{}", path),
+            other => panic!("{}: expected a 404, got {:?}", path, other.map(|(_, r)| r.status())),
+        }
+    }
+    assert!(spawn_viewer_client(&url(&server, "/missing")).await.is_err());
+}
+
+#[tokio::test]
+async fn shutdown_disconnects_clients() {
+    let (server, streams) = start_server().await;
+
+    let mut viewer = spawn_viewer_client(&url(&server, "/view")).await.unwrap();
+    wait_until(|| streams.viewer_count("default") == 1).await;
+    server.shutdown().await.unwrap();
+
+    let err = viewer.receive_frame().await.unwrap_err();
+    assert!(err.to_string().contains("1001"), "{}", err);
 }
@@ -3,8 +3,7 @@ use std::time::{Instant, Duration};
 
 #[test]
 fn camera_applies_fps_setting() {
-    let mut camera = Camera::new(0)
-        .unwrap()
+    let mut camera = Camera::synthetic()
         .fps(10.0)  // 10fps指定（0.1秒間隔）
         .quality(80)
         .build()
@@ -24,8 +23,7 @@ fn camera_applies_fps_setting() {
 
 #[test]
 fn camera_applies_fps_setting() {
-    let mut camera = Camera::new(0)
-        .unwrap()
+    let mut camera = Camera::synthetic()
         .fps(10.0)  // 10fps指定（0.1秒間隔）
         .quality(80)
         .build()
@@ -46,8 +44,8 @@ fn camera_applies_fps_setting() {
 
 #[test]
 fn camera_applies_quality_setting() {
-    let mut high_quality = Camera::new(0).unwrap().quality(90).build().unwrap();
-    let mut low_quality = Camera::new(0).unwrap().quality(50).build().unwrap();
+    let mut high_quality = Camera::synthetic().quality(90).build().unwrap();
+    let mut low_quality = Camera::synthetic().quality(50).build().unwrap();
     
     let high_frame = high_quality.capture_frame().unwrap();
     let low_frame = low_quality.capture_frame().unwrap();
@@ -60,7 +58,7 @@ fn camera_applies_quality_setting() {
 
 #[test]
 fn camera_clamps_quality_values() {
-    let camera = Camera::new(0).unwrap()
+    let camera = Camera::synthetic()
         .quality(5)    // 下限10にクランプされる
         .quality(120)  // 上限95にクランプされる
         .build()
@@ -75,7 +73,7 @@ fn camera_clamps_quality_values() {
 
 #[test]
 fn camera_fps_zero_is_clamped() {
-    let mut camera = Camera::new(0).unwrap()
+    let mut camera = Camera::synthetic()
         .fps(0.0)  // 0fpsは1fpsにクランプ
         .quality(80)
         .build()
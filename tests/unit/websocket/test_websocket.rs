@@ -1,10 +1,10 @@
 use web2ws::websocket::spawn_test_websocket;
 
-#[test]
-fn websocket_sends_binary_frame() {
-    let (server, client) = spawn_test_websocket();
+#[tokio::test]
+async fn websocket_sends_binary_frame() {
+    let (server, mut client) = spawn_test_websocket().await.unwrap();
     let test_frame = vec![0u8; 1024]; // ダミーフレーム
     server.send_frame(&test_frame).unwrap();
-    let received = client.receive_binary().unwrap();
+    let received = client.receive_binary().await.unwrap();
     assert_eq!(received, test_frame);
 }